@@ -0,0 +1,14 @@
+-- Your SQL goes here
+CREATE TABLE `sync_jobs`(
+	`id` INTEGER NOT NULL PRIMARY KEY,
+	`path` TEXT NOT NULL,
+	`syncr_id` TEXT NOT NULL,
+	`known_name` TEXT NOT NULL,
+	`status` TEXT NOT NULL,
+	`attempts` INTEGER NOT NULL,
+	`last_error` TEXT,
+	`created_at` TIMESTAMP NOT NULL,
+	`updated_at` TIMESTAMP NOT NULL
+);
+
+CREATE INDEX `sync_jobs_status` ON `sync_jobs`(`status`, `id`);
@@ -0,0 +1,8 @@
+-- Your SQL goes here
+CREATE TABLE `known_devices`(
+	`id` INTEGER NOT NULL PRIMARY KEY,
+	`name` TEXT NOT NULL UNIQUE,
+	`public_key` BINARY NOT NULL,
+	`created_at` TIMESTAMP NOT NULL,
+	`updated_at` TIMESTAMP NOT NULL
+);
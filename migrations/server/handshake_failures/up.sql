@@ -0,0 +1,9 @@
+-- Your SQL goes here
+CREATE TABLE `handshake_failures`(
+	`id` INTEGER NOT NULL PRIMARY KEY,
+	`ip` TEXT NOT NULL,
+	`reason` TEXT NOT NULL,
+	`created_at` TIMESTAMP NOT NULL,
+	`updated_at` TIMESTAMP NOT NULL
+);
+CREATE INDEX `handshake_failures_created_at` ON `handshake_failures`(`created_at`);
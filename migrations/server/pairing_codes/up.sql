@@ -0,0 +1,10 @@
+-- Your SQL goes here
+CREATE TABLE `pairing_codes`(
+	`id` INTEGER NOT NULL PRIMARY KEY,
+	`code_id` TEXT NOT NULL UNIQUE,
+	`psk` BINARY NOT NULL,
+	`expires_at` TIMESTAMP NOT NULL,
+	`used_at` TIMESTAMP,
+	`created_at` TIMESTAMP NOT NULL,
+	`updated_at` TIMESTAMP NOT NULL
+);
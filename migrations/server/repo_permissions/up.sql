@@ -0,0 +1,11 @@
+-- Your SQL goes here
+CREATE TABLE `repo_permissions`(
+	`id` INTEGER NOT NULL PRIMARY KEY,
+	`device` TEXT NOT NULL,
+	`syncr_id` TEXT NOT NULL,
+	`permission` TEXT NOT NULL,
+	`created_at` TIMESTAMP NOT NULL,
+	`updated_at` TIMESTAMP NOT NULL
+);
+
+CREATE UNIQUE INDEX `repo_permissions_device_syncr_id` ON `repo_permissions`(`device`, `syncr_id`);
@@ -0,0 +1,37 @@
+use std::time::Duration;
+
+use rand::Rng;
+
+// exponential backoff with full jitter, every client waking up at the same
+// moment after a server restart is exactly what the jitter is there to avoid
+pub struct Backoff {
+    attempt: u32,
+    base: Duration,
+    max: Duration,
+}
+
+impl Backoff {
+    pub fn new(base: Duration, max: Duration) -> Self {
+        Self {
+            attempt: 0,
+            base,
+            max,
+        }
+    }
+
+    // somewhere between nothing and base * 2^attempt (capped at max)
+    pub fn next_delay(&mut self) -> Duration {
+        let ceiling = self
+            .base
+            .saturating_mul(2u32.saturating_pow(self.attempt))
+            .min(self.max);
+        self.attempt = self.attempt.saturating_add(1);
+
+        let millis = rand::thread_rng().gen_range(0..=ceiling.as_millis() as u64);
+        Duration::from_millis(millis)
+    }
+
+    pub fn reset(&mut self) {
+        self.attempt = 0;
+    }
+}
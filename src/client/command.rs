@@ -0,0 +1,82 @@
+use std::{
+    io,
+    pin::Pin,
+    process::Stdio,
+    task::{Context, Poll},
+};
+
+use log::info;
+use tokio::{
+    io::{AsyncRead, AsyncWrite, Join, ReadBuf},
+    process::{Child, ChildStdin, ChildStdout, Command},
+};
+
+// a spawned command whose stdout/stdin we talk to, the way rsync uses a remote shell
+//
+// the child lives exactly as long as the transport, dropping it (connection
+// closed) kills the command too
+pub struct CommandTransport {
+    _child: Child,
+    pipes: Join<ChildStdout, ChildStdin>,
+}
+
+impl CommandTransport {
+    pub fn spawn(command: &[String]) -> Result<Self, anyhow::Error> {
+        let (program, args) = command
+            .split_first()
+            .ok_or(anyhow::anyhow!("server-command is empty"))?;
+
+        info!("Spawning {:?} as the transport", command);
+
+        // stderr is left alone so ssh prompts and the server's logs still show up
+        let mut child = Command::new(program)
+            .args(args)
+            .stdin(Stdio::piped())
+            .stdout(Stdio::piped())
+            .stderr(Stdio::inherit())
+            .kill_on_drop(true)
+            .spawn()?;
+
+        let stdout = child
+            .stdout
+            .take()
+            .ok_or(anyhow::anyhow!("Unable to take the command's stdout"))?;
+        let stdin = child
+            .stdin
+            .take()
+            .ok_or(anyhow::anyhow!("Unable to take the command's stdin"))?;
+
+        Ok(Self {
+            _child: child,
+            pipes: tokio::io::join(stdout, stdin),
+        })
+    }
+}
+
+impl AsyncRead for CommandTransport {
+    fn poll_read(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.pipes).poll_read(cx, buf)
+    }
+}
+
+impl AsyncWrite for CommandTransport {
+    fn poll_write(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<io::Result<usize>> {
+        Pin::new(&mut self.pipes).poll_write(cx, buf)
+    }
+
+    fn poll_flush(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.pipes).poll_flush(cx)
+    }
+
+    fn poll_shutdown(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.pipes).poll_shutdown(cx)
+    }
+}
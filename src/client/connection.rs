@@ -0,0 +1,307 @@
+use std::{
+    collections::HashMap,
+    sync::{
+        Arc, Mutex,
+        atomic::{AtomicU32, Ordering},
+    },
+};
+
+use log::{info, warn};
+use tokio::{
+    sync::mpsc,
+    task::{AbortHandle, JoinHandle},
+    time,
+};
+
+use crate::common::heartbeat::Heartbeat;
+use crate::common::idle::IdleTimeout;
+use crate::common::packets::{
+    CONNECTION, Capabilities, DynamicPacket, FrameLimits, MmapPacket, PacketBase, Packets,
+    PingPacket, PongPacket, ProtocolError, TransactionId, read_frame, send_frame, send_mmap_frame,
+};
+use crate::common::stream::{SecureReader, SecureStream, SecureWriter, Streams, Transport};
+
+// how many packets can queue up for a single transaction before the reader waits on it
+const INBOX_SIZE: usize = 8;
+
+// what a transaction is handed, a packet that didn't decode included so it doesn't wait forever
+type Delivery = Result<Packets, ProtocolError>;
+type Pending = Arc<Mutex<HashMap<TransactionId, mpsc::Sender<Delivery>>>>;
+// the secret the server last told us to switch to, if it did
+type Rotated = Arc<Mutex<Option<String>>>;
+
+// one connection to the server, shared by every transaction running on it
+//
+// a background task reads frames and routes them to whichever transaction they
+// belong to, writers take turns on the write half
+pub struct Connection {
+    writer: SecureWriter,
+    streams: Streams,
+    #[cfg(feature = "quic")]
+    limits: FrameLimits,
+    pending: Pending,
+    next_id: AtomicU32,
+    capabilities: Capabilities,
+    remote_key: [u8; 32],
+    rotated: Rotated,
+    reader: JoinHandle<()>,
+    heartbeat: JoinHandle<()>,
+}
+
+impl Connection {
+    pub fn new<T: Transport>(
+        stream: SecureStream<T>,
+        streams: Streams,
+        limits: FrameLimits,
+        heartbeat: Heartbeat,
+    ) -> Self {
+        let capabilities = stream.capabilities();
+        let remote_key = *stream.remote_key();
+        let (reader, writer) = stream.split();
+
+        let pending: Pending = Arc::new(Mutex::new(HashMap::new()));
+        let rotated: Rotated = Arc::new(Mutex::new(None));
+        let reader = tokio::spawn(Self::route(
+            IdleTimeout::new(reader, heartbeat.timeout),
+            writer.clone(),
+            pending.clone(),
+            rotated.clone(),
+            limits,
+        ));
+        let heartbeat = tokio::spawn(Self::ping(writer.clone(), heartbeat));
+
+        Self {
+            writer,
+            streams,
+            #[cfg(feature = "quic")]
+            limits,
+            pending,
+            next_id: AtomicU32::new(CONNECTION + 1),
+            capabilities,
+            remote_key,
+            rotated,
+            reader,
+            heartbeat,
+        }
+    }
+
+    pub fn capabilities(&self) -> Capabilities {
+        self.capabilities
+    }
+
+    // the server's static key, see SecureStream::remote_key
+    pub fn remote_key(&self) -> &[u8; 32] {
+        &self.remote_key
+    }
+
+    // the secret the server moved on to, handed out once, still there after the connection died
+    pub fn take_rotated_secret(&self) -> Option<String> {
+        self.rotated.lock().ok()?.take()
+    }
+
+    // false once the server stopped answering (or hung up) and the connection is gone
+    pub fn is_alive(&self) -> bool {
+        !self.reader.is_finished()
+    }
+
+    async fn ping(writer: SecureWriter, heartbeat: Heartbeat) {
+        let mut interval = time::interval(heartbeat.interval);
+        let mut nonce = 0u64;
+
+        loop {
+            interval.tick().await;
+            nonce += 1;
+
+            if let Err(e) = send_frame(&writer, CONNECTION, &PingPacket::build(nonce)).await {
+                warn!("Unable to send heartbeat: {e}");
+                break;
+            }
+        }
+    }
+
+    // the server PINGs us every interval, so IdleTimeout tripping means it's gone
+    async fn route(
+        mut reader: IdleTimeout<SecureReader>,
+        writer: SecureWriter,
+        pending: Pending,
+        rotated: Rotated,
+        limits: FrameLimits,
+    ) {
+        loop {
+            let (id, packet) = match read_frame(&mut reader, &limits).await {
+                Ok(frame) => frame,
+                Err(e) => {
+                    warn!("Connection lost: {e}");
+                    break;
+                }
+            };
+
+            if id == CONNECTION {
+                match packet {
+                    Ok(Packets::Ping(ping)) => {
+                        let pong = PongPacket::build(ping.nonce);
+                        if let Err(e) = send_frame(&writer, CONNECTION, &pong).await {
+                            warn!("Unable to answer heartbeat: {e}");
+                            break;
+                        }
+                    }
+                    Ok(Packets::Pong(_)) => {}
+                    Ok(Packets::Error(error)) => {
+                        warn!("Server error ({:?}): {}", error.code, error.message)
+                    }
+                    Ok(Packets::Rotate(rotate)) => {
+                        info!("Server rotated its secret, switching over");
+                        if let Ok(mut rotated) = rotated.lock() {
+                            *rotated = Some(rotate.secret);
+                        }
+                    }
+                    Ok(other) => info!("Connection level {} packet", other.name()),
+                    // nobody is waiting on it, the connection itself is fine
+                    Err(e) => warn!("Dropping bad frame: {e}"),
+                }
+                continue;
+            }
+
+            // clone the sender out so the lock isn't held across the await
+            let inbox = match pending.lock() {
+                Ok(pending) => pending.get(&id).cloned(),
+                Err(_) => break,
+            };
+
+            match inbox {
+                Some(inbox) => {
+                    if inbox.send(packet).await.is_err() {
+                        warn!("Transaction {} went away before its packet arrived", id);
+                    }
+                }
+                None => match packet {
+                    Ok(packet) => warn!("Got {} for unknown transaction {}", packet.name(), id),
+                    Err(e) => warn!("Dropping bad frame for unknown transaction {}: {e}", id),
+                },
+            }
+        }
+
+        // dropping every sender wakes up whoever is still waiting on a reply
+        if let Ok(mut pending) = pending.lock() {
+            pending.clear();
+        }
+    }
+
+    // replies on a transaction's own stream, there's only ever the one transaction on it
+    #[cfg(feature = "quic")]
+    async fn forward<R: tokio::io::AsyncRead + Unpin>(
+        mut reader: R,
+        inbox: mpsc::Sender<Delivery>,
+        limits: FrameLimits,
+    ) {
+        loop {
+            let packet = match read_frame(&mut reader, &limits).await {
+                Ok((_, packet)) => packet,
+                Err(e) => {
+                    info!("Transaction stream closed: {e}");
+                    break;
+                }
+            };
+
+            if inbox.send(packet).await.is_err() {
+                break;
+            }
+        }
+    }
+
+    // reserves a fresh transaction id and the inbox its replies will land in
+    pub async fn open(&self) -> Result<Transaction, anyhow::Error> {
+        let mut id = self.next_id.fetch_add(1, Ordering::Relaxed);
+        if id == CONNECTION {
+            // wrapped around, CONNECTION is never handed out
+            id = self.next_id.fetch_add(1, Ordering::Relaxed);
+        }
+
+        let (inbox_tx, inbox_rx) = mpsc::channel(INBOX_SIZE);
+
+        let (writer, stream) = match &self.streams {
+            Streams::Shared => {
+                self.pending
+                    .lock()
+                    .map_err(|e| anyhow::anyhow!("Lock poisoned: {}", e))?
+                    .insert(id, inbox_tx);
+
+                (self.writer.clone(), None)
+            }
+            #[cfg(feature = "quic")]
+            Streams::Quic(quic) => {
+                let (send, recv) = quic.open_bi().await?;
+                let writer: SecureWriter = Arc::new(tokio::sync::Mutex::new(Box::new(send)));
+                let forward = tokio::spawn(Self::forward(recv, inbox_tx, self.limits));
+
+                (writer, Some(forward.abort_handle()))
+            }
+        };
+
+        Ok(Transaction {
+            id,
+            inbox: inbox_rx,
+            writer,
+            stream,
+            pending: self.pending.clone(),
+        })
+    }
+}
+
+impl Drop for Connection {
+    fn drop(&mut self) {
+        self.reader.abort();
+        self.heartbeat.abort();
+    }
+}
+
+// a transaction's view of the connection, unregisters itself once dropped
+pub struct Transaction {
+    pub id: TransactionId,
+    inbox: mpsc::Receiver<Delivery>,
+    writer: SecureWriter,
+    // whatever reads this transaction's own stream, if it has one
+    stream: Option<AbortHandle>,
+    pending: Pending,
+}
+
+impl Transaction {
+    pub async fn send<P: DynamicPacket>(&self, packet: &P) -> Result<(), anyhow::Error> {
+        send_frame(&self.writer, self.id, packet).await
+    }
+
+    pub async fn send_mmap<P: MmapPacket>(&self, packet: &P) -> Result<(), anyhow::Error> {
+        send_mmap_frame(&self.writer, self.id, packet).await
+    }
+
+    // next packet the server sent for this transaction
+    pub async fn receive(&mut self) -> Result<Packets, anyhow::Error> {
+        match self.inbox.recv().await {
+            Some(Ok(Packets::Error(error))) => Err(anyhow::anyhow!(
+                "Server aborted transaction {} ({:?}): {}",
+                self.id,
+                error.code,
+                error.message
+            )),
+            Some(Ok(packet)) => Ok(packet),
+            Some(Err(e)) => Err(anyhow::anyhow!(
+                "Bad packet for transaction {}: {}",
+                self.id,
+                e
+            )),
+            None => Err(ProtocolError::Io(std::io::ErrorKind::ConnectionAborted.into()).into()),
+        }
+    }
+}
+
+impl Drop for Transaction {
+    fn drop(&mut self) {
+        if let Some(stream) = &self.stream {
+            stream.abort();
+        }
+
+        if let Ok(mut pending) = self.pending.lock() {
+            pending.remove(&self.id);
+        }
+    }
+}
@@ -1,32 +1,81 @@
-use std::sync::Mutex;
+use std::fs::File;
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
 
-use log::info;
-use tokio::io::AsyncReadExt;
+use futures::{StreamExt, stream};
+use indexmap::IndexMap;
+use log::{info, warn};
+use memmap2::MmapOptions;
+use notify::EventKind;
 use tokio::net::TcpStream;
+use tokio::sync::{Notify, mpsc};
 
-use crate::common::config::{Config, quick_config};
-use crate::common::packets::{DynamicPacket, PacketBase, SanityPacket};
-use crate::common::stream::SecureStream;
+use crate::common::compression::Compressor;
+use crate::common::config::{
+    Config, SyncConfig, quick_config,
+    structure::{ClientConfig, TransportKind},
+};
+use crate::common::heartbeat::Heartbeat;
+use crate::common::identity;
+use crate::common::packets::{
+    Capabilities, FORCE_CHUNK_SIZE, FrameLimits, HelloPacket, PacketBase, Packets,
+    SyncAcknowledgePacket, SyncDeltaBeginPacket, SyncDeltaChunkPacket, SyncDeltaEndPacket,
+    SyncForcePacket, SyncInitPacket,
+};
+use crate::common::pairing::Greeting;
+use crate::common::psk;
+#[cfg(feature = "quic")]
+use crate::common::quic;
+use crate::common::stream::{Credentials, Role, SecureStream, Streams, Transport};
+use crate::common::sync;
 use crate::data::DatabaseDriver;
+use crate::data::entities::known_device::{self, KnownDevice, Trust};
+use crate::data::entities::sync_job::SyncJob;
 use crate::model::{self, CompressionTree};
+use crate::utils::hash::hash_file;
 
+use super::backoff::Backoff;
+use super::command::CommandTransport;
+use super::connection::{Connection, Transaction};
 use super::database::ClientDatabase;
+use super::watcher::Watcher;
+
+// delta chunks generated ahead of the ones being sent
+const DELTA_CHANNEL_SIZE: usize = 2;
+// first reconnection delay, doubled (and jittered) on every failed attempt
+const RECONNECT_BASE_DELAY: Duration = Duration::from_millis(500);
+// how often run() looks at the job queue for new work
+const QUEUE_POLL_INTERVAL: Duration = Duration::from_secs(2);
+// how long a job waits after its first failed attempt, doubled on every one after that
+const JOB_RETRY_BASE_DELAY: Duration = Duration::from_secs(5);
+const JOB_RETRY_MAX_DELAY: Duration = Duration::from_secs(15 * 60);
+
+// a file that changed on disk, where it is along with its syncr_id and known_name
+pub type Change = (PathBuf, String, String);
 
 pub struct Client {
-    stream: SecureStream,
+    connection: Option<Connection>,
+    backoff: Backoff,
     config: Config,
+    credentials: Credentials,
     database: ClientDatabase,
     predictor: Mutex<CompressionTree>,
 }
 
 impl Client {
-    pub async fn connect(config: Option<Config>) -> Result<Self, anyhow::Error> {
+    async fn new(config: Option<Config>) -> Result<Self, anyhow::Error> {
         let config = match config {
             Some(c) => c,
             None => quick_config!()?,
         };
         let client_ref = config.as_client()?; // implicitly assert we're in client mode too!
 
+        let credentials = Credentials {
+            keypair: Arc::new(identity::load_or_create(None)?),
+            psk: psk::from_config(&config).await?,
+        };
+
         let mut database = ClientDatabase::new(None).await?;
 
         info!("Connected to database");
@@ -35,35 +84,600 @@ impl Client {
 
         info!("Initialized predictor model");
 
-        let stream = TcpStream::connect((
-            client_ref.client().server_ip,
-            client_ref.client().server_port,
-        ))
-        .await?;
-        let stream = SecureStream::new(stream, &config.secret).await?;
-
-        info!("Connected to server");
+        let backoff = Backoff::new(
+            RECONNECT_BASE_DELAY,
+            Duration::from_secs(client_ref.client().reconnect_max_delay),
+        );
 
         Ok(Self {
-            stream,
+            connection: None,
+            backoff,
             config,
+            credentials,
             predictor: Mutex::new(predictor),
             database,
         })
     }
 
-    pub async fn run(&mut self) {
-        let message = b"hello world";
-        let sanity_packet = SanityPacket::build(message.to_vec());
-        sanity_packet.write(&mut *self.stream).await.unwrap();
+    pub async fn connect(config: Option<Config>) -> Result<Self, anyhow::Error> {
+        let mut client = Self::new(config).await?;
+        client.reconnect().await?;
+
+        Ok(client)
+    }
+
+    // talks to the server over a transport that is already open, in place of the one the
+    // config points at. once it drops, reconnecting goes by the config again
+    pub async fn over<T: Transport>(
+        config: Option<Config>,
+        mut transport: T,
+    ) -> Result<Self, anyhow::Error> {
+        let mut client = Self::new(config).await?;
+
+        let greeting = Greeting::Session {
+            key_id: psk::key_id(&client.credentials.psk),
+        };
+        greeting.write(&mut transport).await?;
+        let connection = Self::handshake(
+            transport,
+            Streams::Shared,
+            &greeting.prologue(&[]),
+            &client.config,
+            &client.credentials,
+        )
+        .await?;
+        client.verify_server(&connection)?;
+        client.connection = Some(connection);
+
+        Ok(client)
+    }
+
+    // reaches the server however the config says to, along with whatever the transport
+    // negotiated for the handshake to be tied to (QUIC's TLS)
+    pub(super) async fn open(
+        config: &Config,
+    ) -> Result<(Box<dyn Transport>, Streams, Vec<u8>), anyhow::Error> {
+        let client_ref = config.as_client()?;
+
+        if let Some(command) = &client_ref.client().server_command {
+            let transport = CommandTransport::spawn(command)?;
+            return Ok((
+                Box::new(transport) as Box<dyn Transport>,
+                Streams::Shared,
+                Vec::new(),
+            ));
+        }
+
+        let addr = (
+            client_ref.client().server_ip,
+            client_ref.client().server_port,
+        );
+
+        match client_ref.client().transport {
+            TransportKind::Tcp => {
+                let stream = TcpStream::connect(addr).await?;
+                Ok((
+                    Box::new(stream) as Box<dyn Transport>,
+                    Streams::Shared,
+                    Vec::new(),
+                ))
+            }
+            #[cfg(feature = "quic")]
+            TransportKind::Quic => {
+                let (connection, control) = quic::connect(addr.into()).await?;
+                let binding = quic::binding(&connection)?.to_vec();
+                Ok((
+                    Box::new(control) as Box<dyn Transport>,
+                    Streams::Quic(connection),
+                    binding,
+                ))
+            }
+            #[cfg(not(feature = "quic"))]
+            TransportKind::Quic => {
+                anyhow::bail!("transport is set to quic but this build has no QUIC support")
+            }
+        }
+    }
+
+    async fn establish(
+        config: &Config,
+        credentials: &Credentials,
+    ) -> Result<Connection, anyhow::Error> {
+        let (mut transport, streams, binding) = Self::open(config).await?;
+
+        let greeting = Greeting::Session {
+            key_id: psk::key_id(&credentials.psk),
+        };
+        greeting.write(&mut transport).await?;
+        let prologue = greeting.prologue(&binding);
+
+        Self::handshake(transport, streams, &prologue, config, credentials).await
+    }
+
+    // secures an already open transport and negotiates with the server on the other end
+    pub async fn handshake<T: Transport>(
+        transport: T,
+        streams: Streams,
+        prologue: &[u8],
+        config: &Config,
+        credentials: &Credentials,
+    ) -> Result<Connection, anyhow::Error> {
+        let client_ref = config.as_client()?;
+
+        let hello = HelloPacket::build((
+            config.device_name.clone(),
+            Self::syncr_ids(client_ref.client()),
+            Capabilities::local(),
+        ));
+        let stream =
+            SecureStream::with_prologue(transport, Role::Initiator, credentials, hello, prologue)
+                .await?;
+
+        Ok(Connection::new(
+            stream,
+            streams,
+            FrameLimits::default(),
+            Heartbeat::from_config(config),
+        ))
+    }
+
+    // makes sure we have a live connection, retrying with backoff for as long as it takes
+    async fn reconnect(&mut self) -> Result<(), anyhow::Error> {
+        self.adopt_rotated_secret().await?;
+
+        if self.connection.as_ref().is_some_and(Connection::is_alive) {
+            return Ok(());
+        }
+        self.connection = None;
+
+        loop {
+            match Self::establish(&self.config, &self.credentials).await {
+                Ok(connection) => {
+                    // a server that isn't who it was last time is not worth retrying
+                    self.verify_server(&connection)?;
+
+                    info!("Connected to server");
+                    self.backoff.reset();
+                    self.connection = Some(connection);
+
+                    return Ok(());
+                }
+                Err(e) => {
+                    let delay = self.backoff.next_delay();
+                    warn!("Unable to reach server ({e}), retrying in {:?}", delay);
+                    tokio::time::sleep(delay).await;
+                }
+            }
+        }
+    }
+
+    // switches to the secret the server pushed, if it did, the open connection stays as is
+    // and every connection from here on is made with the new one
+    async fn adopt_rotated_secret(&mut self) -> Result<(), anyhow::Error> {
+        let Some(secret) = self
+            .connection
+            .as_ref()
+            .and_then(Connection::take_rotated_secret)
+        else {
+            return Ok(());
+        };
+
+        self.config.secret = secret;
+        self.credentials.psk = psk::from_config(&self.config).await?;
+        self.config.async_save().await?;
+        info!("Switched to the server's new secret");
+
+        Ok(())
+    }
+
+    // pins the server's key the first time we reach it, like ssh's known_hosts
+    fn verify_server(&mut self, connection: &Connection) -> Result<(), anyhow::Error> {
+        let server = Self::server_name(self.config.as_client()?.client());
+
+        match KnownDevice::verify(&server, connection.remote_key(), &mut self.database)? {
+            Trust::New => info!(
+                "First connection to {}, pinned its key {}",
+                server,
+                known_device::fingerprint(connection.remote_key())
+            ),
+            Trust::Known => {}
+        }
+
+        Ok(())
+    }
+
+    // what the server's key is pinned under, however we happen to reach it
+    pub(super) fn server_name(client: &ClientConfig) -> String {
+        match &client.server_command {
+            Some(command) => command.join(" "),
+            None => format!("{}:{}", client.server_ip, client.server_port),
+        }
+    }
+
+    fn connection(&self) -> Result<&Connection, anyhow::Error> {
+        self.connection
+            .as_ref()
+            .ok_or(anyhow::anyhow!("Not connected to the server"))
+    }
+
+    // every active directory that has already been set up
+    fn sync_configs(client: &ClientConfig) -> Vec<SyncConfig> {
+        client
+            .directories
+            .iter()
+            .filter(|directory| directory.active && directory.path.join(".syncr").is_file())
+            .filter_map(|directory| match SyncConfig::read(directory.path.clone()) {
+                Ok(sync_config) => Some(sync_config),
+                Err(e) => {
+                    warn!("Unable to read {:?}: {e}", directory.path);
+                    None
+                }
+            })
+            .collect()
+    }
+
+    fn syncr_ids(client: &ClientConfig) -> Vec<String> {
+        Self::sync_configs(client)
+            .iter()
+            .map(|sync_config| sync_config.syncr_id.clone())
+            .collect()
+    }
+
+    // starts watching every active directory, files that change in them are queued as
+    // jobs right away (so they survive a crash mid-replay) and the returned Notify wakes run()
+    pub async fn watch(&self) -> Result<Arc<Notify>, anyhow::Error> {
+        let queued = Arc::new(Notify::new());
+
+        for sync_config in Self::sync_configs(self.config.as_client()?.client()) {
+            let syncr_id = sync_config.syncr_id.clone();
+            let watcher = Watcher::new(sync_config).await?;
+            let (root, config_path) = (watcher.parent.clone(), watcher.path.clone());
+            // diesel is sync and so is this thread, it gets a connection of its own
+            let mut database = ClientDatabase::new(None).await?;
+            let queued = queued.clone();
+
+            info!("Watching {:?} as {}", root, syncr_id);
+
+            // the watcher blocks on its events, so it gets a thread of its own
+            std::thread::spawn(move || {
+                let run = watcher.run(move |event| {
+                    // removals have nothing left to send
+                    if matches!(event.kind, EventKind::Remove(_)) {
+                        return;
+                    }
+
+                    for path in event.paths {
+                        if path == config_path || !path.is_file() {
+                            continue;
+                        }
+                        let Ok(relative) = path.strip_prefix(&root) else {
+                            continue;
+                        };
+
+                        let known_name = relative.to_string_lossy().replace('\\', "/");
+                        match SyncJob::enqueue(
+                            path.to_string_lossy().into_owned(),
+                            syncr_id.clone(),
+                            known_name,
+                            &mut database,
+                        ) {
+                            Ok(()) => queued.notify_one(),
+                            Err(e) => warn!("Unable to queue {:?}: {e}", path),
+                        }
+                    }
+                });
+
+                if let Err(e) = futures::executor::block_on(run) {
+                    warn!("Watcher stopped: {e}");
+                }
+            });
+        }
+
+        Ok(queued)
+    }
+
+    // keeps replaying the job queue, forever, `queued` cuts the wait short when something new came in
+    pub async fn run(&mut self, queued: Arc<Notify>) {
+        loop {
+            if let Err(e) = self.adopt_rotated_secret().await {
+                warn!("Unable to switch to the server's new secret: {e}");
+            }
+
+            if let Err(e) = self.replay().await {
+                warn!("Unable to replay queued changes: {e}");
+            }
+
+            tokio::select! {
+                _ = tokio::time::sleep(QUEUE_POLL_INTERVAL) => {}
+                _ = queued.notified() => {}
+            }
+        }
+    }
+
+    // queues a change to be synced, it survives restarts and lost connections
+    pub fn enqueue(
+        &mut self,
+        path: &Path,
+        syncr_id: &str,
+        known_name: &str,
+    ) -> Result<(), anyhow::Error> {
+        SyncJob::enqueue(
+            path.to_string_lossy().into_owned(),
+            syncr_id.to_owned(),
+            known_name.to_owned(),
+            &mut self.database,
+        )
+    }
+
+    // sends every pending job, started in the order it was queued, reconnecting whenever
+    // the connection drops out from under us, until no job is due anymore
+    //
+    // jobs for the same file are collapsed into one sync, so a file is never sent twice at
+    // once, a change that comes in while it's being sent keeps its job queued for the next round
+    //
+    // a job that failed waits exponentially longer before each retry, see due
+    pub async fn replay(&mut self) -> Result<(), anyhow::Error> {
+        let max_attempts = self.config.as_client()?.client().max_job_attempts;
+
+        loop {
+            let now = chrono::Utc::now().naive_utc();
+            let mut files: IndexMap<(String, String), (PathBuf, Vec<SyncJob>)> = IndexMap::new();
+            for job in SyncJob::pending(&mut self.database)? {
+                if !Self::due(&job, now) {
+                    continue;
+                }
+
+                let (path, jobs) = files
+                    .entry((job.syncr_id.clone(), job.known_name.clone()))
+                    .or_default();
+                // the latest job knows best where the file is now
+                *path = PathBuf::from(&job.path);
+                jobs.push(job);
+            }
+            if files.is_empty() {
+                return Ok(());
+            }
+
+            self.reconnect().await?;
+
+            let changes = files
+                .iter()
+                .map(|((syncr_id, known_name), (path, _))| {
+                    (path.clone(), syncr_id.clone(), known_name.clone())
+                })
+                .collect();
+            let results = self.sync_all(changes).await;
+            let connected = self.connection.as_ref().is_some_and(Connection::is_alive);
+
+            for ((_, jobs), result) in files.into_values().zip(results) {
+                for job in jobs {
+                    match &result {
+                        Ok(()) => {
+                            if !job.complete(&mut self.database)? {
+                                info!("{} changed again while syncing, sending it again", job.path);
+                            }
+                        }
+                        // the connection went away, that's not the job's fault, it goes again
+                        Err(_) if !connected => {}
+                        Err(e) => {
+                            warn!("Syncing {} failed: {e}", job.path);
+                            job.record_failure(e, max_attempts, &mut self.database)?;
+                        }
+                    }
+                }
+            }
+        }
+    }
+
+    // whether a job is past the delay its failed attempts earned it
+    fn due(job: &SyncJob, now: chrono::NaiveDateTime) -> bool {
+        if job.attempts <= 0 {
+            return true;
+        }
+
+        let delay = JOB_RETRY_BASE_DELAY
+            .saturating_mul(2u32.saturating_pow(job.attempts as u32 - 1))
+            .min(JOB_RETRY_MAX_DELAY);
+
+        chrono::Duration::from_std(delay).is_ok_and(|delay| job.updated_at + delay <= now)
+    }
+
+    // syncs every given file at once, up to max-concurrent-transfers in flight
+    // started in order, and results come back in the same order as `files`
+    //
+    // each file has to be in there only once, the server turns a second transaction
+    // for a file that's still being sent away as busy
+    pub async fn sync_all(&self, files: Vec<Change>) -> Vec<Result<(), anyhow::Error>> {
+        let limit = self
+            .config
+            .as_client()
+            .map(|client_ref| client_ref.client().max_concurrent_transfers)
+            .unwrap_or(1)
+            .max(1);
+
+        stream::iter(files)
+            .map(|(path, syncr_id, known_name)| async move {
+                self.sync(&path, &syncr_id, &known_name).await
+            })
+            .buffered(limit)
+            .collect()
+            .await
+    }
+
+    // SDLB -> SDLC... -> SDLE, the delta is generated on a blocking thread and sent
+    // out as it comes so only a couple of chunks are ever held in memory
+    //
+    // returns the hash of the file as the delta saw it, the server checks its result against it
+    async fn stream_delta(
+        transaction: &Transaction,
+        path: &Path,
+        signature: Vec<u8>,
+        compress: bool,
+    ) -> Result<blake3::Hash, anyhow::Error> {
+        let file = File::options().read(true).open(path)?;
+        let new_file_size = file.metadata()?.len();
+        let mut compressor = Compressor::for_file(compress, &file)?;
+
+        transaction
+            .send(&SyncDeltaBeginPacket::build(new_file_size))
+            .await?;
+
+        let (chunks_tx, mut chunks_rx) = mpsc::channel(DELTA_CHANNEL_SIZE);
+        let producer =
+            tokio::task::spawn_blocking(move || sync::stream_delta(&file, signature, chunks_tx));
+
+        let mut hasher = blake3::Hasher::new();
+        while let Some(chunk) = chunks_rx.recv().await {
+            hasher.update(&chunk);
+
+            let packet;
+            (packet, compressor) = Self::compress_chunk(compressor, chunk).await?;
+            transaction.send(&packet).await?;
+        }
+        let file_hash = producer.await??;
+
+        if compress {
+            info!("Delta for {:?} compressed {:.2}x", path, compressor.ratio());
+        }
+
+        transaction
+            .send(&SyncDeltaEndPacket::build((hasher.finalize(), file_hash)))
+            .await?;
+
+        Ok(file_hash)
+    }
+
+    // zstd on a whole chunk takes long enough that it shouldn't run on the runtime's threads
+    async fn compress_chunk(
+        mut compressor: Compressor,
+        chunk: Vec<u8>,
+    ) -> Result<(SyncDeltaChunkPacket, Compressor), anyhow::Error> {
+        tokio::task::spawn_blocking(move || {
+            let packet = match compressor.compress(&chunk)? {
+                Some(compressed) => SyncDeltaChunkPacket::build((compressed, true)),
+                None => SyncDeltaChunkPacket::build((chunk, false)),
+            };
+
+            Ok((packet, compressor))
+        })
+        .await?
+    }
+
+    // sends the file from `offset` onwards, one mmapped FORCE_CHUNK_SIZE slice per FRCE,
+    // `file_hash` is the version of the file the server asked for
+    async fn force(
+        transaction: &Transaction,
+        path: &Path,
+        offset: u64,
+        file_hash: blake3::Hash,
+        compress: bool,
+    ) -> Result<(), anyhow::Error> {
+        let file = File::options().read(true).open(path)?;
+        let file_size = file.metadata()?.len();
+        let mut compressor = Compressor::for_file(compress, &file)?;
+
+        if offset > file_size {
+            anyhow::bail!(
+                "Server has {} bytes of a {} byte file, it changed under us",
+                offset,
+                file_size
+            );
+        }
+
+        let mut offset = offset;
+        while offset < file_size {
+            let len = FORCE_CHUNK_SIZE.min(file_size - offset);
+            let chunk = unsafe {
+                MmapOptions::new()
+                    .offset(offset)
+                    .len(len as usize)
+                    .map(&file)?
+            };
+
+            // hashing and compressing 16MiB is blocking work too
+            let packet;
+            (packet, compressor) = tokio::task::spawn_blocking(move || {
+                let packet = SyncForcePacket::build((chunk, offset, file_size, file_hash))
+                    .compress(&mut compressor)?;
+
+                Ok::<_, anyhow::Error>((packet, compressor))
+            })
+            .await??;
+
+            transaction.send_mmap(&packet).await?;
+            offset += len;
+        }
+
+        if compress {
+            info!("{:?} compressed {:.2}x", path, compressor.ratio());
+        }
+
+        Ok(())
+    }
+
+    // runs a whole SYNC transaction for a single file, INIT -> SACK/RSUM -> SDLB..SDLE/FRCE... -> SACK
+    // (or SDLE -> RSUM -> FRCE... -> SACK when the delta didn't check out on the server)
+    pub async fn sync(
+        &self,
+        path: &Path,
+        syncr_id: &str,
+        known_name: &str,
+    ) -> Result<(), anyhow::Error> {
+        let hash = hash_file(path)?;
+        let connection = self.connection()?;
+        let compress = connection
+            .capabilities()
+            .contains(Capabilities::COMPRESSION);
+        let mut transaction = connection.open().await?;
+
+        transaction
+            .send(&SyncInitPacket::build((
+                hash,
+                syncr_id.to_owned(),
+                known_name.to_owned(),
+            )))
+            .await?;
+
+        // Some(hash of what was sent) if we went the delta route
+        let delta = match transaction.receive().await? {
+            Packets::SyncAck(SyncAcknowledgePacket { ack: false, .. }) => {
+                info!("{} already in sync", known_name);
+                return Ok(());
+            }
+            Packets::SyncAck(SyncAcknowledgePacket {
+                ack: true,
+                data: Some(data),
+            }) => Some(Self::stream_delta(&transaction, path, data.signature, compress).await?),
+            Packets::SyncResume(resume) => {
+                if resume.offset > 0 {
+                    info!("Resuming {} from byte {}", known_name, resume.offset);
+                }
+
+                Self::force(&transaction, path, resume.offset, hash, compress).await?;
+                None
+            }
+            other => anyhow::bail!("Expected SACK, got {}", other.name()),
+        };
+
+        let mut reply = transaction.receive().await?;
 
-        let message = b"goodbye world";
-        let sanity_packet = SanityPacket::build(message.to_vec());
-        sanity_packet.write(&mut *self.stream).await.unwrap();
+        // the delta didn't rebuild into our file on the server's end, it wants the whole thing
+        if let (Some(file_hash), Packets::SyncResume(resume)) = (delta, &reply) {
+            warn!(
+                "{} failed verification after the delta, sending all of it",
+                known_name
+            );
 
-        let mut buf = vec![0; 4096];
-        self.stream.read(&mut buf).await.unwrap();
+            Self::force(&transaction, path, resume.offset, file_hash, compress).await?;
+            reply = transaction.receive().await?;
+        }
 
-        info!("{}", std::str::from_utf8(&buf).unwrap());
+        match reply {
+            Packets::SyncAck(SyncAcknowledgePacket { ack: false, .. }) => {
+                info!("{} synced", known_name);
+                Ok(())
+            }
+            other => anyhow::bail!("Expected final SACK, got {}", other.name()),
+        }
     }
 }
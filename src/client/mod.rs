@@ -1,7 +1,14 @@
 // pub mod handlers;
+mod backoff;
+mod command;
+mod connection;
 mod database;
 mod init;
+mod pairing;
 pub mod tray;
 pub mod watcher;
 
-pub(crate) use init::Client;
+pub use connection::{Connection, Transaction};
+pub use database::ClientDatabase;
+pub use init::Client;
+pub use pairing::pair;
@@ -0,0 +1,80 @@
+use std::sync::Arc;
+
+use log::info;
+
+use crate::common::config::Config;
+use crate::common::identity;
+use crate::common::packets::{
+    CONNECTION, Capabilities, FrameLimits, HelloPacket, PacketBase, Packets, read_frame,
+};
+use crate::common::pairing::{Code, Greeting};
+use crate::common::stream::{Credentials, Role, SecureStream};
+use crate::data::DatabaseDriver;
+use crate::data::entities::known_device::{self, KnownDevice};
+
+use super::database::ClientDatabase;
+use super::init::Client;
+
+// `syncr pair <code>`, proves to the server we were handed a one-time code, trades
+// identities with it and keeps the deployment's secret in the config from then on
+pub async fn pair(mut config: Config, code: &str) -> Result<(), anyhow::Error> {
+    let code = Code::parse(code)?;
+    let credentials = Credentials {
+        keypair: Arc::new(identity::load_or_create(None)?),
+        psk: code.psk().await?,
+    };
+
+    let (mut transport, _, binding) = Client::open(&config).await?;
+    let greeting = Greeting::Pair {
+        code_id: code.id.clone(),
+    };
+    greeting.write(&mut transport).await?;
+
+    // nothing to announce yet, we'll say which syncr_ids we have once we connect for real
+    let hello = HelloPacket::build((
+        config.device_name.clone(),
+        Vec::new(),
+        Capabilities::local(),
+    ));
+    let stream = SecureStream::with_prologue(
+        transport,
+        Role::Initiator,
+        &credentials,
+        hello,
+        &greeting.prologue(&binding),
+    )
+    .await
+    .map_err(|e| anyhow::anyhow!("Pairing failed, the code is wrong, used or expired ({e})"))?;
+    let server_key = *stream.remote_key();
+
+    let (mut reader, _writer) = stream.split();
+    let pairing = match read_frame(&mut reader, &FrameLimits::default()).await? {
+        (_, Err(e)) => return Err(e.into()),
+        (CONNECTION, Ok(Packets::Pairing(pairing))) => pairing,
+        (CONNECTION, Ok(Packets::Error(error))) => {
+            anyhow::bail!(
+                "Server refused to pair ({:?}): {}",
+                error.code,
+                error.message
+            )
+        }
+        (_, Ok(other)) => anyhow::bail!("Expected PAIR, got {}", other.name()),
+    };
+
+    // the code vouches for the server just as much as it does for us
+    let server = Client::server_name(config.as_client()?.client());
+    let mut database = ClientDatabase::new(None).await?;
+    KnownDevice::pin(&server, &server_key, &mut database)?;
+
+    config.secret = pairing.secret;
+    config.salt = pairing.salt;
+    config.save()?;
+
+    info!(
+        "Paired with {} (key {}), the config now has its secret",
+        server,
+        known_device::fingerprint(&server_key)
+    );
+
+    Ok(())
+}
@@ -12,7 +12,7 @@ use std::{
     sync::mpsc::{Receiver, Sender},
 };
 
-use notify::{Config, FsEventWatcher, RecursiveMode};
+use notify::{Config, RecommendedWatcher, RecursiveMode};
 
 use crate::common::config::SyncConfig;
 
@@ -25,7 +25,7 @@ pub struct Watcher {
     pub path: PathBuf,
     pub parent: PathBuf,
     pub config: SyncConfig,
-    pub watcher: FsEventWatcher,
+    pub watcher: RecommendedWatcher,
     patterns: GlobSet,
     recv: Option<Receiver<notify::Result<Event>>>,
 }
@@ -0,0 +1,175 @@
+use std::{fs::File, io::Read, time::Instant};
+
+use anyhow::Context;
+
+// below this it's not worth the frame overhead (or the cpu)
+const MIN_COMPRESS_SIZE: usize = 512;
+// how much of a payload is looked at to guess its entropy
+const ENTROPY_SAMPLE_SIZE: usize = 64 * 1024;
+// bits per byte, random or already compressed data sits right under 8
+const MAX_ENTROPY: f64 = 7.5;
+// compressed output has to be at most this much of the input to be sent compressed
+const MIN_SAVING: f64 = 0.95;
+// payloads in a row that didn't compress before we stop trying for this file
+const MAX_MISSES: u32 = 3;
+
+// zstd levels the adaptive selection moves between
+const MIN_LEVEL: i32 = 1;
+const MAX_LEVEL: i32 = 12;
+const START_LEVEL: i32 = 3;
+// compression throughput (bytes/s) under which we go a level down, and over which we go up
+const SLOW_THROUGHPUT: f64 = 50.0 * 1024.0 * 1024.0;
+const FAST_THROUGHPUT: f64 = 200.0 * 1024.0 * 1024.0;
+
+// leading bytes of formats that are already compressed, (offset, magic)
+const COMPRESSED_MAGIC: &[(usize, &[u8])] = &[
+    (0, &[0x28, 0xb5, 0x2f, 0xfd]),             // zstd
+    (0, &[0x1f, 0x8b]),                         // gzip
+    (0, &[0xfd, b'7', b'z', b'X', b'Z', 0x00]), // xz
+    (0, b"BZh"),                                // bzip2
+    (0, &[0x04, 0x22, 0x4d, 0x18]),             // lz4
+    (0, &[b'P', b'K', 0x03, 0x04]),             // zip (and docx, jar, apk...)
+    (0, &[b'7', b'z', 0xbc, 0xaf, 0x27, 0x1c]), // 7z
+    (0, b"Rar!\x1a\x07"),                       // rar
+    (0, &[0x89, b'P', b'N', b'G']),             // png
+    (0, &[0xff, 0xd8, 0xff]),                   // jpeg
+    (0, b"GIF8"),                               // gif
+    (8, b"WEBP"),                               // webp
+    (4, b"ftyp"),                               // mp4, mov, heic
+    (0, b"OggS"),                               // ogg
+    (0, b"fLaC"),                               // flac
+    (0, b"ID3"),                                // mp3
+];
+// the longest offset + magic above, how much of a file's head we need
+const MAGIC_LEN: usize = 12;
+
+// compresses the payloads of one transfer, going up or down in level depending on
+// how fast it's keeping up, and giving up on data that won't compress
+pub struct Compressor {
+    enabled: bool,
+    level: i32,
+    misses: u32,
+    raw: u64,
+    wire: u64,
+}
+
+impl Compressor {
+    // `enabled` is whether the connection negotiated COMPRESSION at all
+    pub fn new(enabled: bool) -> Self {
+        Self {
+            enabled,
+            level: START_LEVEL,
+            misses: 0,
+            raw: 0,
+            wire: 0,
+        }
+    }
+
+    // same, but skips files that already start like a compressed format
+    pub fn for_file(enabled: bool, file: &File) -> Result<Self, anyhow::Error> {
+        if !enabled {
+            return Ok(Self::new(false));
+        }
+
+        let mut head = Vec::with_capacity(MAGIC_LEN);
+        file.take(MAGIC_LEN as u64).read_to_end(&mut head)?;
+
+        Ok(Self::new(!looks_compressed(&head)))
+    }
+
+    // Some(compressed) if `data` is worth sending compressed, None to send it as is
+    pub fn compress(&mut self, data: &[u8]) -> Result<Option<Vec<u8>>, anyhow::Error> {
+        self.raw += data.len() as u64;
+
+        if !self.enabled || data.len() < MIN_COMPRESS_SIZE || high_entropy(data) {
+            self.wire += data.len() as u64;
+            return Ok(None);
+        }
+
+        let start = Instant::now();
+        let compressed = zstd::bulk::compress(data, self.level).context("Failed to compress")?;
+        self.adapt(data.len(), start.elapsed().as_secs_f64());
+
+        if compressed.len() as f64 > data.len() as f64 * MIN_SAVING {
+            self.misses += 1;
+            if self.misses >= MAX_MISSES {
+                // it's not going to get any better, stop wasting time on it
+                self.enabled = false;
+            }
+
+            self.wire += data.len() as u64;
+            return Ok(None);
+        }
+
+        self.misses = 0;
+        self.wire += compressed.len() as u64;
+        Ok(Some(compressed))
+    }
+
+    // a fast machine creeps up to better ratios, a slow one backs off before
+    // compression becomes the bottleneck instead of the link
+    fn adapt(&mut self, len: usize, elapsed: f64) {
+        let throughput = len as f64 / elapsed.max(f64::EPSILON);
+
+        if throughput < SLOW_THROUGHPUT {
+            self.level = (self.level - 1).max(MIN_LEVEL);
+        } else if throughput > FAST_THROUGHPUT {
+            self.level = (self.level + 1).min(MAX_LEVEL);
+        }
+    }
+
+    // raw bytes per byte that actually went out, 1.0 when nothing was compressed
+    pub fn ratio(&self) -> f64 {
+        match self.wire {
+            0 => 1.0,
+            wire => self.raw as f64 / wire as f64,
+        }
+    }
+}
+
+// undoes Compressor::compress, refusing to inflate past `max_len` (no zstd bombs)
+pub fn decompress(data: &[u8], max_len: usize) -> Result<Vec<u8>, anyhow::Error> {
+    let decoder = zstd::stream::read::Decoder::new(data)?;
+
+    let mut decompressed = Vec::new();
+    decoder
+        .take(max_len as u64 + 1)
+        .read_to_end(&mut decompressed)
+        .context("Failed to decompress")?;
+
+    if decompressed.len() > max_len {
+        anyhow::bail!("Payload decompresses past {} bytes", max_len);
+    }
+
+    Ok(decompressed)
+}
+
+fn looks_compressed(head: &[u8]) -> bool {
+    COMPRESSED_MAGIC.iter().any(|&(offset, magic)| {
+        head.get(offset..offset + magic.len())
+            .is_some_and(|bytes| bytes == magic)
+    })
+}
+
+// shannon entropy of the first ENTROPY_SAMPLE_SIZE bytes, good enough to spot
+// data that's already compressed (or encrypted) whatever its format
+fn high_entropy(data: &[u8]) -> bool {
+    let sample = &data[..data.len().min(ENTROPY_SAMPLE_SIZE)];
+
+    let mut counts = [0u32; 256];
+    for &byte in sample {
+        counts[byte as usize] += 1;
+    }
+
+    let len = sample.len() as f64;
+    let entropy: f64 = counts
+        .iter()
+        .filter(|&&count| count > 0)
+        .map(|&count| {
+            let p = count as f64 / len;
+            -p * p.log2()
+        })
+        .sum();
+
+    entropy > MAX_ENTROPY
+}
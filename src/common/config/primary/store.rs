@@ -1,6 +1,6 @@
 use std::{
     ops::{Deref, DerefMut},
-    path::PathBuf,
+    path::{Path, PathBuf},
 };
 
 use super::structure::{ClientConfig, ConfigTOML, ModeConfig, ServerConfig};
@@ -38,8 +38,9 @@ impl Config {
         })
     }
 
-    pub fn as_ref(&self) -> &ConfigTOML {
-        &self.cached
+    // where it was read from, and where save() writes it back to
+    pub fn path(&self) -> &Path {
+        &self.path
     }
 
     pub fn as_mut_ref(&mut self) -> &mut ConfigTOML {
@@ -90,6 +91,12 @@ impl Config {
     }
 }
 
+impl AsRef<ConfigTOML> for Config {
+    fn as_ref(&self) -> &ConfigTOML {
+        &self.cached
+    }
+}
+
 impl Deref for Config {
     type Target = ConfigTOML;
 
@@ -1,12 +1,15 @@
 use std::{
+    collections::HashMap,
     net::IpAddr,
     ops::{Deref, DerefMut},
     path::PathBuf,
 };
 
-use fixedstr::zstr;
 use serde::{Deserialize, Serialize};
 
+use crate::common::heartbeat::{DEFAULT_HEARTBEAT_INTERVAL, DEFAULT_HEARTBEAT_TIMEOUT};
+use crate::common::packets::{DEFAULT_MAX_FILE_SIZE, DEFAULT_MAX_FRAME_SIZE};
+
 #[derive(Deserialize, Serialize, Default, Debug)]
 pub struct ConfigTOML {
     config: ConfigInner,
@@ -28,11 +31,30 @@ impl DerefMut for ConfigTOML {
 
 #[derive(Deserialize, Serialize, Debug)]
 pub struct ConfigInner {
-    pub secret: zstr<32>,
+    // passphrase the psk is derived from, any length
+    pub secret: String,
+
+    // per deployment, has to be the same on every device (see common::psk)
+    #[serde(default)]
+    pub salt: String,
+
+    // lets an empty or placeholder secret through, never set this outside of testing
+    #[serde(rename = "insecure-secret", default)]
+    pub insecure_secret: bool,
+
+    // how this device introduces itself, its identity key is pinned under this name
+    #[serde(rename = "device-name", default = "default_device_name")]
+    pub device_name: String,
 
     #[serde(rename = "auto-wonder")]
     pub auto_wonder: bool,
 
+    // seconds between PINGs, and of silence before the peer is considered dead
+    #[serde(rename = "heartbeat-interval", default = "default_heartbeat_interval")]
+    pub heartbeat_interval: u64,
+    #[serde(rename = "heartbeat-timeout", default = "default_heartbeat_timeout")]
+    pub heartbeat_timeout: u64,
+
     #[serde(flatten)]
     pub mode_config: ModeConfig,
 }
@@ -40,8 +62,14 @@ pub struct ConfigInner {
 impl Default for ConfigInner {
     fn default() -> Self {
         Self {
-            secret: "password".into(),
+            secret: "change-me".into(),
+            // has to be shared by every device, so there's nothing sensible to default to
+            salt: String::new(),
+            insecure_secret: false,
+            device_name: default_device_name(),
             auto_wonder: true,
+            heartbeat_interval: default_heartbeat_interval(),
+            heartbeat_timeout: default_heartbeat_timeout(),
             mode_config: ModeConfig::Client {
                 client: ClientConfig::default(),
             },
@@ -49,6 +77,24 @@ impl Default for ConfigInner {
     }
 }
 
+fn default_device_name() -> String {
+    std::env::var("HOSTNAME")
+        .or_else(|_| std::env::var("COMPUTERNAME"))
+        .or_else(|_| std::fs::read_to_string("/etc/hostname"))
+        .map(|name| name.trim().to_owned())
+        .ok()
+        .filter(|name| !name.is_empty())
+        .unwrap_or_else(|| "syncr".to_owned())
+}
+
+fn default_heartbeat_interval() -> u64 {
+    DEFAULT_HEARTBEAT_INTERVAL
+}
+
+fn default_heartbeat_timeout() -> u64 {
+    DEFAULT_HEARTBEAT_TIMEOUT
+}
+
 #[derive(Deserialize, Serialize, Debug)]
 #[serde(tag = "mode")]
 pub enum ModeConfig {
@@ -70,6 +116,131 @@ impl Default for ModeConfig {
 pub struct ServerConfig {
     pub ip: IpAddr,
     pub port: u16,
+
+    // largest packet a client may announce, in bytes, the DATA of a FRCE chunk
+    // included (clients send 16MiB of a file per FRCE, anything below that is refused)
+    #[serde(rename = "max-frame-size", default = "default_max_frame_size")]
+    pub max_frame_size: u64,
+
+    // largest file a client may upload, in bytes
+    #[serde(rename = "max-file-size", default = "default_max_file_size")]
+    pub max_file_size: u64,
+
+    // how many transactions a single connection may have in flight
+    #[serde(
+        rename = "max-concurrent-transactions",
+        default = "default_max_concurrent_transactions"
+    )]
+    pub max_concurrent_transactions: usize,
+
+    // seconds a single transaction may stay open before the whole connection is cut
+    #[serde(
+        rename = "transaction-deadline",
+        default = "default_transaction_deadline"
+    )]
+    pub transaction_deadline: u64,
+
+    // seconds open transactions get to wrap up after SIGTERM/SIGINT before they're aborted
+    #[serde(
+        rename = "shutdown-grace-period",
+        default = "default_shutdown_grace_period"
+    )]
+    pub shutdown_grace_period: u64,
+
+    // also listen for QUIC clients on the same ip and port (over udp)
+    #[serde(default)]
+    pub quic: bool,
+
+    // connections open at once, handshakes in progress included, everyone past it is turned away
+    #[serde(rename = "max-connections", default = "default_max_connections")]
+    pub max_connections: usize,
+
+    // same, but from a single ip
+    #[serde(
+        rename = "max-connections-per-ip",
+        default = "default_max_connections_per_ip"
+    )]
+    pub max_connections_per_ip: usize,
+
+    // handshakes a single ip may fail per minute, the one failing after that gets it banned
+    #[serde(
+        rename = "handshakes-per-minute",
+        default = "default_handshakes_per_minute"
+    )]
+    pub handshakes_per_minute: usize,
+
+    // seconds an ip stays banned for
+    #[serde(rename = "ban-duration", default = "default_ban_duration")]
+    pub ban_duration: u64,
+
+    // seconds a connection gets to finish its handshake before it's dropped
+    #[serde(rename = "handshake-timeout", default = "default_handshake_timeout")]
+    pub handshake_timeout: u64,
+
+    // where synced files are kept, one directory per syncr_id in here, ~/.syncr/storage if unset
+    #[serde(rename = "storage-root", default)]
+    pub storage_root: Option<PathBuf>,
+
+    // syncr_id -> directory, for repositories that should live somewhere of their own
+    #[serde(default)]
+    pub storage: HashMap<String, PathBuf>,
+
+    // older secrets still let in next to `secret` while clients move over, tried in order
+    // after it, clients that connect with one of these are handed `secret` (SIGHUP reloads)
+    #[serde(rename = "accepted-secrets", default)]
+    pub accepted_secrets: Vec<AcceptedSecret>,
+}
+
+#[derive(Deserialize, Serialize, Debug, Clone)]
+pub struct AcceptedSecret {
+    pub secret: String,
+    // a full date and time with an offset, e.g. 2026-11-01T00:00:00Z, never expires if unset
+    #[serde(default)]
+    pub expires: Option<toml::value::Datetime>,
+}
+
+fn default_max_frame_size() -> u64 {
+    DEFAULT_MAX_FRAME_SIZE
+}
+
+fn default_max_file_size() -> u64 {
+    DEFAULT_MAX_FILE_SIZE
+}
+
+fn default_max_concurrent_transactions() -> usize {
+    16
+}
+
+fn default_transaction_deadline() -> u64 {
+    60 * 60
+}
+
+fn default_shutdown_grace_period() -> u64 {
+    30
+}
+
+fn default_max_connections() -> usize {
+    256
+}
+
+fn default_max_connections_per_ip() -> usize {
+    16
+}
+
+fn default_handshakes_per_minute() -> usize {
+    30
+}
+
+fn default_ban_duration() -> u64 {
+    10 * 60
+}
+
+fn default_handshake_timeout() -> u64 {
+    10
+}
+
+fn default_max_concurrent_transfers() -> usize {
+    8
 }
 
 impl Default for ServerConfig {
@@ -77,6 +248,20 @@ impl Default for ServerConfig {
         Self {
             ip: IpAddr::V4(std::net::Ipv4Addr::new(0, 0, 0, 0)),
             port: 7878,
+            max_frame_size: default_max_frame_size(),
+            max_file_size: default_max_file_size(),
+            max_concurrent_transactions: default_max_concurrent_transactions(),
+            transaction_deadline: default_transaction_deadline(),
+            shutdown_grace_period: default_shutdown_grace_period(),
+            quic: false,
+            max_connections: default_max_connections(),
+            max_connections_per_ip: default_max_connections_per_ip(),
+            handshakes_per_minute: default_handshakes_per_minute(),
+            ban_duration: default_ban_duration(),
+            handshake_timeout: default_handshake_timeout(),
+            storage_root: None,
+            storage: HashMap::new(),
+            accepted_secrets: Vec::new(),
         }
     }
 }
@@ -89,7 +274,53 @@ pub struct ClientConfig {
     #[serde(rename = "server-port")]
     pub server_port: u16,
 
+    // when set, this command is spawned and its stdin/stdout are used instead of
+    // connecting to server-ip:server-port, e.g. ["ssh", "host", "syncr", "serve", "--stdio"]
+    #[serde(rename = "server-command", default)]
+    pub server_command: Option<Vec<String>>,
+
+    // what we reach server-ip:server-port over, the server needs quic enabled for "quic"
+    #[serde(default)]
+    pub transport: TransportKind,
+
     pub directories: Vec<Directory>,
+
+    // how many files we sync at once, keep it at or below the server's
+    // max-concurrent-transactions or the extra ones will be bounced as busy
+    #[serde(
+        rename = "max-concurrent-transfers",
+        default = "default_max_concurrent_transfers"
+    )]
+    pub max_concurrent_transfers: usize,
+
+    // longest we wait between reconnection attempts, in seconds
+    #[serde(
+        rename = "reconnect-max-delay",
+        default = "default_reconnect_max_delay"
+    )]
+    pub reconnect_max_delay: u64,
+
+    // how many times a queued change is retried before it's marked as failed
+    #[serde(rename = "max-job-attempts", default = "default_max_job_attempts")]
+    pub max_job_attempts: u32,
+}
+
+fn default_reconnect_max_delay() -> u64 {
+    60
+}
+
+fn default_max_job_attempts() -> u32 {
+    5
+}
+
+#[derive(Deserialize, Serialize, Default, Debug, Clone, Copy, PartialEq, Eq)]
+pub enum TransportKind {
+    #[default]
+    #[serde(rename = "tcp")]
+    Tcp,
+    // one stream per transaction, holds up a lot better on lossy links
+    #[serde(rename = "quic")]
+    Quic,
 }
 
 #[derive(Deserialize, Serialize, Debug)]
@@ -103,6 +334,8 @@ impl Default for ClientConfig {
         Self {
             server_ip: IpAddr::V4(std::net::Ipv4Addr::new(127, 0, 0, 1)),
             server_port: 7878,
+            server_command: None,
+            transport: TransportKind::default(),
             directories: Vec::from([
                 Directory {
                     path: PathBuf::from("~/Documents/enabled"),
@@ -113,6 +346,9 @@ impl Default for ClientConfig {
                     active: false,
                 },
             ]),
+            max_concurrent_transfers: default_max_concurrent_transfers(),
+            reconnect_max_delay: default_reconnect_max_delay(),
+            max_job_attempts: default_max_job_attempts(),
         }
     }
 }
@@ -38,10 +38,6 @@ impl SyncConfig {
         })
     }
 
-    pub fn as_ref(&self) -> &SyncConfigTOML {
-        &self.cached
-    }
-
     pub fn as_static_ref(&'static self) -> &'static SyncConfigTOML {
         &self.cached
     }
@@ -88,6 +84,12 @@ impl SyncConfig {
     }
 }
 
+impl AsRef<SyncConfigTOML> for SyncConfig {
+    fn as_ref(&self) -> &SyncConfigTOML {
+        &self.cached
+    }
+}
+
 impl Deref for SyncConfig {
     type Target = SyncConfigInner;
 
@@ -0,0 +1,32 @@
+use std::time::Duration;
+
+use super::config::structure::ConfigInner;
+
+pub const DEFAULT_HEARTBEAT_INTERVAL: u64 = 15; // seconds
+pub const DEFAULT_HEARTBEAT_TIMEOUT: u64 = 45; // seconds
+
+// how often we PING the peer, and how long it may stay completely silent
+// (no frames at all, PONGs included) before we call it dead and hang up
+#[derive(Debug, Clone, Copy)]
+pub struct Heartbeat {
+    pub interval: Duration,
+    pub timeout: Duration,
+}
+
+impl Heartbeat {
+    pub fn from_config(config: &ConfigInner) -> Self {
+        Self {
+            interval: Duration::from_secs(config.heartbeat_interval),
+            timeout: Duration::from_secs(config.heartbeat_timeout),
+        }
+    }
+}
+
+impl Default for Heartbeat {
+    fn default() -> Self {
+        Self {
+            interval: Duration::from_secs(DEFAULT_HEARTBEAT_INTERVAL),
+            timeout: Duration::from_secs(DEFAULT_HEARTBEAT_TIMEOUT),
+        }
+    }
+}
@@ -0,0 +1,53 @@
+use std::{fs, io::Write, path::PathBuf};
+
+use anyhow::Context;
+use log::info;
+
+use super::stream::{self, Keypair};
+
+// private key followed by public key, both 32 bytes
+const KEY_FILE_LEN: usize = 64;
+
+// this device's long-term Noise keypair, made on first run and kept in ~/.syncr/identity
+// from then on, peers pin its public key so losing the file means re-trusting the device
+pub fn load_or_create(path: Option<PathBuf>) -> Result<Keypair, anyhow::Error> {
+    let path = path
+        .or_else(|| dirs::home_dir().map(|dir| dir.join(".syncr").join("identity")))
+        .ok_or(anyhow::anyhow!(
+            "Unable to extract identity path, default home directory not found."
+        ))?;
+
+    if path.exists() {
+        let bytes = fs::read(&path).context("Failed to read identity")?;
+        if bytes.len() != KEY_FILE_LEN {
+            anyhow::bail!("{:?} is not a syncr identity, move it out of the way", path);
+        }
+
+        let (private, public) = bytes.split_at(KEY_FILE_LEN / 2);
+        return Ok(Keypair {
+            private: private.to_vec(),
+            public: public.to_vec(),
+        });
+    }
+
+    let keypair = stream::generate_keypair()?;
+    fs::create_dir_all(
+        path.parent()
+            .ok_or(anyhow::anyhow!("Unable to get parent dir"))?,
+    )?;
+
+    let mut options = fs::OpenOptions::new();
+    options.write(true).create_new(true);
+    // nobody but us has any business reading it
+    #[cfg(unix)]
+    std::os::unix::fs::OpenOptionsExt::mode(&mut options, 0o600);
+
+    let mut file = options.open(&path).context("Failed to create identity")?;
+    file.write_all(&keypair.private)?;
+    file.write_all(&keypair.public)?;
+    file.sync_all()?;
+
+    info!("Generated a new identity at {:?}", path);
+
+    Ok(keypair)
+}
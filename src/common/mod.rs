@@ -1,5 +1,13 @@
+pub mod compression;
 pub mod config;
+pub mod heartbeat;
+pub mod identity;
+pub mod idle;
 pub mod packets;
+pub mod pairing;
+pub mod psk;
+#[cfg(feature = "quic")]
+pub mod quic;
 pub mod stream;
 pub mod sync;
 
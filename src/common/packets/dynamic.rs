@@ -1,7 +1,8 @@
-use super::{SizePacket, StaticPacket};
-use log::info;
+use super::{FrameLimits, ProtocolError, SizePacket, StaticPacket};
+use log::trace;
 use tokio::io::{AsyncWrite, AsyncWriteExt};
 
+#[allow(async_fn_in_trait)]
 pub trait DynamicPacket:
     super::PacketBase + serde::de::DeserializeOwned + serde::Serialize
 {
@@ -12,7 +13,8 @@ pub trait DynamicPacket:
 
         let bytes = self.to_bytes();
 
-        info!("Writing packet: {:?}", bytes);
+        // payloads can carry secrets (PAIR), keep them out of the usual logs
+        trace!("Writing packet: {:?}", bytes);
 
         stream.write_all(&bytes).await?;
         stream.flush().await?;
@@ -25,15 +27,31 @@ pub trait DynamicPacket:
             packet_size: bincode::serialized_size(&self).unwrap() as u64,
         }
     }
-    fn make_buffer(size: &SizePacket) -> Result<Vec<u8>, anyhow::Error> {
-        let capacity = size.packet_size as usize;
-        let mut buffer = Vec::with_capacity(capacity);
-        buffer.resize(capacity, 0);
-        Ok(buffer)
+    fn make_buffer(size: &SizePacket, limits: &FrameLimits) -> Result<Vec<u8>, ProtocolError> {
+        // the size comes straight from the peer, check it before allocating anything
+        if size.packet_size > limits.max_frame_size {
+            return Err(ProtocolError::BadSize {
+                packet_type: *Self::TYPE,
+                reason: format!(
+                    "{} bytes exceeds the maximum frame size of {} bytes",
+                    size.packet_size, limits.max_frame_size
+                ),
+            });
+        }
+
+        let capacity = usize::try_from(size.packet_size).map_err(|_| ProtocolError::BadSize {
+            packet_type: *Self::TYPE,
+            reason: format!("{} bytes does not fit in memory", size.packet_size),
+        })?;
+
+        Ok(vec![0u8; capacity])
     }
 
-    fn from_bytes(bytes: &[u8]) -> Self {
-        bincode::deserialize(&bytes).expect("Failed to deserialize")
+    fn from_bytes(bytes: &[u8]) -> Result<Self, ProtocolError> {
+        bincode::deserialize(bytes).map_err(|e| ProtocolError::Decode {
+            packet_type: *Self::TYPE,
+            reason: e.to_string(),
+        })
     }
 
     fn to_bytes(&self) -> Vec<u8> {
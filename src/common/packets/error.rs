@@ -0,0 +1,96 @@
+use std::fmt;
+
+// everything that can go wrong while reading or interpreting packets from a peer
+//
+// fatal errors leave the stream in an unknown position (we don't know where the next
+// frame starts), so the connection has to go. the others only abort the transaction.
+#[derive(Debug)]
+pub enum ProtocolError {
+    UnknownType([u8; 4]),
+    BadSize {
+        packet_type: [u8; 4],
+        reason: String,
+    },
+    Decode {
+        packet_type: [u8; 4],
+        reason: String,
+    },
+    UnexpectedPacket {
+        expected: &'static str,
+        got: [u8; 4],
+    },
+    VersionMismatch {
+        local: u32,
+        remote: u32,
+    },
+    // went wrong partway through a packet, the rest of it is still on the stream
+    Malformed {
+        packet_type: [u8; 4],
+        reason: String,
+    },
+    Io(std::io::Error),
+}
+
+impl ProtocolError {
+    pub fn is_fatal(&self) -> bool {
+        !matches!(
+            self,
+            ProtocolError::Decode { .. } | ProtocolError::UnexpectedPacket { .. }
+        )
+    }
+}
+
+fn type_str(packet_type: &[u8; 4]) -> String {
+    String::from_utf8_lossy(packet_type).into_owned()
+}
+
+impl fmt::Display for ProtocolError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ProtocolError::UnknownType(packet_type) => {
+                write!(f, "unknown packet type {:?}", type_str(packet_type))
+            }
+            ProtocolError::BadSize {
+                packet_type,
+                reason,
+            } => write!(f, "bad size for {:?}: {}", type_str(packet_type), reason),
+            ProtocolError::Decode {
+                packet_type,
+                reason,
+            } => write!(
+                f,
+                "failed to decode {:?}: {}",
+                type_str(packet_type),
+                reason
+            ),
+            ProtocolError::UnexpectedPacket { expected, got } => {
+                write!(f, "expected {}, got {:?}", expected, type_str(got))
+            }
+            ProtocolError::VersionMismatch { local, remote } => write!(
+                f,
+                "protocol version mismatch (local {}, remote {})",
+                local, remote
+            ),
+            ProtocolError::Malformed {
+                packet_type,
+                reason,
+            } => write!(f, "malformed {:?}: {}", type_str(packet_type), reason),
+            ProtocolError::Io(e) => write!(f, "io error: {}", e),
+        }
+    }
+}
+
+impl std::error::Error for ProtocolError {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            ProtocolError::Io(e) => Some(e),
+            _ => None,
+        }
+    }
+}
+
+impl From<std::io::Error> for ProtocolError {
+    fn from(e: std::io::Error) -> Self {
+        ProtocolError::Io(e)
+    }
+}
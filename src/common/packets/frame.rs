@@ -0,0 +1,72 @@
+use tokio::{
+    io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt},
+    sync::Mutex,
+};
+
+use super::{DynamicPacket, FrameLimits, MmapPacket, Packets, ProtocolError, extract_packet};
+
+// every packet sent after the HELLO exchange is prefixed with the id of the
+// transaction it belongs to (a little endian u32), so several transactions can
+// interleave on the same stream
+pub type TransactionId = u32;
+
+// frames that aren't tied to any transaction (connection level errors, sanity...)
+pub const CONNECTION: TransactionId = 0;
+
+pub async fn write_frame_header<S: AsyncWrite + Unpin>(
+    stream: &mut S,
+    id: TransactionId,
+) -> std::io::Result<()> {
+    stream.write_all(&id.to_le_bytes()).await
+}
+
+// writes a whole frame while holding the lock, so frames from concurrent
+// writers (transactions, heartbeats, pushes) never end up interleaved
+pub async fn send_frame<S: AsyncWrite + Unpin, P: DynamicPacket>(
+    writer: &Mutex<S>,
+    id: TransactionId,
+    packet: &P,
+) -> Result<(), anyhow::Error> {
+    let mut writer = writer.lock().await;
+    write_frame_header(&mut *writer, id).await?;
+    packet.write(&mut *writer).await?;
+    writer.flush().await?;
+
+    Ok(())
+}
+
+pub async fn send_mmap_frame<S: AsyncWrite + Unpin, P: MmapPacket>(
+    writer: &Mutex<S>,
+    id: TransactionId,
+    packet: &P,
+) -> Result<(), anyhow::Error> {
+    let mut writer = writer.lock().await;
+    write_frame_header(&mut *writer, id).await?;
+    packet.write(&mut *writer).await?;
+    writer.flush().await?;
+
+    Ok(())
+}
+
+// reads the next frame, SIZE packets are consumed here since they are part of the frame
+//
+// Err only for fatal errors (see ProtocolError::is_fatal), a packet that was read to its
+// end but didn't make sense comes back along with the id of the transaction it was meant for
+pub async fn read_frame<S: AsyncRead + Unpin>(
+    stream: &mut S,
+    limits: &FrameLimits,
+) -> Result<(TransactionId, Result<Packets, ProtocolError>), ProtocolError> {
+    let mut id = [0u8; 4];
+    stream.read_exact(&mut id).await?;
+    let id = TransactionId::from_le_bytes(id);
+
+    let mut last_size_packet = None;
+    loop {
+        match extract_packet(stream, &mut last_size_packet, limits).await {
+            Ok(Packets::Size(_)) => continue,
+            Ok(packet) => return Ok((id, Ok(packet))),
+            Err(e) if e.is_fatal() => return Err(e),
+            Err(e) => return Ok((id, Err(e))),
+        }
+    }
+}
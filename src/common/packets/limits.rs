@@ -0,0 +1,27 @@
+// upper bounds for anything a peer can make us allocate or spool to disk,
+// checked against the announced size before a single byte is read
+#[derive(Debug, Clone, Copy)]
+pub struct FrameLimits {
+    // biggest dynamic packet body we accept
+    pub max_frame_size: u64,
+    // biggest DATA section of a mmap packet we accept
+    pub max_file_size: u64,
+}
+
+pub const DEFAULT_MAX_FRAME_SIZE: u64 = 64 * 1024 * 1024; // 64MiB
+pub const DEFAULT_MAX_FILE_SIZE: u64 = 16 * 1024 * 1024 * 1024; // 16GiB
+
+// how much of a file a single FRCE carries, also the most a dropped connection can lose
+pub const FORCE_CHUNK_SIZE: u64 = 16 * 1024 * 1024; // 16MiB
+// the least a server's max_frame_size may be, clients never learn the limit so anything
+// lower would turn away every full FRCE chunk they send
+pub const MIN_MAX_FRAME_SIZE: u64 = FORCE_CHUNK_SIZE;
+
+impl Default for FrameLimits {
+    fn default() -> Self {
+        Self {
+            max_frame_size: DEFAULT_MAX_FRAME_SIZE,
+            max_file_size: DEFAULT_MAX_FILE_SIZE,
+        }
+    }
+}
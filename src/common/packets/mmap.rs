@@ -1,20 +1,27 @@
 use std::{io::Write, ops::Deref};
 
-use super::StaticPacket;
+use super::{DynamicPacket, FrameLimits, ProtocolError, utils::read_header};
 use memmap2::Mmap;
 use tempfile::NamedTempFile;
 use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt, BufWriter};
 
+pub const MMAP_HEADER: &[u8; 4] = b"MMAP";
+
+#[allow(async_fn_in_trait)]
 pub trait MmapPacket: super::PacketBase + Sized + Deref<Target = Self::MmaplessPacket> {
-    type MmaplessPacket: StaticPacket;
+    // the STAT section, its TYPE is what identifies the mmap packet on the wire
+    type MmaplessPacket: DynamicPacket;
 
     // split the struct into a static packet and a mmap packet
     fn get_mmap(&self) -> &Mmap;
     fn get_mmapless(&self) -> &Self::MmaplessPacket;
 
+    // and glue it back together once it has been read
+    fn from_parts(mmap: Mmap, mmapless: Self::MmaplessPacket) -> Self;
+
     async fn write<S: AsyncWrite + Unpin>(&self, writer: &mut S) -> anyhow::Result<()> {
         let mut writer = BufWriter::new(writer);
-        writer.write_all(b"MMAP").await?; // declare a MMAP-like packet
+        writer.write_all(MMAP_HEADER).await?; // declare a MMAP-like packet
 
         writer.write_all(b"STAT").await?; // declare STATIC section
         let static_packet = self.get_mmapless();
@@ -24,7 +31,7 @@ pub trait MmapPacket: super::PacketBase + Sized + Deref<Target = Self::MmaplessP
         let mmap = self.get_mmap();
 
         writer.write_all(b"DATA").await?; // declare DATA section
-        writer.write_all(&mmap.len().to_le_bytes()).await?;
+        writer.write_all(&(mmap.len() as u64).to_le_bytes()).await?; // always a LE u64, whatever the arch
         writer.write_all(mmap.as_ref()).await?;
 
         writer.write_all(b"DONE").await?; // finish
@@ -34,34 +41,49 @@ pub trait MmapPacket: super::PacketBase + Sized + Deref<Target = Self::MmaplessP
         Ok(())
     }
 
-    async fn deserialize<S: AsyncRead + Unpin>(reader: &mut S) -> anyhow::Result<Self>;
-    async fn deserialize_inner<S: AsyncRead + Unpin>(
+    // reads the DATA section up to and including DONE, given that the STAT section was already read
+    async fn read_data<S: AsyncRead + Unpin>(
         reader: &mut S,
-    ) -> anyhow::Result<(Mmap, Self::MmaplessPacket)> {
-        // given that we've already read "MMAP" header from the stream, we should wait for a "STAT" header
-        let buf = read_header(reader).await?;
-        if buf != *b"STAT" {
-            return Err(anyhow::anyhow!("Expected STAT header"));
-        }
-
-        // read the static packet into bincode
-        let mut buf = Self::MmaplessPacket::make_buffer()?;
-        read_packet(reader, &mut buf).await?;
-        let static_packet = Self::MmaplessPacket::from_bytes(&buf);
-
+        limits: &FrameLimits,
+    ) -> Result<Mmap, ProtocolError> {
         // wait for data header
         let buf = read_header(reader).await?;
         if buf != *b"DATA" {
-            return Err(anyhow::anyhow!("Expected DATA header"));
+            return Err(ProtocolError::UnexpectedPacket {
+                expected: "DATA",
+                got: buf,
+            });
         }
 
         // read the size of the mmap
-        let mut buf = vec![0u8; 8];
-        read_packet(reader, &mut buf).await?;
-        let mmap_size = usize::from_le_bytes(
-            buf.try_into()
-                .map_err(|_| anyhow::anyhow!("Failed to convert mmap size to usize"))?,
-        );
+        let mut buf = [0u8; 8];
+        reader.read_exact(&mut buf).await?;
+        let mmap_size = u64::from_le_bytes(buf);
+
+        // a DATA section is one frame (a FRCE carries a chunk, never the whole file),
+        // so it's held to the frame limit on top of the file one
+        if mmap_size > limits.max_frame_size {
+            return Err(ProtocolError::BadSize {
+                packet_type: *<Self::MmaplessPacket as super::PacketBase>::TYPE,
+                reason: format!(
+                    "{} bytes exceeds the maximum frame size of {} bytes",
+                    mmap_size, limits.max_frame_size
+                ),
+            });
+        }
+        if mmap_size > limits.max_file_size {
+            return Err(ProtocolError::BadSize {
+                packet_type: *<Self::MmaplessPacket as super::PacketBase>::TYPE,
+                reason: format!(
+                    "{} bytes exceeds the maximum file transfer size of {} bytes",
+                    mmap_size, limits.max_file_size
+                ),
+            });
+        }
+        let mmap_size = usize::try_from(mmap_size).map_err(|_| ProtocolError::BadSize {
+            packet_type: *<Self::MmaplessPacket as super::PacketBase>::TYPE,
+            reason: format!("{} bytes does not fit in memory", mmap_size),
+        })?;
 
         // open a temporary file
         let mut temp_file = NamedTempFile::new()?;
@@ -69,9 +91,13 @@ pub trait MmapPacket: super::PacketBase + Sized + Deref<Target = Self::MmaplessP
         let mut temp_buf = vec![0u8; 4096];
         let mut bytes_read = 0;
         while bytes_read < mmap_size {
-            let n = reader.read(&mut temp_buf).await?;
+            let to_read = temp_buf.len().min(mmap_size - bytes_read);
+            let n = reader.read(&mut temp_buf[..to_read]).await?;
             if n == 0 {
-                return Err(anyhow::anyhow!("Unexpected EOF"));
+                return Err(ProtocolError::Io(std::io::Error::new(
+                    std::io::ErrorKind::UnexpectedEof,
+                    "Unexpected EOF",
+                )));
             }
             bytes_read += n;
             temp_file.write_all(&temp_buf[..n])?;
@@ -81,46 +107,12 @@ pub trait MmapPacket: super::PacketBase + Sized + Deref<Target = Self::MmaplessP
         // read the data header
         let buf = read_header(reader).await?;
         if buf != *b"DONE" {
-            return Err(anyhow::anyhow!("Expected DONE header"));
+            return Err(ProtocolError::UnexpectedPacket {
+                expected: "DONE",
+                got: buf,
+            });
         }
 
-        Ok((unsafe { Mmap::map(&temp_file)? }, static_packet))
+        Ok(unsafe { Mmap::map(&temp_file)? })
     }
 }
-
-pub async fn read_packet<T: AsyncRead + Unpin>(
-    stream: &mut T,
-    packet_buffer: &mut Vec<u8>,
-) -> Result<(), anyhow::Error> {
-    let expected_size = packet_buffer.capacity();
-
-    let mut bytes_read = 0;
-    while bytes_read < expected_size {
-        let n = stream.read(&mut packet_buffer[bytes_read..]).await?;
-        if n == 0 {
-            return Err(anyhow::anyhow!("Unexpected EOF"));
-        }
-        bytes_read += n;
-    }
-    Ok(())
-}
-
-pub async fn read_header<T: AsyncRead + Unpin>(stream: &mut T) -> std::io::Result<[u8; 4]> {
-    let expected_size = 4;
-    let mut buf = [0u8; 4];
-    buf.fill(0);
-
-    let mut bytes_read = 0;
-    while bytes_read < expected_size {
-        let n = stream.read(&mut buf[bytes_read..]).await?;
-        if n == 0 {
-            return Err(std::io::Error::new(
-                std::io::ErrorKind::UnexpectedEof,
-                "Unexpected EOF",
-            ));
-        }
-        bytes_read += n;
-    }
-
-    Ok(buf)
-}
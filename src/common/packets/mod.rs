@@ -1,22 +1,56 @@
 mod base;
 mod dynamic;
+mod error;
+mod frame;
+mod limits;
 mod mmap;
 mod r#static;
 mod utils;
 
 pub mod types;
 
-use utils::packet_buffer_mapper;
+use utils::packet_registry;
 
 pub use base::PacketBase;
 pub use dynamic::DynamicPacket;
+pub use error::ProtocolError;
+pub use frame::{
+    CONNECTION, TransactionId, read_frame, send_frame, send_mmap_frame, write_frame_header,
+};
+pub use limits::{
+    DEFAULT_MAX_FILE_SIZE, DEFAULT_MAX_FRAME_SIZE, FORCE_CHUNK_SIZE, FrameLimits,
+    MIN_MAX_FRAME_SIZE,
+};
+pub use mmap::MmapPacket;
 pub use r#static::StaticPacket;
-pub use utils::packetize;
+pub use utils::extract_packet;
 
-pub use types::{DynamicPackets, Packets, SanityPacket, SizePacket, StaticPackets};
+pub use types::{
+    Capabilities, ErrorPacket, HelloPacket, PairingPacket, PingPacket, PongPacket, RotatePacket,
+    SanityPacket, SizePacket, SyncAcknowledgePacket, SyncDeltaBeginPacket, SyncDeltaChunkPacket,
+    SyncDeltaEndPacket, SyncForcePacket, SyncInitPacket, SyncResumePacket,
+};
 
-packet_buffer_mapper!(
-    SizePacket
-    ;
-    SanityPacket
-);
+packet_registry! {
+    static {
+        Size(SizePacket),
+    }
+    dynamic {
+        Sanity(SanityPacket),
+        Error(ErrorPacket),
+        Hello(HelloPacket),
+        Ping(PingPacket),
+        Pong(PongPacket),
+        Pairing(PairingPacket),
+        Rotate(RotatePacket),
+        SyncInit(SyncInitPacket),
+        SyncAck(SyncAcknowledgePacket),
+        SyncDeltaBegin(SyncDeltaBeginPacket),
+        SyncDeltaChunk(SyncDeltaChunkPacket),
+        SyncDeltaEnd(SyncDeltaEndPacket),
+        SyncResume(SyncResumePacket),
+    }
+    mmap {
+        SyncForce(SyncForcePacket),
+    }
+}
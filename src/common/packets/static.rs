@@ -1,6 +1,9 @@
 use log::info;
 use tokio::io::{AsyncWrite, AsyncWriteExt};
 
+use super::ProtocolError;
+
+#[allow(async_fn_in_trait)]
 pub trait StaticPacket:
     super::PacketBase + std::default::Default + serde::de::DeserializeOwned + serde::Serialize
 {
@@ -15,16 +18,21 @@ pub trait StaticPacket:
         Ok(())
     }
 
-    fn make_buffer() -> Result<Vec<u8>, anyhow::Error> {
-        let serialized_size = bincode::serialized_size(&Self::default())? as usize;
-        let mut buffer = Vec::with_capacity(serialized_size);
-        buffer.resize(serialized_size, 0);
+    fn make_buffer() -> Result<Vec<u8>, ProtocolError> {
+        let serialized_size =
+            bincode::serialized_size(&Self::default()).map_err(|e| ProtocolError::BadSize {
+                packet_type: *Self::TYPE,
+                reason: e.to_string(),
+            })? as usize;
 
-        Ok(buffer)
+        Ok(vec![0u8; serialized_size])
     }
 
-    fn from_bytes(bytes: &[u8]) -> Self {
-        bincode::deserialize(&bytes).expect("Failed to deserialize")
+    fn from_bytes(bytes: &[u8]) -> Result<Self, ProtocolError> {
+        bincode::deserialize(bytes).map_err(|e| ProtocolError::Decode {
+            packet_type: *Self::TYPE,
+            reason: e.to_string(),
+        })
     }
 
     fn to_bytes(&self) -> Vec<u8> {
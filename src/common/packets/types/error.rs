@@ -0,0 +1,126 @@
+use serde::{Deserialize, Serialize};
+
+use super::{DynamicPacket, PacketBase};
+use crate::common::packets::ProtocolError;
+
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ErrorCode {
+    UnknownType,
+    BadSize,
+    Decode,
+    UnexpectedPacket,
+    VersionMismatch,
+    // too many transactions in flight on this connection, try again later
+    Busy,
+    // a transaction ran past the server's transaction-deadline
+    Timeout,
+    // the device isn't allowed to do that to this syncr_id
+    Forbidden,
+    // the server is going down, nothing new is taken on but the client can reconnect later
+    ShuttingDown,
+    // anything that isn't the peer's fault (io, disk, a failed delta apply...)
+    Internal,
+    // pairing under a device name that's already pinned to another key, an admin has to
+    // forget it first
+    NameTaken,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct ErrorPacket {
+    pub code: ErrorCode,
+    pub message: String,
+}
+
+// Sent right before a transaction (or the whole connection) is aborted,
+// so the other side knows why instead of just seeing the socket die
+impl PacketBase for ErrorPacket {
+    const TYPE: &'static [u8; 4] = b"EROR";
+    type BuildParams = (ErrorCode, String);
+
+    fn build(params: Self::BuildParams) -> Self {
+        Self {
+            code: params.0,
+            message: params.1,
+        }
+    }
+}
+
+impl DynamicPacket for ErrorPacket {}
+
+// what the peer is told when something on our end failed, the details stay in our log
+const INTERNAL_MESSAGE: &str = "Internal error, the details are in the server's log";
+
+// something the peer did wrong, safe to tell it about word for word unlike every other
+// error (those can carry paths or database errors)
+#[derive(Debug)]
+pub struct PeerError {
+    pub code: ErrorCode,
+    pub message: String,
+}
+
+impl PeerError {
+    pub fn new(code: ErrorCode, message: String) -> Self {
+        Self { code, message }
+    }
+}
+
+impl std::fmt::Display for PeerError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}", self.message)
+    }
+}
+
+impl std::error::Error for PeerError {}
+
+impl From<&ProtocolError> for ErrorPacket {
+    fn from(error: &ProtocolError) -> Self {
+        let code = match error {
+            ProtocolError::UnknownType(_) => ErrorCode::UnknownType,
+            ProtocolError::BadSize { .. } => ErrorCode::BadSize,
+            ProtocolError::Decode { .. } | ProtocolError::Malformed { .. } => ErrorCode::Decode,
+            ProtocolError::UnexpectedPacket { .. } => ErrorCode::UnexpectedPacket,
+            ProtocolError::VersionMismatch { .. } => ErrorCode::VersionMismatch,
+            ProtocolError::Io(_) => {
+                return Self::build((ErrorCode::Internal, INTERNAL_MESSAGE.to_owned()));
+            }
+        };
+
+        Self::build((code, error.to_string()))
+    }
+}
+
+impl From<&anyhow::Error> for ErrorPacket {
+    fn from(error: &anyhow::Error) -> Self {
+        if let Some(protocol_error) = error.downcast_ref::<ProtocolError>() {
+            return protocol_error.into();
+        }
+
+        match error.downcast_ref::<PeerError>() {
+            Some(peer_error) => Self::build((peer_error.code, peer_error.message.clone())),
+            None => Self::build((ErrorCode::Internal, INTERNAL_MESSAGE.to_owned())),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn internal_errors_stay_on_the_server() {
+        let error = anyhow::anyhow!("No such file or directory: /srv/syncr/secret/notes.txt");
+        let packet = ErrorPacket::from(&error);
+
+        assert_eq!(packet.code, ErrorCode::Internal);
+        assert!(!packet.message.contains("/srv"));
+    }
+
+    #[test]
+    fn peer_errors_are_passed_on() {
+        let error = anyhow::Error::new(PeerError::new(ErrorCode::BadSize, "too big".to_owned()));
+        let packet = ErrorPacket::from(&error);
+
+        assert_eq!(packet.code, ErrorCode::BadSize);
+        assert_eq!(packet.message, "too big");
+    }
+}
@@ -0,0 +1,192 @@
+use std::ops::BitAnd;
+
+use serde::{Deserialize, Serialize};
+use tokio::io::AsyncRead;
+
+use super::{DynamicPacket, PacketBase, SizePacket};
+use crate::common::packets::utils::{read_header, read_packet};
+use crate::common::packets::{FrameLimits, ProtocolError, StaticPacket};
+
+// bump whenever the layout of any packet changes
+pub const PROTOCOL_VERSION: u32 = 16;
+pub const BUILD_VERSION: &str = env!("CARGO_PKG_VERSION");
+
+// the most a HELLO may take up, it's read before the peer's key is checked against anything
+// so it doesn't get the usual frame limits, a build version and a few names fit easily
+const MAX_HELLO_SIZE: u64 = 64 * 1024;
+
+// optional features, a bitset so unknown bits from newer peers are simply dropped
+// by the intersection instead of failing to decode
+#[derive(Serialize, Deserialize, Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub struct Capabilities(u64);
+
+impl Capabilities {
+    pub const COMPRESSION: Self = Self(1 << 0);
+    pub const RESUMABLE: Self = Self(1 << 1);
+
+    pub const fn empty() -> Self {
+        Self(0)
+    }
+
+    // what this build actually implements
+    pub const fn local() -> Self {
+        Self::RESUMABLE.union(Self::COMPRESSION)
+    }
+
+    pub const fn union(self, other: Self) -> Self {
+        Self(self.0 | other.0)
+    }
+
+    pub fn contains(&self, other: Self) -> bool {
+        self.0 & other.0 == other.0
+    }
+}
+
+impl BitAnd for Capabilities {
+    type Output = Self;
+
+    fn bitand(self, rhs: Self) -> Self::Output {
+        Self(self.0 & rhs.0)
+    }
+}
+
+// First packet both sides send once the Noise handshake is done.
+// Its layout must never change, protocol_version has to stay the first field
+// so mismatched builds can at least tell each other apart.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct HelloPacket {
+    pub protocol_version: u32,
+    pub build_version: String,
+    pub syncr_ids: Vec<String>,
+    pub capabilities: Capabilities,
+    // what the device calls itself, the server pins its key under this name,
+    // new fields only ever go after everything above
+    pub device: String,
+}
+
+impl PacketBase for HelloPacket {
+    const TYPE: &'static [u8; 4] = b"HELO";
+    type BuildParams = (String, Vec<String>, Capabilities);
+
+    fn build(params: Self::BuildParams) -> Self {
+        Self {
+            protocol_version: PROTOCOL_VERSION,
+            build_version: BUILD_VERSION.to_owned(),
+            syncr_ids: params.1,
+            capabilities: params.2,
+            device: params.0,
+        }
+    }
+}
+
+impl DynamicPacket for HelloPacket {}
+
+impl HelloPacket {
+    // reads the peer's HELLO (SIZE, then HELO), protocol_version is checked before the rest
+    // is decoded, so a peer on another version gets VersionMismatch rather than a Decode
+    // error over fields only one of us knows about
+    pub async fn read<S: AsyncRead + Unpin>(stream: &mut S) -> Result<Self, ProtocolError> {
+        let limits = FrameLimits {
+            max_frame_size: MAX_HELLO_SIZE,
+            max_file_size: 0,
+        };
+
+        let header = read_header(stream).await?;
+        if header != *SizePacket::TYPE {
+            return Err(ProtocolError::UnexpectedPacket {
+                expected: "SIZE",
+                got: header,
+            });
+        }
+        let mut buffer = SizePacket::make_buffer()?;
+        read_packet(stream, &mut buffer).await?;
+        let size = SizePacket::from_bytes(&buffer)?;
+
+        let header = read_header(stream).await?;
+        if header != *Self::TYPE {
+            return Err(ProtocolError::UnexpectedPacket {
+                expected: "HELO",
+                got: header,
+            });
+        }
+        let mut buffer = Self::make_buffer(&size, &limits)?;
+        read_packet(stream, &mut buffer).await?;
+
+        let remote = buffer
+            .first_chunk::<4>()
+            .map(|version| u32::from_le_bytes(*version))
+            .ok_or(ProtocolError::Decode {
+                packet_type: *Self::TYPE,
+                reason: "too short to hold a protocol version".to_owned(),
+            })?;
+        if remote != PROTOCOL_VERSION {
+            return Err(ProtocolError::VersionMismatch {
+                local: PROTOCOL_VERSION,
+                remote,
+            });
+        }
+
+        Self::from_bytes(&buffer)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // what a build from before `device` existed sends
+    #[derive(Serialize)]
+    struct OlderHello {
+        protocol_version: u32,
+        build_version: String,
+        syncr_ids: Vec<String>,
+        capabilities: Capabilities,
+    }
+
+    fn frame(body: &[u8]) -> Vec<u8> {
+        let mut frame = b"SIZE".to_vec();
+        frame.extend_from_slice(&(body.len() as u64).to_le_bytes());
+        frame.extend_from_slice(b"HELO");
+        frame.extend_from_slice(body);
+        frame
+    }
+
+    #[tokio::test]
+    async fn older_peer_is_a_version_mismatch() {
+        let body = bincode::serialize(&OlderHello {
+            protocol_version: PROTOCOL_VERSION - 1,
+            build_version: "0.0.1".to_owned(),
+            syncr_ids: Vec::new(),
+            capabilities: Capabilities::empty(),
+        })
+        .unwrap();
+
+        let error = HelloPacket::read(&mut frame(&body).as_slice())
+            .await
+            .unwrap_err();
+        assert!(
+            matches!(error, ProtocolError::VersionMismatch { .. }),
+            "{error}"
+        );
+    }
+
+    #[tokio::test]
+    async fn oversized_hello_is_refused_before_reading_it() {
+        let mut frame = b"SIZE".to_vec();
+        frame.extend_from_slice(&(MAX_HELLO_SIZE + 1).to_le_bytes());
+        frame.extend_from_slice(b"HELO");
+
+        let error = HelloPacket::read(&mut frame.as_slice()).await.unwrap_err();
+        assert!(matches!(error, ProtocolError::BadSize { .. }), "{error}");
+    }
+
+    #[tokio::test]
+    async fn reads_a_current_hello() {
+        let hello = HelloPacket::build(("laptop".to_owned(), Vec::new(), Capabilities::local()));
+        let mut bytes = Vec::new();
+        hello.write(&mut bytes).await.unwrap();
+
+        let read = HelloPacket::read(&mut bytes.as_slice()).await.unwrap();
+        assert_eq!(read.device, "laptop");
+    }
+}
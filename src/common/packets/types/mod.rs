@@ -1,33 +1,23 @@
+pub mod error;
+pub mod hello;
+pub mod pairing;
+pub mod ping;
+pub mod rotate;
 pub mod sanity;
 pub mod size;
 pub mod sync;
 
 use super::{DynamicPacket, PacketBase, StaticPacket};
 
+pub use error::{ErrorCode, ErrorPacket, PeerError};
+pub use hello::{Capabilities, HelloPacket};
+pub use pairing::PairingPacket;
+pub use ping::{PingPacket, PongPacket};
+pub use rotate::RotatePacket;
 pub use sanity::SanityPacket;
 pub use size::SizePacket;
 pub use sync::ack::SyncAcknowledgePacket;
-pub use sync::delta::SyncDeltaPacket;
+pub use sync::delta::{SyncDeltaBeginPacket, SyncDeltaChunkPacket, SyncDeltaEndPacket};
+pub use sync::force::SyncForcePacket;
 pub use sync::init::SyncInitPacket;
-
-#[derive(Debug)]
-pub enum DynamicPackets {
-    Sanity(SanityPacket),
-    SyncAck(SyncAcknowledgePacket),
-    SyncDelta(SyncDeltaPacket),
-}
-
-#[derive(Debug)]
-pub enum StaticPackets {
-    Size(SizePacket),
-    SyncInit(SyncInitPacket),
-}
-
-#[derive(Debug)]
-pub enum Packets {
-    Sanity(SanityPacket),
-    Size(SizePacket),
-    SyncInit(SyncInitPacket),
-    SyncAck(SyncAcknowledgePacket),
-    SyncDelta(SyncDeltaPacket),
-}
+pub use sync::resume::SyncResumePacket;
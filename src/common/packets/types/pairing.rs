@@ -0,0 +1,25 @@
+use serde::{Deserialize, Serialize};
+
+use super::{DynamicPacket, PacketBase};
+
+#[derive(Serialize, Deserialize, Default, Debug, Clone)]
+pub struct PairingPacket {
+    pub secret: String,
+    pub salt: String,
+}
+
+// Sent by the server once a pairing handshake went through, the only frame on that
+// connection. Carries what the device needs to connect normally from then on
+impl PacketBase for PairingPacket {
+    const TYPE: &'static [u8; 4] = b"PAIR";
+    type BuildParams = (String, String); // secret, salt
+
+    fn build(params: Self::BuildParams) -> Self {
+        Self {
+            secret: params.0,
+            salt: params.1,
+        }
+    }
+}
+
+impl DynamicPacket for PairingPacket {}
@@ -0,0 +1,37 @@
+use serde::{Deserialize, Serialize};
+
+use super::{DynamicPacket, PacketBase};
+
+#[derive(Serialize, Deserialize, Default, Debug, Clone)]
+pub struct PingPacket {
+    pub nonce: u64,
+}
+
+// Heartbeat, sent on the CONNECTION id every heartbeat-interval by both sides
+// Whoever gets one answers with a PONG carrying the same nonce
+impl PacketBase for PingPacket {
+    const TYPE: &'static [u8; 4] = b"PING";
+    type BuildParams = u64;
+
+    fn build(params: Self::BuildParams) -> Self {
+        Self { nonce: params }
+    }
+}
+
+impl DynamicPacket for PingPacket {}
+
+#[derive(Serialize, Deserialize, Default, Debug, Clone)]
+pub struct PongPacket {
+    pub nonce: u64,
+}
+
+impl PacketBase for PongPacket {
+    const TYPE: &'static [u8; 4] = b"PONG";
+    type BuildParams = u64;
+
+    fn build(params: Self::BuildParams) -> Self {
+        Self { nonce: params }
+    }
+}
+
+impl DynamicPacket for PongPacket {}
@@ -0,0 +1,21 @@
+use serde::{Deserialize, Serialize};
+
+use super::{DynamicPacket, PacketBase};
+
+#[derive(Serialize, Deserialize, Default, Debug, Clone)]
+pub struct RotatePacket {
+    pub secret: String,
+}
+
+// Pushed by the server on CONNECTION when its secret changed (or the client connected
+// with one that's on its way out). The salt stays, the next handshake uses the new secret
+impl PacketBase for RotatePacket {
+    const TYPE: &'static [u8; 4] = b"ROTA";
+    type BuildParams = String; // secret
+
+    fn build(params: Self::BuildParams) -> Self {
+        Self { secret: params }
+    }
+}
+
+impl DynamicPacket for RotatePacket {}
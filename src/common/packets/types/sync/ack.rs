@@ -17,6 +17,12 @@ pub struct AckData {
 // This packet is sent to acknowledge if a SYNC transaction should begin occuring
 // This means, that the hash sent from the INIT packet told us that we had a different
 // file and that we should be syncing :3
+//
+// ack: false             -> nothing to sync, our copy matches (also sent once a delta/FRCE is applied)
+// ack: true, data: Some  -> here's our signature, stream a delta (or send the whole file
+//                           with FRCE from byte 0, if a delta isn't worth it)
+//
+// if we don't have the file at all we answer with a RSUM instead
 impl PacketBase for SyncAcknowledgePacket {
     const TYPE: &'static [u8; 4] = b"SACK"; // get it? sync ack? sack haha
     type BuildParams = (bool, Option<AckData>);
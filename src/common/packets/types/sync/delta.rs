@@ -2,23 +2,80 @@ use serde::{Deserialize, Serialize};
 
 use super::{DynamicPacket, PacketBase};
 
+// A delta is streamed as SDLB -> SDLC... -> SDLE so neither side ever has to hold
+// the whole thing in memory, the server applies every chunk as soon as it lands
+
+#[derive(Serialize, Deserialize, Default, Debug, Clone)]
+pub struct SyncDeltaBeginPacket {
+    pub new_file_size: u64,
+}
+
+// Opens the delta stream, carries the size the file will have once applied
+impl PacketBase for SyncDeltaBeginPacket {
+    const TYPE: &'static [u8; 4] = b"SDLB"; // sync delta begin
+    type BuildParams = u64;
+
+    fn build(params: Self::BuildParams) -> Self {
+        Self {
+            new_file_size: params,
+        }
+    }
+}
+
+impl DynamicPacket for SyncDeltaBeginPacket {}
+
 #[derive(Serialize, Deserialize, Default, Debug, Clone)]
-pub struct SyncDeltaPacket {
-    pub delta: Vec<u8>,
-    pub new_file_size: usize,
+pub struct SyncDeltaChunkPacket {
+    pub data: Vec<u8>,
+    // zstd'd, only ever set if both sides negotiated COMPRESSION
+    pub compressed: bool,
+}
+
+// A slice of the delta, chunks don't line up with delta commands so the
+// receiver has to carry half-read commands over to the next one
+impl PacketBase for SyncDeltaChunkPacket {
+    const TYPE: &'static [u8; 4] = b"SDLC"; // sync delta chunk
+    type BuildParams = (Vec<u8>, bool);
+
+    fn build(params: Self::BuildParams) -> Self {
+        Self {
+            data: params.0,
+            compressed: params.1,
+        }
+    }
+}
+
+impl DynamicPacket for SyncDeltaChunkPacket {}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct SyncDeltaEndPacket {
+    // blake3 of every chunk sent (before compression), in order
+    pub hash: blake3::Hash,
+    // blake3 of the whole file once the delta is applied, checked before it replaces anything
+    pub file_hash: blake3::Hash,
+}
+
+impl Default for SyncDeltaEndPacket {
+    fn default() -> Self {
+        Self {
+            hash: blake3::Hasher::new().finalize(),
+            file_hash: blake3::Hasher::new().finalize(),
+        }
+    }
 }
 
-// Pretty simple, contains the fat delta buffer and the expected file size
-impl PacketBase for SyncDeltaPacket {
-    const TYPE: &'static [u8; 4] = b"SDLT"; // sync delta (Sync DeLTa)
-    type BuildParams = (Vec<u8>, usize);
+// Closes the delta stream, the server answers with a SACK once the result checks out
+// or a RSUM asking for the whole file if it doesn't
+impl PacketBase for SyncDeltaEndPacket {
+    const TYPE: &'static [u8; 4] = b"SDLE"; // sync delta end
+    type BuildParams = (blake3::Hash, blake3::Hash); // stream hash, file hash
 
     fn build(params: Self::BuildParams) -> Self {
         Self {
-            delta: params.0,
-            new_file_size: params.1,
+            hash: params.0,
+            file_hash: params.1,
         }
     }
 }
 
-impl DynamicPacket for SyncDeltaPacket {}
+impl DynamicPacket for SyncDeltaEndPacket {}
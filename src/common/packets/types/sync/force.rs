@@ -1,11 +1,12 @@
 use std::ops::Deref;
 
-use memmap2::Mmap;
+use memmap2::{Mmap, MmapOptions};
 use serde::{Deserialize, Serialize};
 
-use crate::common::packets::mmap::MmapPacket;
+use crate::common::compression::Compressor;
+use crate::common::packets::MmapPacket;
 
-use super::{PacketBase, StaticPacket};
+use super::{DynamicPacket, PacketBase};
 
 #[derive(Debug)]
 pub struct SyncForcePacket {
@@ -14,40 +15,86 @@ pub struct SyncForcePacket {
     pub inner: SyncForcePacketStatic,
 }
 
-#[derive(Debug, Serialize, Deserialize, Default, Clone)]
+#[derive(Debug, Serialize, Deserialize, Clone)]
 pub struct SyncForcePacketStatic {
-    pub syncr_id: String,
-    pub known_name: String,
+    // where in the file this chunk starts, and how big the whole file is
+    pub offset: u64,
+    pub file_size: u64,
+    // blake3 of just this chunk (before compression), checked before it's appended
+    pub hash: blake3::Hash,
+    // blake3 of the whole file, has to match what the upload was started for
+    pub file_hash: blake3::Hash,
+    // DATA is zstd'd, only ever set if both sides negotiated COMPRESSION
+    pub compressed: bool,
+}
+
+impl Default for SyncForcePacketStatic {
+    fn default() -> Self {
+        Self {
+            offset: 0,
+            file_size: 0,
+            hash: blake3::Hasher::new().finalize(),
+            file_hash: blake3::Hasher::new().finalize(),
+            compressed: false,
+        }
+    }
 }
 
 impl PacketBase for SyncForcePacketStatic {
     const TYPE: &'static [u8; 4] = b"FRCE";
-    type BuildParams = (String, String);
+    type BuildParams = (u64, u64, blake3::Hash, blake3::Hash);
 
     fn build(params: Self::BuildParams) -> Self {
         Self {
-            syncr_id: params.0,
-            known_name: params.1,
+            offset: params.0,
+            file_size: params.1,
+            hash: params.2,
+            file_hash: params.3,
+            compressed: false,
         }
     }
 }
-impl StaticPacket for SyncForcePacketStatic {}
+impl DynamicPacket for SyncForcePacketStatic {}
 
 // this packet is sent in desperation to sync a file
 // that does not want to sync with normal delta
 // diff. basically we send the whole file over lol
+//
+// the file goes over in chunks (the mmap only covers one of them) so that a
+// dropped connection only loses the chunk that was in flight, the server keeps
+// what it got and tells us where to pick up with a RSUM next time
 impl PacketBase for SyncForcePacket {
     const TYPE: &'static [u8; 4] = b"MMAP";
-    type BuildParams = (Mmap, String, String);
+    type BuildParams = (Mmap, u64, u64, blake3::Hash); // chunk, offset, file size, file hash
 
     fn build(params: Self::BuildParams) -> Self {
+        let hash = blake3::hash(&params.0);
+
         Self {
             mmap: params.0,
-            inner: SyncForcePacketStatic::build((params.1, params.2)),
+            inner: SyncForcePacketStatic::build((params.1, params.2, hash, params.3)),
         }
     }
 }
 
+impl SyncForcePacket {
+    // swaps the chunk for its compressed form if the compressor thinks it's worth it,
+    // it goes out of an anonymous mapping so the packet doesn't change shape
+    pub fn compress(mut self, compressor: &mut Compressor) -> Result<Self, anyhow::Error> {
+        let Some(compressed) = compressor.compress(&self.mmap)? else {
+            return Ok(self);
+        };
+
+        let mut mmap = MmapOptions::new().len(compressed.len()).map_anon()?;
+        mmap.copy_from_slice(&compressed);
+
+        self.mmap = mmap.make_read_only()?;
+        self.inner.compressed = true;
+
+        Ok(self)
+    }
+}
+
 impl MmapPacket for SyncForcePacket {
     type MmaplessPacket = SyncForcePacketStatic;
 
@@ -58,10 +105,8 @@ impl MmapPacket for SyncForcePacket {
         &self.inner
     }
 
-    async fn deserialize<S: tokio::io::AsyncRead + Unpin>(reader: &mut S) -> anyhow::Result<Self> {
-        let (mmap, inner) = Self::deserialize_inner(reader).await?;
-
-        Ok(Self { mmap, inner })
+    fn from_parts(mmap: Mmap, inner: Self::MmaplessPacket) -> Self {
+        Self { mmap, inner }
     }
 }
 
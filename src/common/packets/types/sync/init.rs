@@ -1,6 +1,6 @@
 use serde::{Deserialize, Serialize};
 
-use super::{PacketBase, StaticPacket};
+use super::{DynamicPacket, PacketBase};
 
 #[derive(Serialize, Deserialize, Debug, Clone)]
 pub struct SyncInitPacket {
@@ -20,6 +20,7 @@ impl Default for SyncInitPacket {
 }
 
 // This packet is sent to initialize a SYNC transaction
+// It's dynamic since syncr_id and known_name can be any length
 impl PacketBase for SyncInitPacket {
     const TYPE: &'static [u8; 4] = b"INIT";
     type BuildParams = (blake3::Hash, String, String);
@@ -33,4 +34,4 @@ impl PacketBase for SyncInitPacket {
     }
 }
 
-impl StaticPacket for SyncInitPacket {}
+impl DynamicPacket for SyncInitPacket {}
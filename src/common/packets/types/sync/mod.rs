@@ -2,5 +2,6 @@ pub mod ack;
 pub mod delta;
 pub mod force;
 pub mod init;
+pub mod resume;
 
-use super::{DynamicPacket, PacketBase, StaticPacket};
+use super::{DynamicPacket, PacketBase};
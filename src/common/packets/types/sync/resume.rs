@@ -0,0 +1,21 @@
+use serde::{Deserialize, Serialize};
+
+use super::{DynamicPacket, PacketBase};
+
+#[derive(Serialize, Deserialize, Default, Debug, Clone)]
+pub struct SyncResumePacket {
+    pub offset: u64,
+}
+
+// Sent instead of a SACK when we need the whole file, offset is how much of it we
+// already have from an earlier, interrupted FRCE (0 if nothing)
+impl PacketBase for SyncResumePacket {
+    const TYPE: &'static [u8; 4] = b"RSUM";
+    type BuildParams = u64;
+
+    fn build(params: Self::BuildParams) -> Self {
+        Self { offset: params }
+    }
+}
+
+impl DynamicPacket for SyncResumePacket {}
@@ -1,20 +1,133 @@
-macro_rules! packet_buffer_mapper {
-    ($($static_packet:ident),* ; $($dynamic_packet:ident),*) => {
-        pub fn get_buffer_for_type(packet_type: &[u8; 4], size: &Option<crate::common::packets::types::SizePacket>) -> Result<Vec<u8>, anyhow::Error> {
-            match (packet_type, size) {
-                // Static packets (ignore size parameter)
-                $(
-                    ($static_packet::TYPE, _) => $static_packet::make_buffer(),
-                )*
-                // Dynamic packets (require size parameter)
-                $(
-                    ($dynamic_packet::TYPE, Some(size)) => <$dynamic_packet as crate::common::packets::DynamicPacket>::make_buffer(&size),
-                    ($dynamic_packet::TYPE, None) => panic!("Dynamic packet requires size parameter"),
-                )*
-                _ => panic!("Unknown packet type: {:?}", packet_type)
+// single source of truth for every packet the protocol knows about
+//
+// static:  fixed size packets, buffer is sized from Default
+// dynamic: preceded by a SIZE packet that tells us how much to read
+// mmap:    framed as MMAP/STAT/DATA/DONE, the STAT section is a dynamic packet
+//          whose TYPE picks which mmap packet we are reading
+//
+// adding a packet means adding one line here, everything below is generated from it
+macro_rules! packet_registry {
+    (
+        static { $($static_variant:ident($static_packet:ident)),* $(,)? }
+        dynamic { $($dynamic_variant:ident($dynamic_packet:ident)),* $(,)? }
+        mmap { $($mmap_variant:ident($mmap_packet:ident)),* $(,)? }
+    ) => {
+        #[derive(Debug)]
+        pub enum Packets {
+            $($static_variant($static_packet),)*
+            $($dynamic_variant($dynamic_packet),)*
+            $($mmap_variant($mmap_packet),)*
+        }
+
+        impl Packets {
+            // the TYPE that identifies this packet on the wire (mmap packets report their STAT type)
+            pub fn get_type(&self) -> &'static [u8; 4] {
+                match self {
+                    $(Packets::$static_variant(_) => <$static_packet as crate::common::packets::PacketBase>::TYPE,)*
+                    $(Packets::$dynamic_variant(_) => <$dynamic_packet as crate::common::packets::PacketBase>::TYPE,)*
+                    $(Packets::$mmap_variant(_) => <<$mmap_packet as crate::common::packets::MmapPacket>::MmaplessPacket as crate::common::packets::PacketBase>::TYPE,)*
+                }
+            }
+
+            pub fn name(&self) -> &'static str {
+                std::str::from_utf8(self.get_type()).unwrap_or("????")
+            }
+        }
+
+        pub fn get_buffer_for_type(
+            packet_type: &[u8; 4],
+            size: &Option<crate::common::packets::SizePacket>,
+            limits: &crate::common::packets::FrameLimits,
+        ) -> Result<Vec<u8>, crate::common::packets::ProtocolError> {
+            // Static packets (ignore size parameter)
+            $(
+                if packet_type == <$static_packet as crate::common::packets::PacketBase>::TYPE {
+                    return <$static_packet as crate::common::packets::StaticPacket>::make_buffer();
+                }
+            )*
+            // Dynamic packets (require size parameter)
+            $(
+                if packet_type == <$dynamic_packet as crate::common::packets::PacketBase>::TYPE {
+                    return match size {
+                        Some(size) => <$dynamic_packet as crate::common::packets::DynamicPacket>::make_buffer(size, limits),
+                        None => Err(crate::common::packets::ProtocolError::BadSize {
+                            packet_type: *packet_type,
+                            reason: "dynamic packet was not preceded by a SIZE packet".to_owned(),
+                        }),
+                    };
+                }
+            )*
+
+            Err(crate::common::packets::ProtocolError::UnknownType(*packet_type))
+        }
+
+        pub fn packetize(
+            packet_type: &[u8; 4],
+            packet_buf: Vec<u8>,
+        ) -> Result<Packets, crate::common::packets::ProtocolError> {
+            $(
+                if packet_type == <$static_packet as crate::common::packets::PacketBase>::TYPE {
+                    return Ok(Packets::$static_variant(
+                        <$static_packet as crate::common::packets::StaticPacket>::from_bytes(&packet_buf)?,
+                    ));
+                }
+            )*
+            $(
+                if packet_type == <$dynamic_packet as crate::common::packets::PacketBase>::TYPE {
+                    return Ok(Packets::$dynamic_variant(
+                        <$dynamic_packet as crate::common::packets::DynamicPacket>::from_bytes(&packet_buf)?,
+                    ));
+                }
+            )*
+
+            Err(crate::common::packets::ProtocolError::UnknownType(*packet_type))
+        }
+
+        // reads the rest of a MMAP packet, given that the "MMAP" header was already consumed
+        pub async fn read_mmap_packet<S: tokio::io::AsyncRead + Unpin>(
+            stream: &mut S,
+            limits: &crate::common::packets::FrameLimits,
+        ) -> Result<Packets, crate::common::packets::ProtocolError> {
+            use crate::common::packets::utils::{read_header, read_packet};
+
+            let header = read_header(stream).await?;
+            if header != *b"STAT" {
+                return Err(crate::common::packets::ProtocolError::UnexpectedPacket {
+                    expected: "STAT",
+                    got: header,
+                });
+            }
+
+            // the STAT section is a regular dynamic packet, SIZE first
+            let header = read_header(stream).await?;
+            if header != *<crate::common::packets::SizePacket as crate::common::packets::PacketBase>::TYPE {
+                return Err(crate::common::packets::ProtocolError::UnexpectedPacket {
+                    expected: "SIZE",
+                    got: header,
+                });
             }
+            let mut buffer = <crate::common::packets::SizePacket as crate::common::packets::StaticPacket>::make_buffer()?;
+            read_packet(stream, &mut buffer).await?;
+            let size = <crate::common::packets::SizePacket as crate::common::packets::StaticPacket>::from_bytes(&buffer)?;
+
+            let header = read_header(stream).await?;
+            $(
+                if header == *<<$mmap_packet as crate::common::packets::MmapPacket>::MmaplessPacket as crate::common::packets::PacketBase>::TYPE {
+                    let mut buffer = <<$mmap_packet as crate::common::packets::MmapPacket>::MmaplessPacket as crate::common::packets::DynamicPacket>::make_buffer(&size, limits)?;
+                    read_packet(stream, &mut buffer).await?;
+                    let mmapless = <<$mmap_packet as crate::common::packets::MmapPacket>::MmaplessPacket as crate::common::packets::DynamicPacket>::from_bytes(&buffer)?;
+
+                    let mmap = <$mmap_packet as crate::common::packets::MmapPacket>::read_data(stream, limits).await?;
+
+                    return Ok(Packets::$mmap_variant(
+                        <$mmap_packet as crate::common::packets::MmapPacket>::from_parts(mmap, mmapless),
+                    ));
+                }
+            )*
+
+            Err(crate::common::packets::ProtocolError::UnknownType(header))
         }
     };
 }
 
-pub(crate) use packet_buffer_mapper;
+pub(crate) use packet_registry;
@@ -1,20 +1,88 @@
 pub mod macros;
 
-pub(crate) use macros::packet_buffer_mapper;
+pub(crate) use macros::packet_registry;
+
+use tokio::io::{AsyncRead, AsyncReadExt};
 
 use super::{
-    DynamicPacket, Packets, StaticPacket,
-    base::PacketBase,
-    types::{SanityPacket, SizePacket},
+    FrameLimits, Packets, ProtocolError, get_buffer_for_type, mmap::MMAP_HEADER, packetize,
+    read_mmap_packet, types::SizePacket,
 };
 
-pub fn packetize(packet_type: &[u8; 4], packet_buf: Vec<u8>) -> Result<Packets, anyhow::Error> {
-    let packet_type = std::str::from_utf8(packet_type)?;
-    let packet_type = packet_type.to_uppercase();
+// reads one packet off the stream, SIZE packets are returned as well
+// (and remembered) so the caller can tell them apart from the packet they announce
+pub async fn extract_packet<S: AsyncRead + Unpin>(
+    stream: &mut S,
+    last_size_packet: &mut Option<SizePacket>,
+    limits: &FrameLimits,
+) -> Result<Packets, ProtocolError> {
+    let header_buffer = read_header(stream).await?;
+
+    if header_buffer == *MMAP_HEADER {
+        *last_size_packet = None;
+        // anything off in here is noticed halfway through, with the data still on the stream
+        return read_mmap_packet(stream, limits)
+            .await
+            .map_err(|e| match e.is_fatal() {
+                true => e,
+                false => ProtocolError::Malformed {
+                    packet_type: *MMAP_HEADER,
+                    reason: e.to_string(),
+                },
+            });
+    }
+
+    let mut buffer = get_buffer_for_type(&header_buffer, last_size_packet, limits)?;
+    read_packet(stream, &mut buffer).await?;
 
-    match packet_type.as_str() {
-        "SIZE" => Ok(Packets::Size(SizePacket::from_bytes(&packet_buf))),
-        "SNTY" => Ok(Packets::Sanity(SanityPacket::from_bytes(&packet_buf))),
-        _ => Err(anyhow::anyhow!("Invalid packet type")),
+    match packetize(&header_buffer, buffer)? {
+        Packets::Size(size_packet) => {
+            *last_size_packet = Some(size_packet.clone());
+            Ok(Packets::Size(size_packet))
+        }
+        other_packet => {
+            *last_size_packet = None;
+            Ok(other_packet)
+        }
     }
 }
+
+pub async fn read_packet<T: AsyncRead + Unpin>(
+    stream: &mut T,
+    packet_buffer: &mut Vec<u8>,
+) -> Result<(), ProtocolError> {
+    let expected_size = packet_buffer.len();
+
+    let mut bytes_read = 0;
+    while bytes_read < expected_size {
+        let n = stream.read(&mut packet_buffer[bytes_read..]).await?;
+        if n == 0 {
+            return Err(ProtocolError::Io(std::io::Error::new(
+                std::io::ErrorKind::UnexpectedEof,
+                "Unexpected EOF",
+            )));
+        }
+        bytes_read += n;
+    }
+    Ok(())
+}
+
+pub async fn read_header<T: AsyncRead + Unpin>(stream: &mut T) -> std::io::Result<[u8; 4]> {
+    let expected_size = 4;
+    let mut buf = [0u8; 4];
+    buf.fill(0);
+
+    let mut bytes_read = 0;
+    while bytes_read < expected_size {
+        let n = stream.read(&mut buf[bytes_read..]).await?;
+        if n == 0 {
+            return Err(std::io::Error::new(
+                std::io::ErrorKind::UnexpectedEof,
+                "Unexpected EOF",
+            ));
+        }
+        bytes_read += n;
+    }
+
+    Ok(buf)
+}
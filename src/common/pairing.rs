@@ -0,0 +1,131 @@
+use std::fmt;
+
+use rand::{Rng, rngs::OsRng};
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt};
+
+use super::psk::{self, KeyId, Psk};
+
+// no 0/O, 1/I/L, codes get read out loud and typed in by hand
+const CODE_ALPHABET: &[u8] = b"ABCDEFGHJKMNPQRSTUVWXYZ23456789";
+// the id is sent in the clear to find the code, only the rest is secret
+const CODE_ID_LEN: usize = 4;
+// ~40 bits, plenty for something that's argon2'd, single use and gone in minutes
+const CODE_SECRET_LEN: usize = 8;
+
+const GREETING_SESSION: u8 = 0;
+const GREETING_PAIR: u8 = 1;
+
+// what a connection is for, the very first thing the client sends (in the clear)
+// so the server knows which psk to run the handshake with, it's part of the prologue
+// too so nobody in the middle can change it
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Greeting {
+    // a normal connection, on whichever of the deployment's secrets has this id
+    Session { key_id: KeyId },
+    // onboarding a device, the psk comes from the one-time code with this id
+    Pair { code_id: String },
+}
+
+impl Greeting {
+    fn encode(&self) -> Vec<u8> {
+        match self {
+            Greeting::Session { key_id } => [&[GREETING_SESSION], &key_id[..]].concat(),
+            Greeting::Pair { code_id } => [&[GREETING_PAIR], code_id.as_bytes()].concat(),
+        }
+    }
+
+    pub async fn write<W: AsyncWrite + Unpin>(&self, writer: &mut W) -> Result<(), anyhow::Error> {
+        writer.write_all(&self.encode()).await?;
+        writer.flush().await?;
+
+        Ok(())
+    }
+
+    pub async fn read<R: AsyncRead + Unpin>(reader: &mut R) -> Result<Self, anyhow::Error> {
+        match reader.read_u8().await? {
+            GREETING_SESSION => {
+                let mut key_id = KeyId::default();
+                reader.read_exact(&mut key_id).await?;
+
+                Ok(Greeting::Session { key_id })
+            }
+            GREETING_PAIR => {
+                let mut code_id = [0u8; CODE_ID_LEN];
+                reader.read_exact(&mut code_id).await?;
+
+                Ok(Greeting::Pair {
+                    code_id: String::from_utf8(code_id.to_vec())?,
+                })
+            }
+            other => anyhow::bail!("Unknown greeting {}", other),
+        }
+    }
+
+    // what the handshake is bound to, whatever the transport negotiated plus this greeting
+    pub fn prologue(&self, binding: &[u8]) -> Vec<u8> {
+        [binding, &self.encode()].concat()
+    }
+}
+
+// a one-time pairing code, shown as XXXX-XXXX-XXXX
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Code {
+    pub id: String,
+    secret: String,
+}
+
+impl Code {
+    pub fn generate() -> Self {
+        let random = |len: usize| {
+            (0..len)
+                .map(|_| CODE_ALPHABET[OsRng.gen_range(0..CODE_ALPHABET.len())] as char)
+                .collect::<String>()
+        };
+
+        Self {
+            id: random(CODE_ID_LEN),
+            secret: random(CODE_SECRET_LEN),
+        }
+    }
+
+    // forgiving about case, dashes and spaces, whatever the user ends up typing
+    pub fn parse(code: &str) -> Result<Self, anyhow::Error> {
+        let code: String = code
+            .chars()
+            .filter(|c| !matches!(c, '-' | ' '))
+            .map(|c| c.to_ascii_uppercase())
+            .collect();
+
+        if code.len() != CODE_ID_LEN + CODE_SECRET_LEN
+            || !code.bytes().all(|byte| CODE_ALPHABET.contains(&byte))
+        {
+            anyhow::bail!("Not a pairing code, it should look like XXXX-XXXX-XXXX");
+        }
+
+        let (id, secret) = code.split_at(CODE_ID_LEN);
+
+        Ok(Self {
+            id: id.to_owned(),
+            secret: secret.to_owned(),
+        })
+    }
+
+    // what both sides run the pairing handshake with, stretched just like the deployment's
+    pub async fn psk(&self) -> Result<Psk, anyhow::Error> {
+        let (secret, salt) = (self.secret.clone(), format!("syncr pairing {}", self.id));
+
+        tokio::task::spawn_blocking(move || psk::derive(&secret, &salt)).await?
+    }
+}
+
+impl fmt::Display for Code {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "{}-{}-{}",
+            self.id,
+            &self.secret[..CODE_SECRET_LEN / 2],
+            &self.secret[CODE_SECRET_LEN / 2..]
+        )
+    }
+}
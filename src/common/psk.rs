@@ -0,0 +1,118 @@
+use anyhow::Context;
+use argon2::{Algorithm, Argon2, Params, Version};
+use log::warn;
+use rand::{RngCore, rngs::OsRng};
+
+use super::config::structure::ConfigInner;
+
+// the Noise psk, what every device of a deployment has to end up with
+pub type Psk = [u8; 32];
+
+// names a psk in the clear so the server knows which of its secrets to answer with,
+// learning it doesn't help guessing the passphrase any more than a recorded handshake does
+pub type KeyId = [u8; 8];
+
+// argon2id costs, 64MiB and a few passes keeps offline guessing (from a recorded
+// handshake) expensive while still taking well under a second once per startup
+const MEMORY_COST_KIB: u32 = 64 * 1024;
+const TIME_COST: u32 = 3;
+const PARALLELISM: u32 = 1;
+
+// shorter than this and the salt isn't doing much (argon2 won't take less than 8)
+const MIN_SALT_LEN: usize = 16;
+
+// secrets that ship in example configs or come from a config nobody filled in
+const INSECURE_SECRETS: &[&str] = &["", "password", "change-me"];
+// the salt the example configs ship with, public so it's no salt at all
+const EXAMPLE_SALT: &str = "syncr-example-deployment";
+
+// stretches the configured passphrase into the psk, refusing the obviously guessable
+// ones unless the config explicitly says it doesn't care
+//
+// argon2 is slow on purpose, so it runs off the runtime and is meant to be done once
+pub async fn from_config(config: &ConfigInner) -> Result<Psk, anyhow::Error> {
+    check(
+        "secret",
+        &config.secret,
+        &config.salt,
+        config.insecure_secret,
+    )?;
+
+    let (secret, salt) = (config.secret.clone(), config.salt.clone());
+    tokio::task::spawn_blocking(move || derive(&secret, &salt)).await?
+}
+
+// what every passphrase a device lets anyone in with has to pass, `name` is what
+// the config calls it so the error points at the right one
+pub fn check(name: &str, secret: &str, salt: &str, insecure: bool) -> Result<(), anyhow::Error> {
+    if INSECURE_SECRETS.contains(&secret) {
+        if !insecure {
+            anyhow::bail!(
+                "{} is empty or the default, set a passphrase (or insecure-secret = true for local testing)",
+                name
+            );
+        }
+
+        warn!(
+            "Running with an empty or default {}, anyone can connect",
+            name
+        );
+    }
+
+    if salt.is_empty() {
+        anyhow::bail!(
+            "salt isn't set, set a salt shared by every device (e.g. salt = \"{}\")",
+            generate_salt()
+        );
+    }
+
+    if salt == EXAMPLE_SALT {
+        if !insecure {
+            anyhow::bail!(
+                "salt is the one from the example config, set a salt of your own shared by every device (e.g. salt = \"{}\")",
+                generate_salt()
+            );
+        }
+
+        warn!("Running with the example salt, it does nothing against precomputed guesses");
+    }
+
+    if salt.len() < MIN_SALT_LEN {
+        anyhow::bail!(
+            "salt has to be at least {} characters, and the same on every device",
+            MIN_SALT_LEN
+        );
+    }
+
+    Ok(())
+}
+
+// same passphrase and salt always give the same psk, any length of passphrase counts
+pub fn derive(passphrase: &str, salt: &str) -> Result<Psk, anyhow::Error> {
+    let params = Params::new(MEMORY_COST_KIB, TIME_COST, PARALLELISM, Some(32))
+        .map_err(|e| anyhow::anyhow!("Invalid argon2 parameters: {}", e))?;
+
+    let mut psk = [0u8; 32];
+    Argon2::new(Algorithm::Argon2id, Version::V0x13, params)
+        .hash_password_into(passphrase.as_bytes(), salt.as_bytes(), &mut psk)
+        .map_err(|e| anyhow::anyhow!("{}", e))
+        .context("Failed to derive psk")?;
+
+    Ok(psk)
+}
+
+pub fn key_id(psk: &Psk) -> KeyId {
+    let hash = blake3::derive_key("syncr psk key id", psk);
+
+    let mut id = [0u8; 8];
+    id.copy_from_slice(&hash[..8]);
+    id
+}
+
+// suggested to whoever sets up a deployment, every device of it has to be given the same one
+pub fn generate_salt() -> String {
+    let mut salt = [0u8; 16];
+    OsRng.fill_bytes(&mut salt);
+
+    salt.iter().map(|byte| format!("{:02x}", byte)).collect()
+}
@@ -0,0 +1,141 @@
+use std::{net::SocketAddr, sync::Arc};
+
+use quinn::{
+    ClientConfig, Connection, Endpoint, RecvStream, SendStream, ServerConfig,
+    crypto::rustls::{QuicClientConfig, QuicServerConfig},
+};
+use rustls::{
+    DigitallySignedStruct, SignatureScheme,
+    client::danger::{HandshakeSignatureValid, ServerCertVerified, ServerCertVerifier},
+    crypto::{CryptoProvider, ring, verify_tls12_signature, verify_tls13_signature},
+    pki_types::{CertificateDer, PrivatePkcs8KeyDer, ServerName, UnixTime},
+};
+use tokio::io::{self, Join};
+
+const ALPN: &[u8] = b"syncr";
+// what both sides feed TLS' keying material exporter, see binding
+const BINDING_LABEL: &[u8] = b"syncr noise binding";
+
+// one bidirectional QUIC stream, usable as a Transport
+pub type QuicStream = Join<RecvStream, SendStream>;
+
+// QUIC only gets us a fast, ordered-per-stream pipe, who's on the other end is still
+// decided by the Noise handshake (and its psk) running on the first stream
+//
+// so the certificate is throwaway and never checked, the handshake is bound to this
+// exact TLS session instead (see binding), a relay in the middle ends up with a
+// different session on each side and the psk handshake falls apart
+pub fn listen(addr: SocketAddr) -> Result<Endpoint, anyhow::Error> {
+    let certified = rcgen::generate_simple_self_signed(vec!["syncr".to_owned()])?;
+    let cert = certified.cert.der().clone();
+    let key = PrivatePkcs8KeyDer::from(certified.key_pair.serialize_der());
+
+    let mut crypto =
+        rustls::ServerConfig::builder_with_provider(Arc::new(ring::default_provider()))
+            .with_protocol_versions(&[&rustls::version::TLS13])?
+            .with_no_client_auth()
+            .with_single_cert(vec![cert], key.into())?;
+    crypto.alpn_protocols = vec![ALPN.to_vec()];
+
+    let config = ServerConfig::with_crypto(Arc::new(QuicServerConfig::try_from(crypto)?));
+
+    Ok(Endpoint::server(config, addr)?)
+}
+
+// opens a QUIC connection along with the stream the control connection runs over
+pub async fn connect(addr: SocketAddr) -> Result<(Connection, QuicStream), anyhow::Error> {
+    let provider = Arc::new(ring::default_provider());
+
+    let mut crypto = rustls::ClientConfig::builder_with_provider(provider.clone())
+        .with_protocol_versions(&[&rustls::version::TLS13])?
+        .dangerous()
+        .with_custom_certificate_verifier(Arc::new(AnyCertificate(provider)))
+        .with_no_client_auth();
+    crypto.alpn_protocols = vec![ALPN.to_vec()];
+
+    let local: SocketAddr = match addr {
+        SocketAddr::V4(_) => ([0, 0, 0, 0], 0).into(),
+        SocketAddr::V6(_) => ([0u16; 8], 0).into(),
+    };
+    let mut endpoint = Endpoint::client(local)?;
+    endpoint.set_default_client_config(ClientConfig::new(Arc::new(QuicClientConfig::try_from(
+        crypto,
+    )?)));
+
+    let connection = endpoint.connect(addr, "syncr")?.await?;
+    let control = open(&connection).await?;
+
+    Ok((connection, control))
+}
+
+// the control stream on the server's end, the client opens it right after connecting
+pub async fn accept(connection: &Connection) -> Result<QuicStream, anyhow::Error> {
+    let (send, recv) = connection.accept_bi().await?;
+
+    Ok(io::join(recv, send))
+}
+
+pub async fn open(connection: &Connection) -> Result<QuicStream, anyhow::Error> {
+    let (send, recv) = connection.open_bi().await?;
+
+    Ok(io::join(recv, send))
+}
+
+// unique to this TLS session and the same on both ends, used as the Noise prologue
+pub fn binding(connection: &Connection) -> Result<[u8; 32], anyhow::Error> {
+    let mut binding = [0u8; 32];
+    connection
+        .export_keying_material(&mut binding, BINDING_LABEL, &[])
+        .map_err(|e| anyhow::anyhow!("Unable to export keying material: {:?}", e))?;
+
+    Ok(binding)
+}
+
+// takes any certificate, but still checks the server actually holds its key
+#[derive(Debug)]
+struct AnyCertificate(Arc<CryptoProvider>);
+
+impl ServerCertVerifier for AnyCertificate {
+    fn verify_server_cert(
+        &self,
+        _end_entity: &CertificateDer<'_>,
+        _intermediates: &[CertificateDer<'_>],
+        _server_name: &ServerName<'_>,
+        _ocsp_response: &[u8],
+        _now: UnixTime,
+    ) -> Result<ServerCertVerified, rustls::Error> {
+        Ok(ServerCertVerified::assertion())
+    }
+
+    fn verify_tls12_signature(
+        &self,
+        message: &[u8],
+        cert: &CertificateDer<'_>,
+        dss: &DigitallySignedStruct,
+    ) -> Result<HandshakeSignatureValid, rustls::Error> {
+        verify_tls12_signature(
+            message,
+            cert,
+            dss,
+            &self.0.signature_verification_algorithms,
+        )
+    }
+
+    fn verify_tls13_signature(
+        &self,
+        message: &[u8],
+        cert: &CertificateDer<'_>,
+        dss: &DigitallySignedStruct,
+    ) -> Result<HandshakeSignatureValid, rustls::Error> {
+        verify_tls13_signature(
+            message,
+            cert,
+            dss,
+            &self.0.signature_verification_algorithms,
+        )
+    }
+
+    fn supported_verify_schemes(&self) -> Vec<SignatureScheme> {
+        self.0.signature_verification_algorithms.supported_schemes()
+    }
+}
@@ -1,132 +1,186 @@
-use std::ops::{Deref, DerefMut};
+use std::{
+    ops::{Deref, DerefMut},
+    sync::Arc,
+};
 
-use fixedstr::zstr;
-use rand::{RngCore, rngs::OsRng};
+use log::info;
 use snowstorm::NoiseStream;
 use tokio::{
-    io::{self, AsyncReadExt, AsyncWriteExt, Interest, Ready},
-    net::TcpStream,
+    io::{self, AsyncRead, AsyncWrite},
+    sync::Mutex,
 };
 
-static HANDSHAKE_PARAM: &str = "Noise_XXpsk3_25519_ChaChaPoly_BLAKE2b";
-static TRANSPORT_PARAM: &str = "Noise_XX_25519_ChaChaPoly_BLAKE2b";
+use super::packets::{Capabilities, DynamicPacket, HelloPacket};
+use super::psk::{self, KeyId, Psk};
+
+pub use snowstorm::Keypair;
+
+static NOISE_PARAMS: &str = "Noise_XXpsk3_25519_ChaChaPoly_BLAKE2b";
+
+// what we prove to the other side during the handshake
+#[derive(Clone)]
+pub struct Credentials {
+    // this device's long-term identity, what the other side sees as remote_key()
+    pub keypair: Arc<Keypair>,
+    // shared by the whole deployment, see common::psk
+    //
+    // a server holding several secrets picks the one the client's greeting names,
+    // which is what lets it rotate its secret without locking anyone out
+    pub psk: Psk,
+}
+
+pub fn generate_keypair() -> Result<Keypair, anyhow::Error> {
+    Ok(snowstorm::Builder::new(NOISE_PARAMS.parse()?).generate_keypair()?)
+}
 
-pub struct SecureStream {
-    inner: NoiseStream<TcpStream>,
+// which end of the handshake we are, whoever opened the connection always initiates
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Role {
+    Initiator,
+    Responder,
 }
 
-impl SecureStream {
+// anything the protocol can run over: tcp, unix sockets, stdio pipes, in-memory duplexes...
+pub trait Transport: AsyncRead + AsyncWrite + Unpin + Send + 'static {}
+
+impl<T: AsyncRead + AsyncWrite + Unpin + Send + 'static> Transport for T {}
+
+// the transport is erased once split, so nothing past the handshake cares what it was
+pub type SecureReader = Box<dyn AsyncRead + Unpin + Send>;
+// shared by everything that writes to the connection, see packets::send_frame
+pub type SecureWriter = Arc<Mutex<Box<dyn AsyncWrite + Unpin + Send>>>;
+
+// how a connection's transactions reach the other side
+pub enum Streams {
+    // multiplexed over the one secure stream, see packets::frame
+    Shared,
+    // each transaction on its own QUIC stream, so a lost packet only stalls that one
+    #[cfg(feature = "quic")]
+    Quic(quinn::Connection),
+}
+
+pub struct SecureStream<T: Transport> {
+    inner: NoiseStream<T>,
+    remote: HelloPacket,
+    remote_key: [u8; 32],
+    key_id: KeyId,
+    capabilities: Capabilities,
+}
+
+impl<T: Transport> SecureStream<T> {
+    // a single XXpsk3 handshake, both static keys are exchanged (encrypted) and the psk is
+    // mixed in last, so only peers that know the passphrase ever see the other's key
     async fn handshake(
-        mut stream: TcpStream,
-        password: &[u8],
-    ) -> Result<NoiseStream<TcpStream>, anyhow::Error> {
-        let mut rng = OsRng;
-        let mut random = [0u8; 8];
-        rng.fill_bytes(&mut random);
-        let my_rng = u64::from_le_bytes(random);
-
-        stream.write_all(&random).await?;
-        stream.flush().await?;
-
-        // random collection
-        let mut buf = [0u8; 8];
-        stream.read_exact(&mut buf).await?;
-        let their_rng = u64::from_le_bytes(buf);
-
-        match my_rng > their_rng {
-            true => {
-                // secure initiator using 448 curve, psk on step 2
-                let keypair =
-                    snowstorm::Builder::new(HANDSHAKE_PARAM.parse()?).generate_keypair()?;
-                let initiator = snowstorm::Builder::new(HANDSHAKE_PARAM.parse()?)
-                    .local_private_key(&keypair.private)
-                    .psk(3, password)
-                    .build_initiator()?;
-
-                let mut handshake_stream = NoiseStream::handshake(stream, initiator).await?;
-
-                let keypair =
-                    snowstorm::Builder::new(TRANSPORT_PARAM.parse()?).generate_keypair()?;
-
-                handshake_stream.write_all(&keypair.public).await?;
-                handshake_stream.flush().await?;
-
-                let mut public = [0u8; 32];
-                handshake_stream.read_exact(&mut public).await?;
-
-                let initiator = snowstorm::Builder::new(TRANSPORT_PARAM.parse()?)
-                    .local_private_key(&keypair.private)
-                    .remote_public_key(&public)
-                    .build_initiator()?;
-
-                Ok(NoiseStream::handshake(handshake_stream.into_inner(), initiator).await?)
-            }
-            false => {
-                let keypair =
-                    snowstorm::Builder::new(HANDSHAKE_PARAM.parse()?).generate_keypair()?;
-                let responder = snowstorm::Builder::new(HANDSHAKE_PARAM.parse()?)
-                    .local_private_key(&keypair.private)
-                    .psk(3, password)
-                    .build_responder()?;
-
-                let mut handshake_stream = NoiseStream::handshake(stream, responder).await?;
-
-                let keypair =
-                    snowstorm::Builder::new(TRANSPORT_PARAM.parse()?).generate_keypair()?;
-
-                handshake_stream.write_all(&keypair.public).await?;
-                handshake_stream.flush().await?;
-                let mut public = [0u8; 32];
-                handshake_stream.read_exact(&mut public).await?;
-
-                let responder = snowstorm::Builder::new(TRANSPORT_PARAM.parse()?)
-                    .local_private_key(&keypair.private)
-                    .remote_public_key(&public)
-                    .build_responder()?;
-
-                Ok(NoiseStream::handshake(handshake_stream.into_inner(), responder).await?)
-            }
-        }
+        stream: T,
+        role: Role,
+        credentials: &Credentials,
+        prologue: &[u8],
+    ) -> Result<NoiseStream<T>, anyhow::Error> {
+        let builder = snowstorm::Builder::new(NOISE_PARAMS.parse()?)
+            .local_private_key(&credentials.keypair.private)
+            .psk(3, &credentials.psk)
+            .prologue(prologue);
+        let state = match role {
+            Role::Initiator => builder.build_initiator()?,
+            Role::Responder => builder.build_responder()?,
+        };
+
+        Ok(NoiseStream::handshake(stream, state).await?)
+    }
+
+    // both sides send their HELLO and read the other's, no ordering needed
+    async fn negotiate(
+        stream: &mut NoiseStream<T>,
+        local: &HelloPacket,
+    ) -> Result<HelloPacket, anyhow::Error> {
+        local.write(stream).await?;
+
+        Ok(HelloPacket::read(stream).await?)
     }
 
-    pub async fn new(stream: TcpStream, password_str: &zstr<32>) -> Result<Self, anyhow::Error> {
-        // todo replace with config or .env reading (maybe read from .syncr dir)
-        let mut password = [0u8; 32];
-        fill_buffer(&mut password, password_str);
+    pub async fn new(
+        stream: T,
+        role: Role,
+        credentials: &Credentials,
+        hello: HelloPacket,
+    ) -> Result<Self, anyhow::Error> {
+        Self::with_prologue(stream, role, credentials, hello, &[]).await
+    }
 
-        let encrypted_stream = Self::handshake(stream, &password).await?;
+    // same as new, but both sides must agree on `prologue` or the handshake fails,
+    // used to tie the handshake to whatever the transport already negotiated (QUIC's TLS)
+    pub async fn with_prologue(
+        stream: T,
+        role: Role,
+        credentials: &Credentials,
+        hello: HelloPacket,
+        prologue: &[u8],
+    ) -> Result<Self, anyhow::Error> {
+        let mut encrypted_stream = Self::handshake(stream, role, credentials, prologue).await?;
+        let remote_key = encrypted_stream
+            .get_state()
+            .get_remote_static()
+            .and_then(|key| key.try_into().ok())
+            .ok_or(anyhow::anyhow!(
+                "Handshake finished without the peer's static key"
+            ))?;
+
+        let remote = Self::negotiate(&mut encrypted_stream, &hello).await?;
+        let capabilities = hello.capabilities & remote.capabilities;
+
+        info!(
+            "Peer speaks protocol v{} (build {}), shared capabilities: {:?}",
+            remote.protocol_version, remote.build_version, capabilities
+        );
 
         Ok(Self {
             inner: encrypted_stream,
+            remote,
+            remote_key,
+            key_id: psk::key_id(&credentials.psk),
+            capabilities,
         })
     }
 
-    pub async fn ready(&self, interest: Interest) -> io::Result<Ready> {
-        self.inner.get_inner().ready(interest).await
+    // what the other side told us about itself during negotiation
+    pub fn remote(&self) -> &HelloPacket {
+        &self.remote
+    }
+
+    // the peer's static public key, proven by the handshake, what it should be trusted by
+    pub fn remote_key(&self) -> &[u8; 32] {
+        &self.remote_key
+    }
+
+    // which psk the handshake went through with, see psk::key_id
+    pub fn key_id(&self) -> &KeyId {
+        &self.key_id
+    }
+
+    // features both sides support, anything outside of this must not be used
+    pub fn capabilities(&self) -> Capabilities {
+        self.capabilities
+    }
+
+    // separate halves so one task can keep reading decrypted frames while others write
+    pub fn split(self) -> (SecureReader, SecureWriter) {
+        let (reader, writer) = io::split(self.inner);
+
+        (Box::new(reader), Arc::new(Mutex::new(Box::new(writer))))
     }
 }
 
 // re-expose traits of inner
-impl Deref for SecureStream {
-    type Target = NoiseStream<TcpStream>;
+impl<T: Transport> Deref for SecureStream<T> {
+    type Target = NoiseStream<T>;
 
     fn deref(&self) -> &Self::Target {
         &self.inner
     }
 }
 
-impl DerefMut for SecureStream {
+impl<T: Transport> DerefMut for SecureStream<T> {
     fn deref_mut(&mut self) -> &mut Self::Target {
         &mut self.inner
     }
 }
-
-fn fill_buffer(buf: &mut [u8], s: &zstr<32>) {
-    // Zero the buffer first
-    buf.fill(0);
-
-    // Copy as much of the string as will fit
-    let bytes = s.as_bytes();
-    let len = buf.len().min(bytes.len());
-    buf[..len].copy_from_slice(&bytes[..len]);
-}
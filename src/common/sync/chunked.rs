@@ -0,0 +1,450 @@
+use anyhow::{Context, Result};
+use memmap2::Mmap;
+use std::{
+    fs::File,
+    io::{self, Write},
+    path::Path,
+};
+use tempfile::NamedTempFile;
+use tokio::sync::mpsc;
+
+use fast_rsync::{Signature, diff};
+
+// how big a single SDLC gets, also the most delta either side buffers at once
+pub const DELTA_CHUNK_SIZE: usize = 1024 * 1024;
+
+// librsync delta format, which is what fast_rsync speaks
+const DELTA_MAGIC: u32 = 0x72730236;
+const OP_END: u8 = 0x00;
+const OP_LITERAL_1: u8 = 0x01;
+const OP_LITERAL_64: u8 = 0x40;
+const OP_LITERAL_N1: u8 = 0x41;
+const OP_LITERAL_N8: u8 = 0x44;
+const OP_COPY_N1_N1: u8 = 0x45;
+const OP_COPY_N8_N8: u8 = 0x54;
+
+// hands out the delta in DELTA_CHUNK_SIZE pieces as diff produces it
+struct ChunkWriter {
+    buf: Vec<u8>,
+    chunks: mpsc::Sender<Vec<u8>>,
+}
+
+impl ChunkWriter {
+    fn send(&mut self) -> io::Result<()> {
+        if self.buf.is_empty() {
+            return Ok(());
+        }
+
+        let chunk = std::mem::replace(&mut self.buf, Vec::with_capacity(DELTA_CHUNK_SIZE));
+        self.chunks
+            .blocking_send(chunk)
+            .map_err(|_| io::Error::new(io::ErrorKind::BrokenPipe, "Delta receiver went away"))
+    }
+}
+
+impl Write for ChunkWriter {
+    fn write(&mut self, data: &[u8]) -> io::Result<usize> {
+        let len = data.len().min(DELTA_CHUNK_SIZE - self.buf.len());
+        self.buf.extend_from_slice(&data[..len]);
+
+        if self.buf.len() == DELTA_CHUNK_SIZE {
+            self.send()?;
+        }
+
+        Ok(len)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.send()
+    }
+}
+
+// same as calculate_delta, but the delta is pushed into `chunks` as it's generated
+// instead of collected, blocks on a full channel so run it off the async runtime
+//
+// returns the blake3 of the exact contents the delta was made from, what the
+// other side should end up with once it's applied
+pub fn stream_delta(
+    file: &File,
+    serialized_signature: Vec<u8>,
+    chunks: mpsc::Sender<Vec<u8>>,
+) -> Result<blake3::Hash> {
+    let deserialized = Signature::deserialize(serialized_signature.into())
+        .context("Failed to deserialize signature")?;
+    let signature = deserialized.index();
+
+    let mmap = unsafe { Mmap::map(file)? };
+
+    let mut writer = ChunkWriter {
+        buf: Vec::with_capacity(DELTA_CHUNK_SIZE),
+        chunks,
+    };
+    diff(&signature, &mmap, &mut writer).context("Failed to calculate delta")?;
+    writer.flush()?;
+
+    Ok(blake3::Hasher::new().update_rayon(&mmap).finalize())
+}
+
+#[derive(Debug)]
+enum ApplierState {
+    Magic,
+    Command,
+    Literal(u64),
+    Done,
+}
+
+// applies a delta chunk by chunk into a temp file next to the target,
+// only ever holding a half-read command header in memory
+#[derive(Debug)]
+pub struct DeltaApplier {
+    base: Mmap,
+    output: NamedTempFile,
+    pending: Vec<u8>,
+    state: ApplierState,
+    written: u64,
+    // what the sender said the file comes out to, never written past
+    size: u64,
+}
+
+impl DeltaApplier {
+    pub fn new(path: &Path, size: u64) -> Result<Self> {
+        let file = File::options()
+            .read(true)
+            .open(path)
+            .context("Failed to open the original file for reading")?;
+        let base = unsafe { Mmap::map(&file)? };
+
+        let parent = path
+            .parent()
+            .ok_or(anyhow::anyhow!("Unable to get parent dir"))?;
+        // next to the target so the final rename stays on the same filesystem
+        let output = NamedTempFile::new_in(parent)?;
+
+        Ok(Self {
+            base,
+            output,
+            pending: Vec::new(),
+            state: ApplierState::Magic,
+            written: 0,
+            size,
+        })
+    }
+
+    pub fn push(&mut self, chunk: &[u8]) -> Result<()> {
+        // take the buffer out so step can borrow it while writing through self
+        let mut pending = std::mem::take(&mut self.pending);
+        pending.extend_from_slice(chunk);
+
+        let mut cursor = 0;
+        while let Some(consumed) = self.step(&pending[cursor..])? {
+            cursor += consumed;
+        }
+        pending.drain(..cursor);
+        self.pending = pending;
+
+        Ok(())
+    }
+
+    // runs one command off the front of `data`, None once it needs more bytes
+    fn step(&mut self, data: &[u8]) -> Result<Option<usize>> {
+        match self.state {
+            ApplierState::Done if !data.is_empty() => {
+                anyhow::bail!("Delta continues past its END command")
+            }
+            ApplierState::Done => Ok(None),
+            ApplierState::Magic => {
+                let Some(magic) = read_be(data, 0, 4) else {
+                    return Ok(None);
+                };
+                if magic != DELTA_MAGIC as u64 {
+                    anyhow::bail!("Bad delta magic {:#x}", magic);
+                }
+
+                self.state = ApplierState::Command;
+                Ok(Some(4))
+            }
+            ApplierState::Literal(remaining) => {
+                if data.is_empty() {
+                    return Ok(None);
+                }
+
+                let len = data
+                    .len()
+                    .min(usize::try_from(remaining).unwrap_or(usize::MAX));
+                self.write(&data[..len])?;
+
+                self.state = match remaining - len as u64 {
+                    0 => ApplierState::Command,
+                    left => ApplierState::Literal(left),
+                };
+                Ok(Some(len))
+            }
+            ApplierState::Command => {
+                let Some(&command) = data.first() else {
+                    return Ok(None);
+                };
+
+                match command {
+                    OP_END => {
+                        self.state = ApplierState::Done;
+                        Ok(Some(1))
+                    }
+                    OP_LITERAL_1..=OP_LITERAL_64 => {
+                        self.state = ApplierState::Literal(command as u64);
+                        Ok(Some(1))
+                    }
+                    OP_LITERAL_N1..=OP_LITERAL_N8 => {
+                        let len_len = 1 << (command - OP_LITERAL_N1);
+                        let Some(len) = read_be(data, 1, len_len) else {
+                            return Ok(None);
+                        };
+
+                        self.state = match len {
+                            0 => ApplierState::Command,
+                            len => ApplierState::Literal(len),
+                        };
+                        Ok(Some(1 + len_len))
+                    }
+                    OP_COPY_N1_N1..=OP_COPY_N8_N8 => {
+                        let mode = command - OP_COPY_N1_N1;
+                        let offset_len = 1 << (mode / 4);
+                        let len_len = 1 << (mode % 4);
+
+                        let (Some(offset), Some(len)) = (
+                            read_be(data, 1, offset_len),
+                            read_be(data, 1 + offset_len, len_len),
+                        ) else {
+                            return Ok(None);
+                        };
+
+                        let block = offset
+                            .checked_add(len)
+                            .and_then(|end| usize::try_from(end).ok())
+                            .filter(|&end| end <= self.base.len())
+                            .map(|end| offset as usize..end)
+                            .ok_or(anyhow::anyhow!(
+                                "Delta copies {} bytes at {} out of a {} byte file",
+                                len,
+                                offset,
+                                self.base.len()
+                            ))?;
+
+                        self.reserve(len)?;
+                        self.output.write_all(&self.base[block])?;
+                        self.written += len;
+
+                        Ok(Some(1 + offset_len + len_len))
+                    }
+                    other => anyhow::bail!("Unknown delta command {:#x}", other),
+                }
+            }
+        }
+    }
+
+    fn write(&mut self, data: &[u8]) -> Result<()> {
+        self.reserve(data.len() as u64)?;
+        self.output.write_all(data)?;
+        self.written += data.len() as u64;
+
+        Ok(())
+    }
+
+    // refuses anything that would grow the output past the declared size, a delta
+    // can copy the same block over and over so this can't wait until finish
+    fn reserve(&self, len: u64) -> Result<()> {
+        if self.written.saturating_add(len) > self.size {
+            anyhow::bail!("Delta produces more than the declared {} bytes", self.size);
+        }
+
+        Ok(())
+    }
+
+    // checks the delta actually ended and hands back the rebuilt file, ready to persist
+    pub fn finish(mut self) -> Result<NamedTempFile> {
+        if !matches!(self.state, ApplierState::Done) || !self.pending.is_empty() {
+            anyhow::bail!("Delta ended early");
+        }
+        if self.written != self.size {
+            anyhow::bail!(
+                "Delta produced {} bytes, expected {}",
+                self.written,
+                self.size
+            );
+        }
+
+        self.output
+            .flush()
+            .context("Failed to flush temporary file")?;
+
+        Ok(self.output)
+    }
+}
+
+// big endian integer of `len` bytes at `at`, None if data is too short
+fn read_be(data: &[u8], at: usize, len: usize) -> Option<u64> {
+    let bytes = data.get(at..at + len)?;
+
+    Some(bytes.iter().fold(0u64, |acc, &b| (acc << 8) | b as u64))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use fast_rsync::{SignatureOptions, apply};
+    use rand::{Rng, SeedableRng, rngs::StdRng};
+
+    const OPTIONS: SignatureOptions = SignatureOptions {
+        block_size: 64,
+        crypto_hash_size: 8,
+    };
+
+    // a base file plus a version of it with bits moved around, dropped and added
+    fn versions(seed: u64) -> (Vec<u8>, Vec<u8>) {
+        let mut rng = StdRng::seed_from_u64(seed);
+        let base: Vec<u8> = (0..rng.gen_range(0..20_000)).map(|_| rng.r#gen()).collect();
+
+        let mut new = Vec::new();
+        while new.len() < 24_000 && !base.is_empty() {
+            let start = rng.gen_range(0..base.len());
+            let end = (start + rng.gen_range(1..2_000)).min(base.len());
+            new.extend_from_slice(&base[start..end]);
+            new.extend((0..rng.gen_range(0..300)).map(|_| rng.r#gen::<u8>()));
+        }
+
+        (base, new)
+    }
+
+    fn delta(base: &[u8], new: &[u8]) -> Vec<u8> {
+        let signature = Signature::calculate(base, OPTIONS);
+        let mut delta = Vec::new();
+        diff(&signature.index(), new, &mut delta).unwrap();
+        delta
+    }
+
+    fn base_file(base: &[u8]) -> NamedTempFile {
+        let mut file = NamedTempFile::new().unwrap();
+        file.write_all(base).unwrap();
+        file
+    }
+
+    // pushes the delta in pieces of `chunk` bytes and reads back what came out
+    fn applied(base: &NamedTempFile, delta: &[u8], size: u64, chunk: usize) -> Result<Vec<u8>> {
+        let mut applier = DeltaApplier::new(base.path(), size)?;
+        for piece in delta.chunks(chunk) {
+            applier.push(piece)?;
+        }
+
+        Ok(std::fs::read(applier.finish()?.path())?)
+    }
+
+    #[test]
+    fn matches_fast_rsync() {
+        for seed in 0..20 {
+            let (base, new) = versions(seed);
+            let delta = delta(&base, &new);
+            let file = base_file(&base);
+
+            let mut expected = Vec::new();
+            apply(&base, &delta, &mut expected).unwrap();
+            assert_eq!(expected, new);
+
+            // every split, down to one byte at a time, lands command headers across pushes
+            for chunk in [1, 3, 7, 64, 1000, delta.len().max(1)] {
+                let rebuilt = applied(&file, &delta, new.len() as u64, chunk).unwrap();
+                assert_eq!(rebuilt, expected, "seed {seed}, chunks of {chunk}");
+            }
+        }
+    }
+
+    #[test]
+    fn literal_lengths() {
+        // one of each literal command, no base to copy from
+        let file = base_file(&[]);
+        for len in [0usize, 1, 64, 65, 255, 256, 70_000] {
+            let new: Vec<u8> = (0..len).map(|i| i as u8).collect();
+            let delta = delta(&[], &new);
+
+            let mut expected = Vec::new();
+            apply(&[], &delta, &mut expected).unwrap();
+            assert_eq!(applied(&file, &delta, len as u64, 100).unwrap(), expected);
+        }
+    }
+
+    #[test]
+    fn truncated() {
+        let (base, new) = versions(1);
+        let delta = delta(&base, &new);
+        let file = base_file(&base);
+
+        for cut in [0, 3, 4, 5, delta.len() / 2, delta.len() - 1] {
+            let truncated = &delta[..cut];
+            assert!(apply(&base, truncated, &mut Vec::new()).is_err());
+            assert!(applied(&file, truncated, new.len() as u64, 10).is_err());
+        }
+    }
+
+    #[test]
+    fn malformed() {
+        let base = b"0123456789abcdef";
+        let file = base_file(base);
+        let magic = DELTA_MAGIC.to_be_bytes();
+
+        let cases: [(&str, Vec<u8>, u64); 6] = [
+            ("bad magic", vec![0x72, 0x73, 0x02, 0x37, OP_END], 0),
+            ("unknown command", [&magic[..], &[0x55, OP_END]].concat(), 0),
+            (
+                "copy past the end of the base",
+                [&magic[..], &[OP_COPY_N1_N1, 10, 7, OP_END]].concat(),
+                7,
+            ),
+            (
+                "copy length overflowing",
+                [
+                    &magic[..],
+                    &[OP_COPY_N8_N8],
+                    &u64::MAX.to_be_bytes(),
+                    &u64::MAX.to_be_bytes(),
+                    &[OP_END],
+                ]
+                .concat(),
+                0,
+            ),
+            (
+                "trailing bytes after END",
+                [&magic[..], &[OP_LITERAL_1, b'x', OP_END, 0]].concat(),
+                1,
+            ),
+            (
+                "shorter than declared",
+                [&magic[..], &[OP_LITERAL_1 + 1, b'x', b'y', OP_END]].concat(),
+                3,
+            ),
+        ];
+
+        for (name, delta, size) in cases {
+            assert!(applied(&file, &delta, size, 2).is_err(), "{name}");
+        }
+    }
+
+    #[test]
+    fn stops_at_declared_size() {
+        let base = vec![7u8; 4096];
+        let file = base_file(&base);
+
+        // the whole base copied over and over, a small delta for a huge file
+        let mut delta = DELTA_MAGIC.to_be_bytes().to_vec();
+        for _ in 0..1000 {
+            delta.extend_from_slice(&[OP_COPY_N1_N1 + 1, 0, 0x10, 0x00]);
+        }
+        delta.push(OP_END);
+
+        let mut applier = DeltaApplier::new(file.path(), 8192).unwrap();
+        let error = applier.push(&delta).unwrap_err();
+        assert!(error.to_string().contains("declared"), "{error}");
+        assert_eq!(applier.written, 8192);
+
+        // a literal going over has to be caught too, not only copies
+        let literal = [&DELTA_MAGIC.to_be_bytes()[..], &[OP_LITERAL_1 + 2, 1, 2, 3]].concat();
+        assert!(applied(&file, &literal, 2, 1).is_err());
+    }
+}
@@ -1,5 +1,7 @@
+mod chunked;
 mod delta;
 mod signature;
 
+pub use chunked::{DeltaApplier, stream_delta};
 pub use delta::{apply_delta, calculate_delta};
-pub use signature::calculate_signature;
+pub use signature::{calculate_signature, signature_with};
@@ -8,21 +8,28 @@ pub fn calculate_signature(
     file: &mut File,
     predictor: &mut CompressionTree,
 ) -> anyhow::Result<(Vec<u8>, u32)> {
-    let mmap = unsafe { Mmap::map(&*file)? };
-    let file_len = mmap.len();
+    let file_len = file.metadata()?.len() as usize;
 
     let predicted_block_size = predictor.wonderful_predict(file_len);
 
     //* temporary replacement for testing :)
     // let predicted_block_size = 4096;
 
+    Ok((
+        signature_with(file, predicted_block_size)?,
+        predicted_block_size,
+    ))
+}
+
+// the expensive half of calculate_signature, for when the block size was already
+// picked and the predictor shouldn't stay locked while the whole file is read
+pub fn signature_with(file: &File, block_size: u32) -> anyhow::Result<Vec<u8>> {
+    let mmap = unsafe { Mmap::map(file)? };
+
     let options = SignatureOptions {
-        block_size: predicted_block_size,
+        block_size,
         crypto_hash_size: 8,
     };
 
-    Ok((
-        Signature::calculate(&mmap, options).into_serialized(),
-        predicted_block_size,
-    ))
+    Ok(Signature::calculate(&mmap, options).into_serialized())
 }
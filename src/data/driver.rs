@@ -4,10 +4,11 @@ use std::{
     path::PathBuf,
 };
 
-use diesel::{Connection, SqliteConnection};
+use diesel::{Connection, SqliteConnection, connection::SimpleConnection};
 use diesel_migrations::{EmbeddedMigrations, MigrationHarness};
 use dirs::home_dir;
 
+#[allow(async_fn_in_trait)]
 pub trait DatabaseDriver: Deref<Target = SqliteConnection> + DerefMut + Sized {
     const MIGRATIONS: [EmbeddedMigrations; 2];
 
@@ -34,6 +35,12 @@ pub trait DatabaseDriver: Deref<Target = SqliteConnection> + DerefMut + Sized {
             .to_str()
             .ok_or(anyhow::anyhow!("Unable to convert path to string"))
             .and_then(|path_str| SqliteConnection::establish(&path_str).map_err(Into::into))
+            .and_then(|mut database| {
+                // the watchers and `syncr admin` write next to whoever else has it open,
+                // wait them out instead of failing with SQLITE_BUSY
+                database.batch_execute("PRAGMA busy_timeout = 5000;")?;
+                Ok(database)
+            })
     }
 
     async fn new(path: Option<PathBuf>) -> Result<Self, anyhow::Error>;
@@ -0,0 +1,112 @@
+use crate::schema::handshake_failures;
+use diesel::{prelude::*, query_builder::QueryFragment, sqlite::Sqlite};
+
+use super::base::BaseEntity;
+
+// a connection that never made it past the handshake (wrong secret, a key that doesn't
+// match the pinned one, timed out...) or got an ip banned, kept for the admin to look at
+#[derive(Queryable, Selectable, Identifiable, AsChangeset, Debug, Clone)]
+#[diesel(table_name = handshake_failures)]
+#[diesel(check_for_backend(diesel::sqlite::Sqlite))]
+pub struct HandshakeFailure {
+    pub id: i32,
+
+    pub ip: String,
+
+    pub reason: String,
+
+    pub created_at: chrono::NaiveDateTime,
+
+    pub updated_at: chrono::NaiveDateTime,
+}
+
+#[derive(Insertable)]
+#[diesel(table_name = handshake_failures)]
+pub struct NewHandshakeFailure {
+    pub ip: String,
+
+    pub reason: String,
+
+    pub created_at: chrono::NaiveDateTime,
+
+    pub updated_at: chrono::NaiveDateTime,
+}
+
+impl Default for NewHandshakeFailure {
+    fn default() -> Self {
+        Self {
+            ip: String::new(),
+            reason: String::new(),
+            created_at: chrono::Utc::now().naive_utc(),
+            updated_at: chrono::Utc::now().naive_utc(),
+        }
+    }
+}
+
+impl BaseEntity for HandshakeFailure {
+    type NewEntityType = NewHandshakeFailure;
+    type Table = handshake_failures::table;
+
+    fn find_by_id(id_: i32, conn: &mut SqliteConnection) -> anyhow::Result<Option<Self>> {
+        use crate::schema::handshake_failures::dsl::*;
+
+        Ok(handshake_failures
+            .filter(id.eq(id_))
+            .first::<Self>(conn)
+            .optional()?)
+    }
+
+    fn insert(entity: NewHandshakeFailure, conn: &mut SqliteConnection) -> anyhow::Result<()> {
+        use crate::schema::handshake_failures;
+
+        diesel::insert_into(handshake_failures::table)
+            .values(entity)
+            .execute(conn)?;
+
+        Ok(())
+    }
+
+    fn update<S>(&self, conn: &mut SqliteConnection, changes: S) -> anyhow::Result<()>
+    where
+        S: AsChangeset<Target = <Self as BaseEntity>::Table> + Send,
+        <S as diesel::AsChangeset>::Changeset: QueryFragment<Sqlite> + Send,
+    {
+        conn.transaction(|conn| {
+            diesel::update(&self).set(changes).execute(conn)?;
+
+            Ok(())
+        })
+    }
+}
+
+impl HandshakeFailure {
+    pub fn record(ip_: &str, reason_: &str, conn: &mut SqliteConnection) -> anyhow::Result<()> {
+        Self::insert(
+            NewHandshakeFailure {
+                ip: ip_.to_owned(),
+                reason: reason_.to_owned(),
+                ..Default::default()
+            },
+            conn,
+        )
+    }
+
+    // newest first
+    pub fn latest(limit: i64, conn: &mut SqliteConnection) -> anyhow::Result<Vec<Self>> {
+        use crate::schema::handshake_failures::dsl::*;
+
+        Ok(handshake_failures
+            .order(created_at.desc())
+            .limit(limit)
+            .load::<Self>(conn)?)
+    }
+
+    // anything older than `age` has done its job, a flood shouldn't grow the table forever
+    pub fn prune(age: chrono::Duration, conn: &mut SqliteConnection) -> anyhow::Result<usize> {
+        use crate::schema::handshake_failures::dsl::*;
+
+        let cutoff = chrono::Utc::now().naive_utc() - age;
+
+        Ok(diesel::delete(handshake_failures.filter(created_at.lt(cutoff))).execute(conn)?)
+    }
+}
@@ -0,0 +1,185 @@
+use crate::schema::known_devices;
+use diesel::{prelude::*, query_builder::QueryFragment, sqlite::Sqlite};
+
+use super::base::BaseEntity;
+
+// a peer's static key as we first saw it, like ssh's known_hosts
+// the server keys clients by the device name they announce, clients key the server by its address
+#[derive(Queryable, Selectable, Identifiable, AsChangeset, Debug, Clone)]
+#[diesel(table_name = known_devices)]
+#[diesel(check_for_backend(diesel::sqlite::Sqlite))]
+pub struct KnownDevice {
+    pub id: i32,
+
+    pub name: String,
+
+    pub public_key: Vec<u8>,
+
+    pub created_at: chrono::NaiveDateTime,
+
+    // last time the device connected with this key
+    pub updated_at: chrono::NaiveDateTime,
+}
+
+#[derive(Insertable)]
+#[diesel(table_name = known_devices)]
+pub struct NewKnownDevice {
+    pub name: String,
+
+    pub public_key: Vec<u8>,
+
+    pub created_at: chrono::NaiveDateTime,
+
+    pub updated_at: chrono::NaiveDateTime,
+}
+
+impl Default for NewKnownDevice {
+    fn default() -> Self {
+        Self {
+            name: String::new(),
+            public_key: Vec::new(),
+            created_at: chrono::Utc::now().naive_utc(),
+            updated_at: chrono::Utc::now().naive_utc(),
+        }
+    }
+}
+
+impl BaseEntity for KnownDevice {
+    type NewEntityType = NewKnownDevice;
+    type Table = known_devices::table;
+
+    fn find_by_id(id_: i32, conn: &mut SqliteConnection) -> anyhow::Result<Option<Self>> {
+        use crate::schema::known_devices::dsl::*;
+
+        Ok(known_devices
+            .filter(id.eq(id_))
+            .first::<Self>(conn)
+            .optional()?)
+    }
+
+    fn insert(entity: NewKnownDevice, conn: &mut SqliteConnection) -> anyhow::Result<()> {
+        use crate::schema::known_devices;
+
+        diesel::insert_into(known_devices::table)
+            .values(entity)
+            .execute(conn)?;
+
+        Ok(())
+    }
+
+    fn update<S>(&self, conn: &mut SqliteConnection, changes: S) -> anyhow::Result<()>
+    where
+        S: AsChangeset<Target = <Self as BaseEntity>::Table> + Send,
+        <S as diesel::AsChangeset>::Changeset: QueryFragment<Sqlite> + Send,
+    {
+        conn.transaction(|conn| {
+            diesel::update(&self).set(changes).execute(conn)?;
+
+            Ok(())
+        })
+    }
+}
+
+// what verify made of a key
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Trust {
+    // never seen this device before, the key is pinned from now on
+    New,
+    // same key as every time before
+    Known,
+}
+
+impl KnownDevice {
+    pub fn find_by_name(name_: &str, conn: &mut SqliteConnection) -> anyhow::Result<Option<Self>> {
+        use crate::schema::known_devices::dsl::*;
+
+        Ok(known_devices
+            .filter(name.eq(name_))
+            .first::<Self>(conn)
+            .optional()?)
+    }
+
+    // trust on first use, the first key a name shows up with is the only one it's ever let in with
+    pub fn verify(name_: &str, key: &[u8], conn: &mut SqliteConnection) -> anyhow::Result<Trust> {
+        use crate::schema::known_devices::dsl::*;
+
+        let Some(device) = Self::find_by_name(name_, conn)? else {
+            Self::insert(
+                NewKnownDevice {
+                    name: name_.to_owned(),
+                    public_key: key.to_vec(),
+                    ..Default::default()
+                },
+                conn,
+            )?;
+
+            return Ok(Trust::New);
+        };
+
+        if device.public_key != key {
+            anyhow::bail!(
+                "@@@ IDENTITY OF {} HAS CHANGED @@@\n\
+                 It presented key {} but {} was pinned on {}. Someone could be impersonating it, \
+                 or it was reinstalled. If you trust the new key, `forget` the old one and reconnect.",
+                name_,
+                fingerprint(key),
+                fingerprint(&device.public_key),
+                device.created_at
+            );
+        }
+
+        device.update(conn, updated_at.eq(chrono::Utc::now().naive_utc()))?;
+
+        Ok(Trust::Known)
+    }
+
+    // trusts `key` for the name whatever was pinned before, for when it's been vouched for (pairing)
+    pub fn pin(name_: &str, key: &[u8], conn: &mut SqliteConnection) -> anyhow::Result<()> {
+        conn.transaction(|conn| {
+            Self::forget(name_, conn)?;
+            Self::insert(
+                NewKnownDevice {
+                    name: name_.to_owned(),
+                    public_key: key.to_vec(),
+                    ..Default::default()
+                },
+                conn,
+            )
+        })
+    }
+
+    // pins `key` for a name nobody holds yet, false if it's already pinned to another key
+    // (the same key again is fine, a device may pair twice)
+    pub fn claim(name_: &str, key: &[u8], conn: &mut SqliteConnection) -> anyhow::Result<bool> {
+        conn.exclusive_transaction(|conn| {
+            if let Some(device) = Self::find_by_name(name_, conn)? {
+                return Ok(device.public_key == key);
+            }
+
+            Self::insert(
+                NewKnownDevice {
+                    name: name_.to_owned(),
+                    public_key: key.to_vec(),
+                    ..Default::default()
+                },
+                conn,
+            )?;
+
+            Ok(true)
+        })
+    }
+
+    // drops a pinned key, the next one the device connects with is trusted instead
+    pub fn forget(name_: &str, conn: &mut SqliteConnection) -> anyhow::Result<bool> {
+        use crate::schema::known_devices::dsl::*;
+
+        let deleted = diesel::delete(known_devices.filter(name.eq(name_))).execute(conn)?;
+
+        Ok(deleted > 0)
+    }
+}
+
+// short, readable form of a key for logs and warnings
+pub fn fingerprint(key: &[u8]) -> String {
+    blake3::hash(key).to_hex()[..16].to_owned()
+}
@@ -1,4 +1,9 @@
 mod base;
+pub mod handshake_failure;
+pub mod known_device;
+pub mod pairing_code;
 pub mod predictor;
+pub mod repo_permission;
+pub mod sync_job;
 
 pub use base::BaseEntity;
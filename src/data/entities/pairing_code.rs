@@ -0,0 +1,135 @@
+use crate::schema::pairing_codes;
+use diesel::{prelude::*, query_builder::QueryFragment, sqlite::Sqlite};
+
+use super::base::BaseEntity;
+
+// a one-time code an admin handed out to onboard a device (see common::pairing)
+// only the psk derived from it is kept, the code itself is never stored
+#[derive(Queryable, Selectable, Identifiable, AsChangeset, Debug, Clone)]
+#[diesel(table_name = pairing_codes)]
+#[diesel(check_for_backend(diesel::sqlite::Sqlite))]
+pub struct PairingCode {
+    pub id: i32,
+
+    pub code_id: String,
+
+    pub psk: Vec<u8>,
+
+    pub expires_at: chrono::NaiveDateTime,
+
+    // set once a device got through the handshake with it, guessing is left to the throttle
+    pub used_at: Option<chrono::NaiveDateTime>,
+
+    pub created_at: chrono::NaiveDateTime,
+
+    pub updated_at: chrono::NaiveDateTime,
+}
+
+#[derive(Insertable)]
+#[diesel(table_name = pairing_codes)]
+pub struct NewPairingCode {
+    pub code_id: String,
+
+    pub psk: Vec<u8>,
+
+    pub expires_at: chrono::NaiveDateTime,
+
+    pub created_at: chrono::NaiveDateTime,
+
+    pub updated_at: chrono::NaiveDateTime,
+}
+
+impl Default for NewPairingCode {
+    fn default() -> Self {
+        Self {
+            code_id: String::new(),
+            psk: Vec::new(),
+            expires_at: chrono::Utc::now().naive_utc(),
+            created_at: chrono::Utc::now().naive_utc(),
+            updated_at: chrono::Utc::now().naive_utc(),
+        }
+    }
+}
+
+impl BaseEntity for PairingCode {
+    type NewEntityType = NewPairingCode;
+    type Table = pairing_codes::table;
+
+    fn find_by_id(id_: i32, conn: &mut SqliteConnection) -> anyhow::Result<Option<Self>> {
+        use crate::schema::pairing_codes::dsl::*;
+
+        Ok(pairing_codes
+            .filter(id.eq(id_))
+            .first::<Self>(conn)
+            .optional()?)
+    }
+
+    fn insert(entity: NewPairingCode, conn: &mut SqliteConnection) -> anyhow::Result<()> {
+        use crate::schema::pairing_codes;
+
+        diesel::insert_into(pairing_codes::table)
+            .values(entity)
+            .execute(conn)?;
+
+        Ok(())
+    }
+
+    fn update<S>(&self, conn: &mut SqliteConnection, changes: S) -> anyhow::Result<()>
+    where
+        S: AsChangeset<Target = <Self as BaseEntity>::Table> + Send,
+        <S as diesel::AsChangeset>::Changeset: QueryFragment<Sqlite> + Send,
+    {
+        conn.transaction(|conn| {
+            diesel::update(&self).set(changes).execute(conn)?;
+
+            Ok(())
+        })
+    }
+}
+
+impl PairingCode {
+    // the psk for a code that's still good, it stays good until redeemed
+    pub fn usable(code_id_: &str, conn: &mut SqliteConnection) -> anyhow::Result<Option<Vec<u8>>> {
+        use crate::schema::pairing_codes::dsl::*;
+
+        let now = chrono::Utc::now().naive_utc();
+
+        Ok(pairing_codes
+            .filter(code_id.eq(code_id_))
+            .filter(used_at.is_null())
+            .filter(expires_at.gt(now))
+            .select(psk)
+            .first::<Vec<u8>>(conn)
+            .optional()?)
+    }
+
+    // burns a code that's still good, false when someone else got to it first
+    pub fn redeem(code_id_: &str, conn: &mut SqliteConnection) -> anyhow::Result<bool> {
+        use crate::schema::pairing_codes::dsl::*;
+
+        let now = chrono::Utc::now().naive_utc();
+
+        let burned = diesel::update(
+            pairing_codes
+                .filter(code_id.eq(code_id_))
+                .filter(used_at.is_null())
+                .filter(expires_at.gt(now)),
+        )
+        .set((used_at.eq(Some(now)), updated_at.eq(now)))
+        .execute(conn)?;
+
+        Ok(burned > 0)
+    }
+
+    // codes that are used or expired are no good to anyone
+    pub fn prune(conn: &mut SqliteConnection) -> anyhow::Result<usize> {
+        use crate::schema::pairing_codes::dsl::*;
+
+        let now = chrono::Utc::now().naive_utc();
+
+        Ok(
+            diesel::delete(pairing_codes.filter(used_at.is_not_null().or(expires_at.le(now))))
+                .execute(conn)?,
+        )
+    }
+}
@@ -0,0 +1,173 @@
+use crate::schema::repo_permissions;
+use diesel::{prelude::*, query_builder::QueryFragment, sqlite::Sqlite};
+
+use super::base::BaseEntity;
+
+// can see the repository but not change anything in it
+pub const PERMISSION_READ_ONLY: &str = "ro";
+// can push changes to it too
+pub const PERMISSION_READ_WRITE: &str = "rw";
+
+// what a device may do with a syncr_id, devices are the names their keys are pinned under
+// (see known_device), no row means no access at all
+#[derive(Queryable, Selectable, Identifiable, AsChangeset, Debug, Clone)]
+#[diesel(table_name = repo_permissions)]
+#[diesel(check_for_backend(diesel::sqlite::Sqlite))]
+pub struct RepoPermission {
+    pub id: i32,
+
+    pub device: String,
+
+    pub syncr_id: String,
+
+    pub permission: String,
+
+    pub created_at: chrono::NaiveDateTime,
+
+    pub updated_at: chrono::NaiveDateTime,
+}
+
+#[derive(Insertable)]
+#[diesel(table_name = repo_permissions)]
+pub struct NewRepoPermission {
+    pub device: String,
+
+    pub syncr_id: String,
+
+    pub permission: String,
+
+    pub created_at: chrono::NaiveDateTime,
+
+    pub updated_at: chrono::NaiveDateTime,
+}
+
+impl Default for NewRepoPermission {
+    fn default() -> Self {
+        Self {
+            device: String::new(),
+            syncr_id: String::new(),
+            permission: PERMISSION_READ_ONLY.to_owned(),
+            created_at: chrono::Utc::now().naive_utc(),
+            updated_at: chrono::Utc::now().naive_utc(),
+        }
+    }
+}
+
+impl BaseEntity for RepoPermission {
+    type NewEntityType = NewRepoPermission;
+    type Table = repo_permissions::table;
+
+    fn find_by_id(id_: i32, conn: &mut SqliteConnection) -> anyhow::Result<Option<Self>> {
+        use crate::schema::repo_permissions::dsl::*;
+
+        Ok(repo_permissions
+            .filter(id.eq(id_))
+            .first::<Self>(conn)
+            .optional()?)
+    }
+
+    fn insert(entity: NewRepoPermission, conn: &mut SqliteConnection) -> anyhow::Result<()> {
+        use crate::schema::repo_permissions;
+
+        diesel::insert_into(repo_permissions::table)
+            .values(entity)
+            .execute(conn)?;
+
+        Ok(())
+    }
+
+    fn update<S>(&self, conn: &mut SqliteConnection, changes: S) -> anyhow::Result<()>
+    where
+        S: AsChangeset<Target = <Self as BaseEntity>::Table> + Send,
+        <S as diesel::AsChangeset>::Changeset: QueryFragment<Sqlite> + Send,
+    {
+        conn.transaction(|conn| {
+            diesel::update(&self).set(changes).execute(conn)?;
+
+            Ok(())
+        })
+    }
+}
+
+impl RepoPermission {
+    pub fn find(
+        device_: &str,
+        syncr_id_: &str,
+        conn: &mut SqliteConnection,
+    ) -> anyhow::Result<Option<Self>> {
+        use crate::schema::repo_permissions::dsl::*;
+
+        Ok(repo_permissions
+            .filter(device.eq(device_))
+            .filter(syncr_id.eq(syncr_id_))
+            .first::<Self>(conn)
+            .optional()?)
+    }
+
+    pub fn can_write(&self) -> bool {
+        self.permission == PERMISSION_READ_WRITE
+    }
+
+    // gives a device access to a syncr_id, replacing whatever it had before
+    pub fn grant(
+        device_: &str,
+        syncr_id_: &str,
+        permission_: &str,
+        conn: &mut SqliteConnection,
+    ) -> anyhow::Result<()> {
+        use crate::schema::repo_permissions::dsl::*;
+
+        if ![PERMISSION_READ_ONLY, PERMISSION_READ_WRITE].contains(&permission_) {
+            anyhow::bail!(
+                "Unknown permission {:?}, expected {:?} or {:?}",
+                permission_,
+                PERMISSION_READ_ONLY,
+                PERMISSION_READ_WRITE
+            );
+        }
+
+        match Self::find(device_, syncr_id_, conn)? {
+            Some(existing) => existing.update(
+                conn,
+                (
+                    permission.eq(permission_),
+                    updated_at.eq(chrono::Utc::now().naive_utc()),
+                ),
+            ),
+            None => Self::insert(
+                NewRepoPermission {
+                    device: device_.to_owned(),
+                    syncr_id: syncr_id_.to_owned(),
+                    permission: permission_.to_owned(),
+                    ..Default::default()
+                },
+                conn,
+            ),
+        }
+    }
+
+    pub fn revoke(
+        device_: &str,
+        syncr_id_: &str,
+        conn: &mut SqliteConnection,
+    ) -> anyhow::Result<bool> {
+        use crate::schema::repo_permissions::dsl::*;
+
+        let deleted = diesel::delete(
+            repo_permissions
+                .filter(device.eq(device_))
+                .filter(syncr_id.eq(syncr_id_)),
+        )
+        .execute(conn)?;
+
+        Ok(deleted > 0)
+    }
+
+    pub fn all(conn: &mut SqliteConnection) -> anyhow::Result<Vec<Self>> {
+        use crate::schema::repo_permissions::dsl::*;
+
+        Ok(repo_permissions
+            .order((device.asc(), syncr_id.asc()))
+            .load::<Self>(conn)?)
+    }
+}
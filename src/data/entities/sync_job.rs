@@ -0,0 +1,186 @@
+use crate::schema::sync_jobs;
+use diesel::{prelude::*, query_builder::QueryFragment, sqlite::Sqlite};
+
+use super::base::BaseEntity;
+
+pub const STATUS_PENDING: &str = "pending";
+pub const STATUS_FAILED: &str = "failed";
+
+// a change that still has to reach the server, replayed in id order once we're connected
+// rows are deleted once synced, failed ones stick around for the user to look at
+#[derive(Queryable, Selectable, Identifiable, AsChangeset, Debug, Clone)]
+#[diesel(table_name = sync_jobs)]
+#[diesel(check_for_backend(diesel::sqlite::Sqlite))]
+pub struct SyncJob {
+    pub id: i32,
+
+    pub path: String,
+
+    pub syncr_id: String,
+
+    pub known_name: String,
+
+    pub status: String,
+
+    pub attempts: i32,
+
+    pub last_error: Option<String>,
+
+    pub created_at: chrono::NaiveDateTime,
+
+    pub updated_at: chrono::NaiveDateTime,
+}
+
+#[derive(Insertable)]
+#[diesel(table_name = sync_jobs)]
+pub struct NewSyncJob {
+    pub path: String,
+
+    pub syncr_id: String,
+
+    pub known_name: String,
+
+    pub status: String,
+
+    pub attempts: i32,
+
+    pub created_at: chrono::NaiveDateTime,
+
+    pub updated_at: chrono::NaiveDateTime,
+}
+
+impl Default for NewSyncJob {
+    fn default() -> Self {
+        Self {
+            path: String::new(),
+            syncr_id: String::new(),
+            known_name: String::new(),
+            status: STATUS_PENDING.to_owned(),
+            attempts: 0,
+            created_at: chrono::Utc::now().naive_utc(),
+            updated_at: chrono::Utc::now().naive_utc(),
+        }
+    }
+}
+
+impl BaseEntity for SyncJob {
+    type NewEntityType = NewSyncJob;
+    type Table = sync_jobs::table;
+
+    fn find_by_id(id_: i32, conn: &mut SqliteConnection) -> anyhow::Result<Option<Self>> {
+        use crate::schema::sync_jobs::dsl::*;
+
+        Ok(sync_jobs
+            .filter(id.eq(id_))
+            .first::<Self>(conn)
+            .optional()?)
+    }
+
+    fn insert(entity: NewSyncJob, conn: &mut SqliteConnection) -> anyhow::Result<()> {
+        use crate::schema::sync_jobs;
+
+        diesel::insert_into(sync_jobs::table)
+            .values(entity)
+            .execute(conn)?;
+
+        Ok(())
+    }
+
+    fn update<S>(&self, conn: &mut SqliteConnection, changes: S) -> anyhow::Result<()>
+    where
+        S: AsChangeset<Target = <Self as BaseEntity>::Table> + Send,
+        <S as diesel::AsChangeset>::Changeset: QueryFragment<Sqlite> + Send,
+    {
+        conn.transaction(|conn| {
+            diesel::update(&self).set(changes).execute(conn)?;
+
+            Ok(())
+        })
+    }
+}
+
+impl SyncJob {
+    // queues a file, unless it's already waiting in the queue (it gets hashed when it's sent anyway)
+    // in which case the waiting job is touched, so a sync already under way doesn't clear it
+    pub fn enqueue(
+        path_: String,
+        syncr_id_: String,
+        known_name_: String,
+        conn: &mut SqliteConnection,
+    ) -> anyhow::Result<()> {
+        use crate::schema::sync_jobs::dsl::*;
+
+        let touched = diesel::update(
+            sync_jobs
+                .filter(status.eq(STATUS_PENDING))
+                .filter(syncr_id.eq(&syncr_id_))
+                .filter(known_name.eq(&known_name_)),
+        )
+        .set(updated_at.eq(chrono::Utc::now().naive_utc()))
+        .execute(conn)?;
+        if touched > 0 {
+            return Ok(());
+        }
+
+        Self::insert(
+            NewSyncJob {
+                path: path_,
+                syncr_id: syncr_id_,
+                known_name: known_name_,
+                ..Default::default()
+            },
+            conn,
+        )
+    }
+
+    // every job still waiting to go out, oldest first
+    pub fn pending(conn: &mut SqliteConnection) -> anyhow::Result<Vec<Self>> {
+        use crate::schema::sync_jobs::dsl::*;
+
+        Ok(sync_jobs
+            .filter(status.eq(STATUS_PENDING))
+            .order(id.asc())
+            .load::<Self>(conn)?)
+    }
+
+    // drops the job once synced, unless the file changed again since it was loaded
+    // (see enqueue), then it stays queued and goes out once more, returns whether it was dropped
+    pub fn complete(&self, conn: &mut SqliteConnection) -> anyhow::Result<bool> {
+        use crate::schema::sync_jobs::dsl::*;
+
+        let deleted = diesel::delete(
+            sync_jobs
+                .filter(id.eq(self.id))
+                .filter(updated_at.eq(self.updated_at)),
+        )
+        .execute(conn)?;
+
+        Ok(deleted > 0)
+    }
+
+    // counts a failed attempt, gives up on the job for good once it hits max_attempts
+    pub fn record_failure(
+        &self,
+        error: &anyhow::Error,
+        max_attempts: u32,
+        conn: &mut SqliteConnection,
+    ) -> anyhow::Result<()> {
+        use crate::schema::sync_jobs::dsl::*;
+
+        let attempts_ = self.attempts + 1;
+        let status_ = match attempts_ as u32 >= max_attempts {
+            true => STATUS_FAILED,
+            false => STATUS_PENDING,
+        };
+
+        self.update(
+            conn,
+            (
+                attempts.eq(attempts_),
+                status.eq(status_),
+                last_error.eq(Some(error.to_string())),
+                updated_at.eq(chrono::Utc::now().naive_utc()),
+            ),
+        )
+    }
+}
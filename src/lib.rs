@@ -0,0 +1,10 @@
+// the crate is split into a lib so the integration tests under tests/ can drive
+// client and server in-process
+
+pub mod client;
+pub mod common;
+pub mod data;
+pub mod model;
+pub mod schema;
+pub mod server;
+pub mod utils;
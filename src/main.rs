@@ -1,27 +1,53 @@
-mod client;
-mod common;
-mod data;
-mod model;
-mod schema;
-mod server;
-mod utils;
-
-use client::tray::TrayMenu;
-use client::watcher;
-use common::config::SyncConfig;
-use data::DatabaseDriver;
 use log::{LevelFilter, info};
-use server::database::ServerDatabase;
 use std::{env, fs::File};
-use utils::hash::hash_file;
-use utils::log::Logger;
-
-use common::sync::{self};
-use common::{quick_config, sync::apply_delta};
+use syncr_rust::client::tray::TrayMenu;
+use syncr_rust::client::{self, ClientDatabase, watcher};
+use syncr_rust::common::config::{Config, SyncConfig};
+use syncr_rust::data::DatabaseDriver;
+use syncr_rust::data::entities::known_device::KnownDevice;
+use syncr_rust::server::{self, database::ServerDatabase};
+use syncr_rust::utils::hash::hash_file;
+use syncr_rust::utils::log::Logger;
+
+use syncr_rust::common::sync::apply_delta;
+use syncr_rust::common::sync::{self};
+use syncr_rust::model::CompressionTree;
 
 #[tokio::main]
 async fn main() {
-    Logger::init(Some(LevelFilter::Info));
+    let args: Vec<String> = env::args().skip(1).collect();
+    let args: Vec<&str> = args.iter().map(String::as_str).collect();
+
+    match args.as_slice() {
+        // what clients spawn over ssh, stdout belongs to the protocol so logs go to stderr
+        ["serve", "--stdio"] => {
+            Logger::init_stderr(Some(LevelFilter::Info));
+            stdio_main().await;
+
+            // tokio's stdin reader can sit on a blocking read forever, don't wait for it
+            std::process::exit(0);
+        }
+        ["serve"] => {
+            Logger::init(Some(LevelFilter::Info));
+            return server_main().await;
+        }
+        // drops a pinned key so the device (or server) is trusted again with a new one
+        ["forget", name] => {
+            Logger::init(Some(LevelFilter::Info));
+            return forget_main(name).await;
+        }
+        // onboards this device with a code from `syncr admin pair`
+        ["pair", code] => {
+            Logger::init(Some(LevelFilter::Info));
+            let client_cfg = Config::read(Some("./client.toml".into())).unwrap();
+            return client::pair(client_cfg, code).await.unwrap();
+        }
+        ["admin", command @ ..] => {
+            Logger::init(Some(LevelFilter::Info));
+            return server::admin::run(command).await.unwrap();
+        }
+        _ => Logger::init(Some(LevelFilter::Info)),
+    }
 
     info!("Intializing syncr...");
 
@@ -75,16 +101,32 @@ async fn watch_main() {
 }
 
 async fn client_main() {
-    let client_cfg = quick_config!("./client.toml").unwrap();
+    let client_cfg = Config::read(Some("./client.toml".into())).unwrap();
     let mut client = client::Client::connect(Some(client_cfg)).await.unwrap();
+    let queued = client.watch().await.unwrap();
 
-    client.run().await;
+    client.run(queued).await;
 }
 
 async fn server_main() {
-    let server_cfg = quick_config!("./server.toml").unwrap();
-    let mut server = server::Server::bind(Some(server_cfg)).await.unwrap();
-    server.run().await;
+    let server_cfg = Config::read(Some("./server.toml".into())).unwrap();
+    let server = server::Server::bind(Some(server_cfg)).await.unwrap();
+    server.run().await.unwrap();
+}
+
+async fn stdio_main() {
+    let server_cfg = Config::read(Some("./server.toml".into())).unwrap();
+    let server = server::Server::open(Some(server_cfg)).await.unwrap();
+    server.serve_stdio().await.unwrap();
+}
+
+async fn forget_main(name: &str) {
+    let mut database = ClientDatabase::new(None).await.unwrap();
+
+    match KnownDevice::forget(name, &mut database).unwrap() {
+        true => info!("Forgot {}, its next key will be pinned", name),
+        false => info!("{} was never pinned", name),
+    }
 }
 
 async fn sync_main() {
@@ -102,7 +144,7 @@ async fn sync_main() {
     let common_start = Instant::now();
 
     let mut database = ServerDatabase::new(None).await.unwrap();
-    let mut predictor = model::initialize!(&mut database).unwrap();
+    let mut predictor = CompressionTree::load(&mut database).unwrap();
 
     let common_elapsed = common_start.elapsed();
 
@@ -173,7 +215,7 @@ async fn sync_main() {
     predictor
         .tune(new_file_len, predicted_block_size, compression_rate)
         .unwrap();
-    predictor.save(&mut database).await.unwrap();
+    predictor.save(&mut database).unwrap();
 
     //? END RUNNING SERVER SIDE
 
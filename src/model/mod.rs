@@ -17,4 +17,4 @@ pub mod macros {
 
 // exports
 pub(crate) use macros::initialize;
-pub(crate) use predictor::CompressionTree;
+pub use predictor::CompressionTree;
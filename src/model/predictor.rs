@@ -15,6 +15,12 @@ pub struct CompressionTree {
     naive_nodes: NodeList,
 }
 
+impl Default for CompressionTree {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
 impl CompressionTree {
     //? Constructor Methods
     pub fn new() -> Self {
@@ -39,7 +45,7 @@ impl CompressionTree {
     }
 
     //? Persistence
-    pub async fn save(&self, conn: &mut SqliteConnection) -> Result<(), anyhow::Error> {
+    pub fn save(&self, conn: &mut SqliteConnection) -> Result<(), anyhow::Error> {
         use crate::schema::predictor_saves::dsl::*;
 
         match PredictorSave::find_by_id(1, conn)? {
@@ -1,5 +1,37 @@
 // @generated automatically by Diesel CLI.
 
+diesel::table! {
+    handshake_failures (id) {
+        id -> Integer,
+        ip -> Text,
+        reason -> Text,
+        created_at -> Timestamp,
+        updated_at -> Timestamp,
+    }
+}
+
+diesel::table! {
+    known_devices (id) {
+        id -> Integer,
+        name -> Text,
+        public_key -> Binary,
+        created_at -> Timestamp,
+        updated_at -> Timestamp,
+    }
+}
+
+diesel::table! {
+    pairing_codes (id) {
+        id -> Integer,
+        code_id -> Text,
+        psk -> Binary,
+        expires_at -> Timestamp,
+        used_at -> Nullable<Timestamp>,
+        created_at -> Timestamp,
+        updated_at -> Timestamp,
+    }
+}
+
 diesel::table! {
     predictor_saves (id) {
         id -> Integer,
@@ -8,3 +40,28 @@ diesel::table! {
         updated_at -> Timestamp,
     }
 }
+
+diesel::table! {
+    repo_permissions (id) {
+        id -> Integer,
+        device -> Text,
+        syncr_id -> Text,
+        permission -> Text,
+        created_at -> Timestamp,
+        updated_at -> Timestamp,
+    }
+}
+
+diesel::table! {
+    sync_jobs (id) {
+        id -> Integer,
+        path -> Text,
+        syncr_id -> Text,
+        known_name -> Text,
+        status -> Text,
+        attempts -> Integer,
+        last_error -> Nullable<Text>,
+        created_at -> Timestamp,
+        updated_at -> Timestamp,
+    }
+}
@@ -0,0 +1,36 @@
+use std::sync::{Arc, Mutex};
+
+use crate::data::entities::repo_permission::RepoPermission;
+use crate::server::database::ServerDatabase;
+
+// the device on the other end of a connection and where to look up what it may touch,
+// asked again for every transaction so a grant or revoke takes effect right away
+#[derive(Clone)]
+pub struct Authorizer {
+    device: Arc<str>,
+    database: Arc<Mutex<ServerDatabase>>,
+}
+
+impl Authorizer {
+    pub fn new(device: &str, database: Arc<Mutex<ServerDatabase>>) -> Self {
+        Self {
+            device: device.into(),
+            database,
+        }
+    }
+
+    pub fn device(&self) -> &str {
+        &self.device
+    }
+
+    // only rw lets a device push changes, ro and no grant at all don't
+    pub fn can_write(&self, syncr_id: &str) -> Result<bool, anyhow::Error> {
+        let mut database = self
+            .database
+            .lock()
+            .map_err(|e| anyhow::anyhow!("Lock poisoned: {}", e))?;
+
+        Ok(RepoPermission::find(&self.device, syncr_id, &mut database)?
+            .is_some_and(|permission| permission.can_write()))
+    }
+}
@@ -0,0 +1,90 @@
+use chrono::Duration;
+use log::info;
+
+use crate::common::pairing::Code;
+use crate::data::DatabaseDriver;
+use crate::data::entities::BaseEntity;
+use crate::data::entities::handshake_failure::HandshakeFailure;
+use crate::data::entities::known_device::KnownDevice;
+use crate::data::entities::pairing_code::{NewPairingCode, PairingCode};
+use crate::data::entities::repo_permission::RepoPermission;
+use crate::server::database::ServerDatabase;
+
+const USAGE: &str = "usage: syncr admin <command>
+    grant <device> <syncr_id> <ro|rw>   give a device access to a repository
+    revoke <device> <syncr_id>          take it away again
+    permissions                         list every grant
+    forget <device>                     drop a device's pinned key, freeing its name to pair again
+    pair [minutes]                      one-time code to onboard a device with (default 10)
+    failures [count]                    latest failed handshakes and bans (default 20)";
+
+// how long a pairing code is good for when no lifetime is given
+const DEFAULT_PAIRING_MINUTES: i64 = 10;
+// how many failed handshakes are listed when no count is given
+const DEFAULT_FAILURES: i64 = 20;
+
+// `syncr admin ...`, works on the server's database directly so it can run next to a live server
+pub async fn run(args: &[&str]) -> Result<(), anyhow::Error> {
+    let mut database = ServerDatabase::new(None).await?;
+
+    match args {
+        ["grant", device, syncr_id, permission] => {
+            RepoPermission::grant(device, syncr_id, permission, &mut database)?;
+            info!("{} now has {} access to {}", device, permission, syncr_id);
+        }
+        ["revoke", device, syncr_id] => {
+            match RepoPermission::revoke(device, syncr_id, &mut database)? {
+                true => info!("{} no longer has access to {}", device, syncr_id),
+                false => info!("{} had no access to {}", device, syncr_id),
+            }
+        }
+        ["permissions"] => {
+            for grant in RepoPermission::all(&mut database)? {
+                info!("{} {} {}", grant.device, grant.permission, grant.syncr_id);
+            }
+        }
+        ["forget", device] => match KnownDevice::forget(device, &mut database)? {
+            true => info!("Forgot {}, its next key will be pinned", device),
+            false => info!("{} was never pinned", device),
+        },
+        ["pair"] => pair(DEFAULT_PAIRING_MINUTES, &mut database).await?,
+        ["pair", minutes] => pair(minutes.parse()?, &mut database).await?,
+        ["failures"] => failures(DEFAULT_FAILURES, &mut database)?,
+        ["failures", count] => failures(count.parse()?, &mut database)?,
+        _ => anyhow::bail!("{}", USAGE),
+    }
+
+    Ok(())
+}
+
+async fn pair(minutes: i64, database: &mut ServerDatabase) -> Result<(), anyhow::Error> {
+    PairingCode::prune(database)?;
+
+    let code = Code::generate();
+    let expires_at = chrono::Utc::now().naive_utc() + Duration::minutes(minutes);
+
+    PairingCode::insert(
+        NewPairingCode {
+            code_id: code.id.clone(),
+            psk: code.psk().await?.to_vec(),
+            expires_at,
+            ..Default::default()
+        },
+        database,
+    )?;
+
+    info!(
+        "Pairing code {} is good for one use until {} (UTC), run `syncr pair {}` on the new device",
+        code, expires_at, code
+    );
+
+    Ok(())
+}
+
+fn failures(count: i64, database: &mut ServerDatabase) -> Result<(), anyhow::Error> {
+    for failure in HandshakeFailure::latest(count, database)? {
+        info!("{} {} {}", failure.created_at, failure.ip, failure.reason);
+    }
+
+    Ok(())
+}
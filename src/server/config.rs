@@ -1,2 +0,0 @@
-pub(super) static SECRET: &[u8; 32] = b"i don't care for fidget spinners";
-pub(super) static PARAMS: &'static str = "Noise_XXpsk3_25519_ChaChaPoly_BLAKE2s";
@@ -1,124 +1,405 @@
+use std::{
+    collections::{HashMap, HashSet},
+    sync::{Arc, Mutex},
+    time::Duration,
+};
+
+use log::{info, warn};
 use tokio::{
-    io::{self, AsyncReadExt, Interest},
-    task::AbortHandle,
+    io::AsyncRead,
+    sync::{
+        Semaphore,
+        mpsc::{self, error::TrySendError},
+        watch,
+    },
+    task::{AbortHandle, JoinHandle, JoinSet},
+    time::{self, MissedTickBehavior},
 };
 
+use super::transaction::{Outbound, TransactionTask};
 use crate::common::{
-    packetize,
-    packets::{Packets, SizePacket, get_buffer_for_type},
-    stream::SecureStream,
+    heartbeat::Heartbeat,
+    idle::IdleTimeout,
+    packets::{
+        CONNECTION, Capabilities, DynamicPacket, ErrorPacket, FrameLimits, PacketBase, Packets,
+        PingPacket, PongPacket, ProtocolError, TransactionId, read_frame, types::ErrorCode,
+    },
+    stream::{SecureStream, SecureWriter, Streams, Transport},
 };
+use crate::model::CompressionTree;
+use crate::server::access::Authorizer;
+use crate::server::storage::Storage;
+
+// how many decoded frames can wait on the handler before the reader stops reading
+const FRAME_QUEUE_SIZE: usize = 1;
 
+// a frame along with the stream to answer it on, None for the connection's own stream
+// (see read_frame for which errors end up where)
+type Frame = (
+    Result<(TransactionId, Result<Packets, ProtocolError>), ProtocolError>,
+    Option<SecureWriter>,
+);
+
+// knobs every connection is run with, straight from the server config
+#[derive(Debug, Clone)]
+pub struct ConnectionSettings {
+    pub limits: FrameLimits,
+    pub max_transactions: usize,
+    pub heartbeat: Heartbeat,
+    pub deadline: Duration,
+    pub storage: Storage,
+}
+
+// who's on the other end, what they may touch and what they negotiated
+struct Peer {
+    authorizer: Authorizer,
+    capabilities: Capabilities,
+}
+
+// a transaction in flight, as far as the connection is concerned
+struct Running {
+    inbox: mpsc::Sender<Packets>,
+    outbound: Outbound,
+    task: AbortHandle,
+}
+
+#[derive(Clone)]
 pub struct Client {
     handle: AbortHandle,
+    connection: Outbound,
 }
 
 impl Client {
-    pub fn new(handle: AbortHandle) -> Self {
-        Client { handle }
+    // starts serving the connection on its own task, the handle resolves once it's closed
+    //
+    // flipping `shutdown` to true makes the connection refuse new transactions and
+    // close by itself once the ones in flight are done
+    pub fn spawn<T: Transport>(
+        stream: SecureStream<T>,
+        streams: Streams,
+        predictor: Arc<Mutex<CompressionTree>>,
+        settings: ConnectionSettings,
+        authorizer: Authorizer,
+        shutdown: watch::Receiver<bool>,
+    ) -> (Self, JoinHandle<Result<(), anyhow::Error>>) {
+        let capabilities = stream.capabilities();
+        let (reader, writer) = stream.split();
+        let connection = Outbound::new(CONNECTION, writer);
+
+        let handle = tokio::spawn(Self::handle(
+            IdleTimeout::new(reader, settings.heartbeat.timeout),
+            connection.clone(),
+            streams,
+            predictor,
+            settings,
+            Peer {
+                authorizer,
+                capabilities,
+            },
+            shutdown,
+        ));
+
+        let client = Self {
+            handle: handle.abort_handle(),
+            connection,
+        };
+
+        (client, handle)
+    }
+
+    // server initiated, goes out on the CONNECTION id in between whatever transactions are sending
+    pub async fn push<P: DynamicPacket>(&self, packet: &P) -> Result<(), anyhow::Error> {
+        self.connection.send(packet).await
+    }
+
+    // kills the connection and every transaction on it, whatever state they're in
+    pub fn abort(&self) {
+        self.handle.abort();
     }
 
-    pub async fn handle(mut stream: SecureStream) -> Result<(), anyhow::Error> {
-        let mut last_size_packet: Option<SizePacket> = None;
+    async fn ping(connection: Outbound, interval: Duration) {
+        let mut ticker = time::interval(interval);
+        ticker.set_missed_tick_behavior(MissedTickBehavior::Delay);
+        let mut nonce = 0u64;
+
         loop {
-            let ready = stream
-                .ready(Interest::READABLE | Interest::WRITABLE)
-                .await?;
-            if !ready.is_readable() || !ready.is_writable() {
-                continue;
+            ticker.tick().await;
+            nonce += 1;
+
+            if let Err(e) = connection.send(&PingPacket::build(nonce)).await {
+                warn!("Unable to send heartbeat: {e}");
+                break;
             }
+        }
+    }
 
-            let packet = Client::extract_packet(&mut stream, &mut last_size_packet).await?;
+    // frames are read on their own task so the handler can wait on other things
+    // without ever dropping a half-read frame on the floor
+    async fn read<R: AsyncRead + Unpin>(
+        mut reader: R,
+        reply: Option<SecureWriter>,
+        limits: FrameLimits,
+        frames: mpsc::Sender<Frame>,
+    ) {
+        loop {
+            let frame = read_frame(&mut reader, &limits).await;
+            // nothing good comes after a fatal error, no point reading past one
+            let failed = frame.is_err();
 
-            match packet {
-                // dont handle size packets
-                Packets::Size(_) => {
-                    continue;
-                }
-                _ => Client::handle_packet(packet, &mut stream)?,
+            if frames.send((frame, reply.clone())).await.is_err() || failed {
+                break;
             }
         }
     }
 
-    pub async fn read_packet(
-        stream: &mut SecureStream,
-        packet_buffer: &mut Vec<u8>,
-    ) -> Result<(), anyhow::Error> {
-        let expected_size = packet_buffer.capacity();
+    // every stream the client opens carries one transaction, read like the main one
+    // but answered on its own
+    #[cfg(feature = "quic")]
+    async fn accept_streams(
+        quic: quinn::Connection,
+        limits: FrameLimits,
+        frames: mpsc::Sender<Frame>,
+    ) {
+        let mut readers = JoinSet::new();
 
-        let mut bytes_read = 0;
-        while bytes_read < expected_size {
-            let n = stream.read(&mut packet_buffer[bytes_read..]).await?;
-            if n == 0 {
-                return Err(anyhow::anyhow!("Unexpected EOF"));
+        loop {
+            tokio::select! {
+                stream = quic.accept_bi() => match stream {
+                    Ok((send, recv)) => {
+                        let writer: SecureWriter = Arc::new(tokio::sync::Mutex::new(Box::new(send)));
+                        readers.spawn(Self::read(recv, Some(writer), limits, frames.clone()));
+                    }
+                    Err(e) => {
+                        info!("No more streams: {e}");
+                        break;
+                    }
+                },
+                Some(_) = readers.join_next() => {}
             }
-            bytes_read += n;
         }
-        Ok(())
     }
 
-    pub async fn read_header(stream: &mut SecureStream) -> io::Result<[u8; 4]> {
-        let expected_size = 4;
-        let mut buf = [0u8; 4];
-        buf.fill(0);
-
-        let mut bytes_read = 0;
-        while bytes_read < expected_size {
-            let n = stream.read(&mut buf[bytes_read..]).await?;
-            if n == 0 {
-                return Err(io::Error::new(
-                    io::ErrorKind::UnexpectedEof,
-                    "Unexpected EOF",
+    async fn handle<R: AsyncRead + Unpin + Send + 'static>(
+        reader: IdleTimeout<R>,
+        connection: Outbound,
+        streams: Streams,
+        predictor: Arc<Mutex<CompressionTree>>,
+        settings: ConnectionSettings,
+        peer: Peer,
+        mut shutdown: watch::Receiver<bool>,
+    ) -> Result<(), anyhow::Error> {
+        // open transactions, keyed by the id the client picked for them
+        let mut transactions: HashMap<TransactionId, Running> = HashMap::new();
+        // ones we gave up on for falling behind, whatever the client still sends for them is dropped
+        let mut overrun: HashSet<TransactionId> = HashSet::new();
+        // dropping the sets (connection closed or aborted) aborts every task with them
+        let mut running = JoinSet::new();
+        let mut tasks = JoinSet::new();
+        let permits = Arc::new(Semaphore::new(settings.max_transactions));
+
+        // anything that wants the whole connection gone (a transaction past its deadline)
+        let (kill_tx, mut kill_rx) = mpsc::channel::<anyhow::Error>(1);
+
+        let (frames_tx, mut frames_rx) = mpsc::channel(FRAME_QUEUE_SIZE);
+        // once set, no new transactions are taken and we leave as soon as none are left
+        let mut draining = false;
+
+        tasks.spawn(Self::ping(connection.clone(), settings.heartbeat.interval));
+        match streams {
+            Streams::Shared => {}
+            #[cfg(feature = "quic")]
+            Streams::Quic(quic) => {
+                tasks.spawn(Self::accept_streams(
+                    quic,
+                    settings.limits,
+                    frames_tx.clone(),
                 ));
             }
-            bytes_read += n;
         }
+        tasks.spawn(Self::read(reader, None, settings.limits, frames_tx));
 
-        Ok(buf)
-    }
-
-    pub async fn extract_packet(
-        mut stream: &mut SecureStream,
-        last_size_packet: &mut Option<SizePacket>,
-    ) -> Result<Packets, anyhow::Error> {
-        let header_buffer = Client::read_header(&mut stream).await?;
-
-        // info!("Header: {:?}", header_buffer);
-        let packet = match get_buffer_for_type(&header_buffer, last_size_packet) {
-            Ok(mut buffer) => {
-                Client::read_packet(&mut stream, &mut buffer).await?;
-
-                // info!("Packet: {:?}", buffer);
-
-                let packet = packetize(&header_buffer, buffer)?;
-
-                match packet {
-                    Packets::Size(size_packet) => {
-                        // info!(
-                        //     "Received size packet, ready for a packet of size {:?}",
-                        //     size_packet.packet_size
-                        // );
-                        *last_size_packet = Some(size_packet.clone());
-                        return Ok(Packets::Size(size_packet));
+        loop {
+            // only woken up by a whole decrypted frame, the peer going silent (IdleTimeout),
+            // a transaction finishing or the server shutting down
+            let frame = tokio::select! {
+                frame = frames_rx.recv() => match frame {
+                    Some(frame) => frame,
+                    None => anyhow::bail!("Connection reader stopped"),
+                },
+                Some(reason) = kill_rx.recv() => return Err(reason),
+                Some(_) = running.join_next() => {
+                    if draining && running.is_empty() {
+                        return Self::close(&connection).await;
                     }
-                    other_packet => {
-                        if last_size_packet.is_some() {
-                            *last_size_packet = None;
+                    continue;
+                }
+                Ok(()) = shutdown.changed(), if !draining => {
+                    draining = true;
+                    if running.is_empty() {
+                        return Self::close(&connection).await;
+                    }
+                    info!("Draining {} transaction(s) before closing", running.len());
+                    continue;
+                }
+            };
+
+            let (frame, reply) = frame;
+            let (id, packet) = match frame {
+                Ok(frame) => frame,
+                // only a single transaction's stream is gone, the connection is fine
+                Err(e) if reply.is_some() => {
+                    info!("Transaction stream closed: {e}");
+                    continue;
+                }
+                Err(ProtocolError::Io(e)) => return Err(e.into()),
+                Err(e) => {
+                    // we no longer know where the next frame starts, nothing to do but leave
+                    warn!("Closing connection: {e}");
+                    connection.send(&ErrorPacket::from(&e)).await?;
+                    return Err(e.into());
+                }
+            };
+
+            // forget about transactions that already finished
+            transactions.retain(|_, running| !running.inbox.is_closed());
+
+            let packet = match packet {
+                Ok(packet) => packet,
+                // the frame was read to its end, only the transaction it was meant for is lost
+                Err(e) => {
+                    warn!("Bad packet for transaction {}: {e}", id);
+                    let outbound = match transactions.remove(&id) {
+                        Some(running) => {
+                            running.task.abort();
+                            overrun.insert(id);
+                            running.outbound
                         }
+                        None => match &reply {
+                            Some(writer) => Outbound::new(id, writer.clone()),
+                            None => connection.with_id(id),
+                        },
+                    };
+                    outbound.send(&ErrorPacket::from(&e)).await?;
+                    continue;
+                }
+            };
 
-                        other_packet
+            if let Some(running) = transactions.get(&id) {
+                // never wait on a single transaction, every other one is read through here too
+                match running.inbox.try_send(packet) {
+                    Ok(()) => {}
+                    Err(TrySendError::Closed(_)) => {
+                        warn!("Transaction {} finished before its packet arrived", id);
+                    }
+                    Err(TrySendError::Full(_)) => {
+                        warn!("Transaction {} is falling behind, dropping it", id);
+                        running.task.abort();
+                        running
+                            .outbound
+                            .send(&ErrorPacket::build((
+                                ErrorCode::Busy,
+                                "Transaction fell too far behind, try again later".to_owned(),
+                            )))
+                            .await?;
+
+                        transactions.remove(&id);
+                        overrun.insert(id);
                     }
                 }
+                continue;
             }
-            Err(e) => {
-                return Err(e.into());
+
+            let starts = matches!(packet, Packets::SyncInit(_));
+            if overrun.contains(&id) && !starts {
+                continue;
             }
-        };
+            overrun.remove(&id);
+
+            let outbound = match reply {
+                Some(writer) => Outbound::new(id, writer),
+                None => connection.with_id(id),
+            };
+            match packet {
+                Packets::SyncInit(_) if id != CONNECTION && draining => {
+                    outbound
+                        .send(&ErrorPacket::build((
+                            ErrorCode::ShuttingDown,
+                            "Server is shutting down".to_owned(),
+                        )))
+                        .await?;
+                }
+                Packets::SyncInit(_) if id != CONNECTION => {
+                    let Ok(permit) = permits.clone().try_acquire_owned() else {
+                        outbound
+                            .send(&ErrorPacket::build((
+                                ErrorCode::Busy,
+                                format!(
+                                    "At most {} transactions at once",
+                                    settings.max_transactions
+                                ),
+                            )))
+                            .await?;
+                        continue;
+                    };
+
+                    let (task, inbox, inbox_rx) = TransactionTask::new(
+                        id,
+                        outbound.clone(),
+                        predictor.clone(),
+                        settings.limits,
+                        peer.authorizer.clone(),
+                        settings.storage.clone(),
+                        peer.capabilities,
+                    );
+                    // fresh inbox, always has room for the first packet
+                    inbox.try_send(packet)?;
 
-        Ok(packet)
+                    let task = running.spawn(task.run(
+                        inbox_rx,
+                        permit,
+                        settings.deadline,
+                        kill_tx.clone(),
+                    ));
+                    transactions.insert(
+                        id,
+                        Running {
+                            inbox,
+                            outbound,
+                            task,
+                        },
+                    );
+                }
+                Packets::Ping(ping) if id == CONNECTION => {
+                    connection.send(&PongPacket::build(ping.nonce)).await?;
+                }
+                // reading it already reset the IdleTimeout, that's all a PONG is for
+                Packets::Pong(_) if id == CONNECTION => {}
+                Packets::Sanity(sanity) => {
+                    info!(
+                        "Sanity packet: {}",
+                        String::from_utf8_lossy(&sanity.message)
+                    );
+                }
+                other => {
+                    let error = ProtocolError::UnexpectedPacket {
+                        expected: "INIT",
+                        got: *other.get_type(),
+                    };
+                    warn!("Rejecting packet for transaction {}: {}", id, error);
+                    outbound.send(&ErrorPacket::from(&error)).await?;
+                }
+            }
+        }
     }
 
-    pub fn handle_packet(packet: Packets, stream: &mut SecureStream) -> Result<(), anyhow::Error> {
-        todo!()
+    // lets the client know it's on purpose so it can reconnect later instead of retrying at once
+    async fn close(connection: &Outbound) -> Result<(), anyhow::Error> {
+        info!("Connection drained, closing");
+        connection
+            .send(&ErrorPacket::build((
+                ErrorCode::ShuttingDown,
+                "Server is shutting down".to_owned(),
+            )))
+            .await
     }
 }
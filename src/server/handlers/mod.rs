@@ -1,2 +1,4 @@
 mod client;
-pub(crate) use client::Client;
+mod transaction;
+
+pub(crate) use client::{Client, ConnectionSettings};
@@ -0,0 +1,790 @@
+use std::{
+    fs::File,
+    io::Write,
+    path::{Path, PathBuf},
+    sync::{Arc, Mutex},
+    time::Duration,
+};
+
+use anyhow::Context;
+use log::{info, warn};
+use tempfile::NamedTempFile;
+use tokio::{
+    sync::{OwnedSemaphorePermit, mpsc},
+    time,
+};
+
+use crate::common::{
+    compression,
+    packets::{
+        CONNECTION, Capabilities, DynamicPacket, ErrorPacket, FrameLimits, PacketBase, Packets,
+        ProtocolError, SyncAcknowledgePacket, SyncDeltaEndPacket, SyncForcePacket, SyncInitPacket,
+        SyncResumePacket, TransactionId, send_frame,
+        types::{ErrorCode, PeerError, sync::ack::AckData},
+    },
+    stream::SecureWriter,
+    sync::{self, DeltaApplier},
+};
+use crate::model::CompressionTree;
+use crate::server::access::Authorizer;
+use crate::server::storage::{Partial, Storage};
+use crate::utils::hash::hash_file;
+
+// how many packets can queue up for a single transaction before the reader waits on it
+const INBOX_SIZE: usize = 8;
+
+// state of a single SYNC transaction
+//
+// Idle --INIT--> AwaitingDelta --SDLB--> ReceivingDelta --SDLC...--> ReceivingDelta --SDLE--> Idle
+// ReceivingDelta --SDLE (result doesn't match)--> AwaitingForce
+// AwaitingDelta --FRCE (client would rather send all of it)--> AwaitingForce
+// Idle --INIT--> AwaitingForce --FRCE...--> AwaitingForce --FRCE (last chunk)--> Idle
+// Idle --INIT (same hash)--> Idle
+#[derive(Debug, Default)]
+pub enum Transaction {
+    #[default]
+    Idle,
+    // we have the file, sent out a signature and are waiting for the delta
+    AwaitingDelta {
+        syncr_id: String,
+        known_name: String,
+        path: PathBuf,
+        block_size: u32,
+        signature_len: usize,
+    },
+    // delta is streaming in and being applied to a temp file as it arrives
+    ReceivingDelta {
+        syncr_id: String,
+        known_name: String,
+        path: PathBuf,
+        block_size: u32,
+        signature_len: usize,
+        new_file_size: u64,
+        applier: Box<DeltaApplier>,
+        hasher: Box<blake3::Hasher>,
+        received: u64,
+    },
+    // we don't have the file at all, only a FRCE can fix that
+    // received is how much of it is already sitting in the partial file
+    AwaitingForce {
+        syncr_id: String,
+        known_name: String,
+        path: PathBuf,
+        hash: blake3::Hash,
+        partial: Partial,
+        received: u64,
+    },
+}
+
+impl Transaction {
+    pub fn name(&self) -> &'static str {
+        match self {
+            Transaction::Idle => "Idle",
+            Transaction::AwaitingDelta { .. } => "AwaitingDelta",
+            Transaction::ReceivingDelta { .. } => "ReceivingDelta",
+            Transaction::AwaitingForce { .. } => "AwaitingForce",
+        }
+    }
+
+    // what the next packet is allowed to be, used when rejecting anything else
+    pub fn expects(&self) -> &'static str {
+        match self {
+            Transaction::Idle => "INIT",
+            Transaction::AwaitingDelta { .. } => "SDLB or FRCE",
+            Transaction::ReceivingDelta { .. } => "SDLC or SDLE",
+            Transaction::AwaitingForce { .. } => "FRCE",
+        }
+    }
+}
+
+// what the predictor needs to know about a finished delta
+struct DeltaStats {
+    block_size: u32,
+    signature_len: usize,
+    new_file_size: u64,
+    received: u64,
+}
+
+// writes frames to the connection, tagged with the transaction they belong to
+#[derive(Clone)]
+pub struct Outbound {
+    id: TransactionId,
+    writer: SecureWriter,
+}
+
+impl Outbound {
+    pub fn new(id: TransactionId, writer: SecureWriter) -> Self {
+        Self { id, writer }
+    }
+
+    // same writer, tagged for another transaction
+    pub fn with_id(&self, id: TransactionId) -> Self {
+        Self::new(id, self.writer.clone())
+    }
+
+    // same writer, but for frames about the connection as a whole
+    pub fn connection(&self) -> Self {
+        self.with_id(CONNECTION)
+    }
+
+    pub async fn send<P: DynamicPacket>(&self, packet: &P) -> Result<(), anyhow::Error> {
+        send_frame(&self.writer, self.id, packet).await
+    }
+}
+
+// runs a single transaction on its own task, fed packets by the connection reader
+pub struct TransactionTask {
+    id: TransactionId,
+    state: Transaction,
+    outbound: Outbound,
+    predictor: Arc<Mutex<CompressionTree>>,
+    limits: FrameLimits,
+    authorizer: Authorizer,
+    storage: Storage,
+    // what the connection negotiated, RESUMABLE decides whether old partials are picked up
+    capabilities: Capabilities,
+}
+
+impl TransactionTask {
+    pub fn new(
+        id: TransactionId,
+        outbound: Outbound,
+        predictor: Arc<Mutex<CompressionTree>>,
+        limits: FrameLimits,
+        authorizer: Authorizer,
+        storage: Storage,
+        capabilities: Capabilities,
+    ) -> (Self, mpsc::Sender<Packets>, mpsc::Receiver<Packets>) {
+        let (inbox_tx, inbox_rx) = mpsc::channel(INBOX_SIZE);
+
+        let task = Self {
+            id,
+            state: Transaction::default(),
+            outbound,
+            predictor,
+            limits,
+            authorizer,
+            storage,
+            capabilities,
+        };
+
+        (task, inbox_tx, inbox_rx)
+    }
+
+    // the permit is held for as long as the transaction runs, a transaction that
+    // outlives the deadline takes the whole connection down with it through `kill`
+    pub async fn run(
+        mut self,
+        mut inbox: mpsc::Receiver<Packets>,
+        _permit: OwnedSemaphorePermit,
+        deadline: Duration,
+        kill: mpsc::Sender<anyhow::Error>,
+    ) {
+        if time::timeout(deadline, self.process(&mut inbox))
+            .await
+            .is_ok()
+        {
+            return;
+        }
+
+        let error = ErrorPacket::build((
+            ErrorCode::Timeout,
+            format!(
+                "Transaction {} did not finish within {:?}",
+                self.id, deadline
+            ),
+        ));
+        warn!("Closing connection: {}", error.message);
+
+        if let Err(e) = self.outbound.connection().send(&error).await {
+            warn!("Unable to report timeout for transaction {}: {e}", self.id);
+        }
+        let _ = kill.send(anyhow::anyhow!(error.message)).await;
+    }
+
+    async fn process(&mut self, inbox: &mut mpsc::Receiver<Packets>) {
+        while let Some(packet) = inbox.recv().await {
+            if let Err(e) = self.handle_packet(packet).await {
+                self.abort(&e).await;
+                break;
+            }
+
+            // back to Idle means the transaction is done
+            if matches!(self.state, Transaction::Idle) {
+                break;
+            }
+        }
+    }
+
+    // tells the client why its transaction died
+    async fn abort(&mut self, error: &anyhow::Error) {
+        warn!(
+            "Aborting transaction {} in state {}: {}",
+            self.id,
+            self.state.name(),
+            error
+        );
+        self.state = Transaction::Idle;
+
+        if let Err(e) = self.outbound.send(&ErrorPacket::from(error)).await {
+            warn!("Unable to report error for transaction {}: {e}", self.id);
+        }
+    }
+
+    async fn handle_packet(&mut self, packet: Packets) -> Result<(), anyhow::Error> {
+        // take the current state out, every branch below decides what the next one is
+        let state = std::mem::take(&mut self.state);
+
+        match (state, packet) {
+            (Transaction::Idle, Packets::SyncInit(init)) => {
+                self.state = self.handle_init(init).await?;
+            }
+            (
+                Transaction::AwaitingDelta {
+                    syncr_id,
+                    known_name,
+                    path,
+                    block_size,
+                    signature_len,
+                },
+                Packets::SyncDeltaBegin(begin),
+            ) => {
+                if begin.new_file_size > self.limits.max_file_size {
+                    anyhow::bail!(PeerError::new(
+                        ErrorCode::BadSize,
+                        format!(
+                            "{} bytes exceeds the maximum file transfer size of {} bytes",
+                            begin.new_file_size, self.limits.max_file_size
+                        ),
+                    ));
+                }
+
+                info!("Receiving delta for {}/{}", syncr_id, known_name);
+
+                self.state = Transaction::ReceivingDelta {
+                    applier: Box::new(DeltaApplier::new(&path, begin.new_file_size)?),
+                    syncr_id,
+                    known_name,
+                    path,
+                    block_size,
+                    signature_len,
+                    new_file_size: begin.new_file_size,
+                    hasher: Box::new(blake3::Hasher::new()),
+                    received: 0,
+                };
+            }
+            (
+                Transaction::AwaitingDelta {
+                    syncr_id,
+                    known_name,
+                    path,
+                    ..
+                },
+                Packets::SyncForce(force),
+            ) => {
+                info!(
+                    "{}/{} is sent whole instead of as a delta",
+                    syncr_id, known_name
+                );
+
+                self.state = self
+                    .accept_force(syncr_id, known_name, path, force.file_hash)
+                    .await?;
+                if matches!(self.state, Transaction::AwaitingForce { .. }) {
+                    // the chunk itself goes through the usual FRCE handling
+                    Box::pin(self.handle_packet(Packets::SyncForce(force))).await?;
+                }
+            }
+            (
+                Transaction::ReceivingDelta {
+                    syncr_id,
+                    known_name,
+                    path,
+                    block_size,
+                    signature_len,
+                    new_file_size,
+                    mut applier,
+                    mut hasher,
+                    received,
+                },
+                Packets::SyncDeltaChunk(chunk),
+            ) => {
+                // what went over the wire, so zstd's savings count toward the rate
+                let wire_len = chunk.data.len() as u64;
+                let max_payload = self.max_payload();
+                // unzstd'ing and writing out a whole chunk is blocking work
+                (applier, hasher) = tokio::task::spawn_blocking(move || {
+                    let data = match chunk.compressed {
+                        true => compression::decompress(&chunk.data, max_payload)?,
+                        false => chunk.data,
+                    };
+                    hasher.update(&data);
+                    applier.push(&data)?;
+
+                    Ok::<_, anyhow::Error>((applier, hasher))
+                })
+                .await??;
+
+                self.state = Transaction::ReceivingDelta {
+                    syncr_id,
+                    known_name,
+                    path,
+                    block_size,
+                    signature_len,
+                    new_file_size,
+                    applier,
+                    hasher,
+                    received: received + wire_len,
+                };
+            }
+            (
+                Transaction::ReceivingDelta {
+                    syncr_id,
+                    known_name,
+                    path,
+                    block_size,
+                    signature_len,
+                    new_file_size,
+                    applier,
+                    hasher,
+                    received,
+                },
+                Packets::SyncDeltaEnd(end),
+            ) => {
+                info!("Applying delta to {}/{}", syncr_id, known_name);
+
+                let file_hash = end.file_hash;
+                let rebuilt =
+                    tokio::task::spawn_blocking(move || Self::rebuild(&end, *applier, *hasher))
+                        .await?;
+                let temp_file = match rebuilt {
+                    Ok(temp_file) => temp_file,
+                    Err(e) => {
+                        // the original was never touched, have the client send all of it instead
+                        warn!(
+                            "Delta for {}/{} did not check out, falling back to FRCE: {e}",
+                            syncr_id, known_name
+                        );
+                        self.state = self
+                            .request_force(syncr_id, known_name, path, file_hash)
+                            .await?;
+                        return Ok(());
+                    }
+                };
+
+                temp_file
+                    .persist(&path)
+                    .context("Failed to persist temporary file")?;
+                self.tune(DeltaStats {
+                    block_size,
+                    signature_len,
+                    new_file_size,
+                    received,
+                })?;
+
+                // nothing left to sync, the file is up to date
+                self.outbound
+                    .send(&SyncAcknowledgePacket::build((false, None)))
+                    .await?;
+            }
+            (
+                Transaction::AwaitingForce {
+                    syncr_id,
+                    known_name,
+                    path,
+                    hash,
+                    partial,
+                    received,
+                },
+                Packets::SyncForce(force),
+            ) => {
+                let (limits, file_size) = (self.limits, force.file_size);
+                let chunk_partial = partial.path().to_owned();
+                let received = tokio::task::spawn_blocking(move || {
+                    Self::handle_force_chunk(limits, &force, &hash, &chunk_partial, received)
+                })
+                .await??;
+
+                if received < file_size {
+                    self.state = Transaction::AwaitingForce {
+                        syncr_id,
+                        known_name,
+                        path,
+                        hash,
+                        partial,
+                        received,
+                    };
+                    return Ok(());
+                }
+
+                info!("Force synced {}/{}", syncr_id, known_name);
+                tokio::task::spawn_blocking(move || {
+                    Self::finish_force(partial.path(), &path, &hash)
+                })
+                .await??;
+
+                self.outbound
+                    .send(&SyncAcknowledgePacket::build((false, None)))
+                    .await?;
+            }
+            (state, packet) => {
+                return Err(ProtocolError::UnexpectedPacket {
+                    expected: state.expects(),
+                    got: *packet.get_type(),
+                }
+                .into());
+            }
+        }
+
+        Ok(())
+    }
+
+    async fn handle_init(&self, init: SyncInitPacket) -> Result<Transaction, anyhow::Error> {
+        // every sync writes to the repository, nothing happens without rw on it
+        if !self.authorizer.can_write(&init.syncr_id)? {
+            warn!(
+                "{} tried to sync {} without write access",
+                self.authorizer.device(),
+                init.syncr_id
+            );
+
+            self.outbound
+                .send(&ErrorPacket::build((
+                    ErrorCode::Forbidden,
+                    format!("No write access to {}", init.syncr_id),
+                )))
+                .await?;
+
+            return Ok(Transaction::Idle);
+        }
+
+        let path = match self.storage.resolve(&init.syncr_id, &init.known_name) {
+            Ok(path) => path,
+            Err(e) => {
+                warn!(
+                    "{} sent a name we won't store: {e}",
+                    self.authorizer.device()
+                );
+
+                self.outbound
+                    .send(&ErrorPacket::build((
+                        ErrorCode::Forbidden,
+                        format!("{}/{} is not a valid name", init.syncr_id, init.known_name),
+                    )))
+                    .await?;
+
+                return Ok(Transaction::Idle);
+            }
+        };
+
+        if !path.is_file() {
+            info!("{}/{} not found", init.syncr_id, init.known_name);
+            return self
+                .request_force(init.syncr_id, init.known_name, path, init.hash)
+                .await;
+        }
+
+        if Self::hash(path.clone()).await? == init.hash {
+            info!("{}/{} already in sync", init.syncr_id, init.known_name);
+
+            self.outbound
+                .send(&SyncAcknowledgePacket::build((false, None)))
+                .await?;
+
+            return Ok(Transaction::Idle);
+        }
+
+        let file = File::options().read(true).open(&path)?;
+        let file_len = file.metadata()?.len() as usize;
+        // only held for the prediction, every other transaction needs it too
+        let block_size = self
+            .predictor
+            .lock()
+            .map_err(|e| anyhow::anyhow!("Lock poisoned: {}", e))?
+            .wonderful_predict(file_len);
+        let signature =
+            tokio::task::spawn_blocking(move || sync::signature_with(&file, block_size)).await??;
+        let signature_len = signature.len();
+
+        self.outbound
+            .send(&SyncAcknowledgePacket::build((
+                true,
+                Some(AckData {
+                    signature,
+                    block_size,
+                }),
+            )))
+            .await?;
+
+        Ok(Transaction::AwaitingDelta {
+            syncr_id: init.syncr_id,
+            known_name: init.known_name,
+            path,
+            block_size,
+            signature_len,
+        })
+    }
+
+    // finishes applying the delta and makes sure the result is byte for byte the client's
+    // file, anything off and the temp file is dropped (and deleted) before it replaces anything
+    fn rebuild(
+        end: &SyncDeltaEndPacket,
+        applier: DeltaApplier,
+        hasher: blake3::Hasher,
+    ) -> Result<NamedTempFile, anyhow::Error> {
+        if hasher.finalize() != end.hash {
+            anyhow::bail!("Delta stream hash mismatch, chunks were lost or corrupted");
+        }
+
+        let temp_file = applier.finish()?;
+
+        // hashed from disk, what gets persisted is exactly what we checked
+        if hash_file(temp_file.path())? != end.file_hash {
+            anyhow::bail!("Rebuilt file does not match the client's");
+        }
+
+        Ok(temp_file)
+    }
+
+    // feed the result back into the model, a failed tune is not worth failing the sync over
+    fn tune(&self, stats: DeltaStats) -> Result<(), anyhow::Error> {
+        let compression_rate: f32 =
+            stats.new_file_size as f32 / (stats.received + 8 + stats.signature_len as u64) as f32;
+
+        let mut predictor = self
+            .predictor
+            .lock()
+            .map_err(|e| anyhow::anyhow!("Lock poisoned: {}", e))?;
+        if let Err(e) = predictor.tune(
+            stats.new_file_size as usize,
+            stats.block_size,
+            compression_rate,
+        ) {
+            warn!("Failed to tune predictor: {e}");
+        }
+
+        Ok(())
+    }
+
+    // we need the whole file with this hash, pick up wherever an earlier FRCE for it left off
+    async fn request_force(
+        &self,
+        syncr_id: String,
+        known_name: String,
+        path: PathBuf,
+        hash: blake3::Hash,
+    ) -> Result<Transaction, anyhow::Error> {
+        // empty files can't be mmapped (so can't be FRCE'd), nothing to send anyway
+        if hash == blake3::hash(&[]) {
+            create_parent(&path)?;
+            File::create(&path)?;
+
+            self.outbound
+                .send(&SyncAcknowledgePacket::build((false, None)))
+                .await?;
+
+            return Ok(Transaction::Idle);
+        }
+
+        let Some(partial) = self.storage.claim_partial(&syncr_id, &known_name, &hash)? else {
+            warn!(
+                "{}/{} is already being uploaded by another transaction",
+                syncr_id, known_name
+            );
+
+            self.outbound
+                .send(&ErrorPacket::build((
+                    ErrorCode::Busy,
+                    format!("{}/{} is already being uploaded", syncr_id, known_name),
+                )))
+                .await?;
+
+            return Ok(Transaction::Idle);
+        };
+        // a client that can't resume sends the whole file no matter what, so whatever an
+        // earlier upload left behind would only end up in front of it
+        if !self.capabilities.contains(Capabilities::RESUMABLE) && partial.path().exists() {
+            std::fs::remove_file(partial.path())?;
+        }
+        let received = std::fs::metadata(partial.path()).map_or(0, |metadata| metadata.len());
+
+        // the last upload got every byte across but died before we could move it into place
+        if received > 0 && Self::hash(partial.path().to_owned()).await? == hash {
+            tokio::task::spawn_blocking(move || Self::finish_force(partial.path(), &path, &hash))
+                .await??;
+
+            self.outbound
+                .send(&SyncAcknowledgePacket::build((false, None)))
+                .await?;
+
+            return Ok(Transaction::Idle);
+        }
+
+        info!(
+            "Requesting FRCE for {}/{} from byte {}",
+            syncr_id, known_name, received
+        );
+        self.outbound
+            .send(&SyncResumePacket::build(received))
+            .await?;
+
+        Ok(Transaction::AwaitingForce {
+            syncr_id,
+            known_name,
+            path,
+            hash,
+            partial,
+            received,
+        })
+    }
+
+    // a FRCE the client started on its own, without us asking for it (and saying where
+    // to resume from), so it starts from byte 0 whatever an earlier upload left behind
+    async fn accept_force(
+        &self,
+        syncr_id: String,
+        known_name: String,
+        path: PathBuf,
+        hash: blake3::Hash,
+    ) -> Result<Transaction, anyhow::Error> {
+        let Some(partial) = self.storage.claim_partial(&syncr_id, &known_name, &hash)? else {
+            self.outbound
+                .send(&ErrorPacket::build((
+                    ErrorCode::Busy,
+                    format!("{}/{} is already being uploaded", syncr_id, known_name),
+                )))
+                .await?;
+
+            return Ok(Transaction::Idle);
+        };
+        if partial.path().exists() {
+            std::fs::remove_file(partial.path())?;
+        }
+
+        Ok(Transaction::AwaitingForce {
+            syncr_id,
+            known_name,
+            path,
+            hash,
+            partial,
+            received: 0,
+        })
+    }
+
+    fn max_payload(&self) -> usize {
+        max_payload(self.limits)
+    }
+
+    // blake3 of a whole file is a lot of reading, kept off the runtime's threads
+    async fn hash(path: PathBuf) -> Result<blake3::Hash, anyhow::Error> {
+        Ok(tokio::task::spawn_blocking(move || hash_file(path)).await??)
+    }
+
+    // verifies a single chunk and appends it to the partial upload, returns how much we have now
+    fn handle_force_chunk(
+        limits: FrameLimits,
+        force: &SyncForcePacket,
+        hash: &blake3::Hash,
+        partial: &Path,
+        received: u64,
+    ) -> Result<u64, anyhow::Error> {
+        // the file changed on the client since this upload started
+        if force.file_hash != *hash {
+            anyhow::bail!(PeerError::new(
+                ErrorCode::UnexpectedPacket,
+                "FRCE chunk belongs to a different version of the file".to_owned(),
+            ));
+        }
+        if force.file_size > limits.max_file_size {
+            anyhow::bail!(PeerError::new(
+                ErrorCode::BadSize,
+                format!(
+                    "{} bytes exceeds the maximum file transfer size of {} bytes",
+                    force.file_size, limits.max_file_size
+                ),
+            ));
+        }
+        if force.offset != received {
+            anyhow::bail!(PeerError::new(
+                ErrorCode::UnexpectedPacket,
+                format!(
+                    "FRCE chunk starts at byte {} but we have {} bytes",
+                    force.offset, received
+                ),
+            ));
+        }
+
+        let decompressed;
+        let data: &[u8] = match force.compressed {
+            true => {
+                let left = usize::try_from(force.file_size - received).unwrap_or(usize::MAX);
+                decompressed = compression::decompress(&force.mmap, left.min(max_payload(limits)))?;
+                &decompressed
+            }
+            false => &force.mmap,
+        };
+
+        let end = received + data.len() as u64;
+        if end > force.file_size {
+            anyhow::bail!(PeerError::new(
+                ErrorCode::BadSize,
+                format!(
+                    "FRCE chunk ends at byte {} of a {} byte file",
+                    end, force.file_size
+                ),
+            ));
+        }
+
+        // a bad chunk never touches the partial file, so a retry resumes right before it
+        if blake3::hash(data) != force.hash {
+            anyhow::bail!(PeerError::new(
+                ErrorCode::Decode,
+                format!("FRCE chunk at byte {} failed verification", force.offset),
+            ));
+        }
+
+        let mut file = File::options().append(true).create(true).open(partial)?;
+        file.write_all(data)?;
+        // only count what actually made it to disk, that's what we resume from
+        file.sync_data()?;
+
+        Ok(end)
+    }
+
+    // checks the finished upload against the hash it was requested for and moves it into place
+    fn finish_force(partial: &Path, path: &Path, hash: &blake3::Hash) -> Result<(), anyhow::Error> {
+        if hash_file(partial)? != *hash {
+            // every chunk checked out but the whole doesn't, no point resuming from this
+            std::fs::remove_file(partial)?;
+            anyhow::bail!(PeerError::new(
+                ErrorCode::Decode,
+                "Uploaded file does not match the hash it was announced with".to_owned(),
+            ));
+        }
+
+        create_parent(path)?;
+        if std::fs::rename(partial, path).is_err() {
+            // partials and storage may not share a filesystem, copy next to the target instead
+            let mut temp_file = NamedTempFile::new_in(path.parent().unwrap_or(Path::new(".")))?;
+            std::io::copy(&mut File::open(partial)?, &mut temp_file)?;
+            temp_file.persist(path)?;
+            std::fs::remove_file(partial)?;
+        }
+
+        Ok(())
+    }
+}
+
+// a decompressed payload sits in memory just like a frame does, so it gets the same cap
+fn max_payload(limits: FrameLimits) -> usize {
+    usize::try_from(limits.max_frame_size).unwrap_or(usize::MAX)
+}
+
+fn create_parent(path: &Path) -> Result<(), anyhow::Error> {
+    let parent = path
+        .parent()
+        .ok_or(anyhow::anyhow!("Unable to get parent dir"))?;
+    std::fs::create_dir_all(parent)?;
+
+    Ok(())
+}
@@ -1,32 +1,106 @@
 use std::collections::HashMap;
-use std::net::SocketAddr;
-use std::sync::{Arc, Mutex};
+use std::net::{IpAddr, SocketAddr};
+use std::sync::{Arc, Mutex, MutexGuard, RwLock};
+use std::time::Duration;
 
-use super::handlers::Client;
+use super::handlers::{Client, ConnectionSettings};
 use crate::common::config::Config;
+use crate::common::heartbeat::Heartbeat;
+use crate::common::identity;
+use crate::common::packets::{
+    CONNECTION, Capabilities, ErrorPacket, FrameLimits, HelloPacket, MIN_MAX_FRAME_SIZE,
+    PacketBase, PairingPacket, RotatePacket, send_frame, types::ErrorCode,
+};
+use crate::common::pairing::Greeting;
+use crate::common::psk::{KeyId, Psk};
+#[cfg(feature = "quic")]
+use crate::common::quic;
 use crate::common::quick_config;
-use crate::common::stream::SecureStream;
+use crate::common::stream::{Credentials, Keypair, Role, SecureStream, Streams, Transport};
 use crate::data::DatabaseDriver;
+use crate::data::entities::handshake_failure::HandshakeFailure;
+use crate::data::entities::known_device::{self, KnownDevice, Trust};
+use crate::data::entities::pairing_code::PairingCode;
 use crate::model::{self, CompressionTree};
+use crate::server::access::Authorizer;
 use crate::server::database::ServerDatabase;
-use futures::FutureExt;
-use log::info;
-use tokio::net::TcpListener;
+use crate::server::secrets::Secrets;
+use crate::server::storage::Storage;
+use crate::server::throttle::{Permit, Throttle};
+use futures::stream::FuturesUnordered;
+use futures::{FutureExt, StreamExt};
+use log::{info, warn};
+use tokio::io::{self, AsyncReadExt};
+use tokio::net::{TcpListener, TcpStream};
+use tokio::sync::watch;
+use tokio::task::JoinSet;
+use tokio::time;
+
+// how long a paired device gets to hang up before we do
+const PAIRING_LINGER: Duration = Duration::from_secs(5);
+// how long failed handshakes stay in the audit table
+const AUDIT_RETENTION: chrono::Duration = chrono::Duration::days(30);
+
+// a secured connection fresh out of the handshake, plus a label for whoever is on the other end
+// and its slot in the throttle
+type Incoming = (
+    SecureStream<Box<dyn Transport>>,
+    Streams,
+    String,
+    Option<Permit>,
+);
+
+// a connection that was just accepted, nothing has been said on it yet
+enum Arrival {
+    Tcp(TcpStream, SocketAddr),
+    #[cfg(feature = "quic")]
+    Quic(quinn::Incoming),
+}
+
+impl Arrival {
+    fn ip(&self) -> IpAddr {
+        match self {
+            Arrival::Tcp(_, addr) => addr.ip(),
+            #[cfg(feature = "quic")]
+            Arrival::Quic(incoming) => incoming.remote_address().ip(),
+        }
+    }
+
+    // tcp just gets closed, QUIC is told so the client doesn't keep retrying
+    fn refuse(self) {
+        match self {
+            Arrival::Tcp(..) => {}
+            #[cfg(feature = "quic")]
+            Arrival::Quic(incoming) => incoming.refuse(),
+        }
+    }
+}
+
 pub struct Server {
-    listener: TcpListener,
+    // None when the one connection comes from somewhere else (serve --stdio)
+    listener: Option<TcpListener>,
+    #[cfg(feature = "quic")]
+    endpoint: Option<quinn::Endpoint>,
     config: Config,
-    database: ServerDatabase,
-    clients: Arc<Mutex<HashMap<SocketAddr, Client>>>,
+    identity: Arc<Keypair>,
+    // swapped out whole on SIGHUP, never held across an await
+    secrets: RwLock<Secrets>,
+    // shared with the connections, diesel is sync so it's only ever locked briefly
+    database: Arc<Mutex<ServerDatabase>>,
+    // keyed by a label for whoever is on the other end (the socket address for tcp)
+    clients: Arc<Mutex<HashMap<String, Client>>>,
     predictor: Arc<Mutex<CompressionTree>>,
+    settings: ConnectionSettings,
+    // who may handshake at all, and how long they get to
+    throttle: Throttle,
+    handshake_timeout: Duration,
+    grace_period: Duration,
 }
 
 impl Server {
     pub async fn bind(config: Option<Config>) -> Result<Self, anyhow::Error> {
-        let config = match config {
-            Some(c) => c,
-            None => quick_config!()?,
-        };
-        let server_ref = config.as_server()?; // implicitly assert we're in server mode too!
+        let mut server = Self::open(config).await?;
+        let server_ref = server.config.as_server()?;
 
         let listener = TcpListener::bind((
             server_ref.server().ip, //
@@ -39,37 +113,402 @@ impl Server {
             server_ref.server().port
         );
 
+        if server_ref.server().quic {
+            #[cfg(feature = "quic")]
+            {
+                // the port tcp ended up on, so port 0 still gets both on the same one
+                let addr = listener.local_addr()?;
+                server.endpoint = Some(quic::listen(addr)?);
+                info!("Also listening for QUIC on {}", addr);
+            }
+
+            #[cfg(not(feature = "quic"))]
+            anyhow::bail!("quic is enabled but this build has no QUIC support");
+        }
+
+        server.listener = Some(listener);
+
+        Ok(server)
+    }
+
+    // where the tcp listener ended up, handy when binding port 0
+    pub fn local_addr(&self) -> Option<SocketAddr> {
+        self.listener.as_ref()?.local_addr().ok()
+    }
+
+    // everything but the listener
+    pub async fn open(config: Option<Config>) -> Result<Self, anyhow::Error> {
+        let config = match config {
+            Some(c) => c,
+            None => quick_config!()?,
+        };
+        let server_ref = config.as_server()?; // implicitly assert we're in server mode too!
+
+        if server_ref.server().max_frame_size < MIN_MAX_FRAME_SIZE {
+            anyhow::bail!(
+                "max-frame-size has to be at least {} bytes, that's how much of a file clients send at once",
+                MIN_MAX_FRAME_SIZE
+            );
+        }
+
+        let settings = ConnectionSettings {
+            limits: FrameLimits {
+                max_frame_size: server_ref.server().max_frame_size,
+                max_file_size: server_ref.server().max_file_size,
+            },
+            max_transactions: server_ref.server().max_concurrent_transactions,
+            heartbeat: Heartbeat::from_config(&config),
+            deadline: Duration::from_secs(server_ref.server().transaction_deadline),
+            storage: Storage::from_config(server_ref.server())?,
+        };
+        let grace_period = Duration::from_secs(server_ref.server().shutdown_grace_period);
+        let throttle = Throttle::from_config(server_ref.server());
+        let handshake_timeout = Duration::from_secs(server_ref.server().handshake_timeout);
+
+        let identity = Arc::new(identity::load_or_create(None)?);
+        let secrets = Secrets::load(&config).await?;
+
         let mut database = ServerDatabase::new(None).await?;
 
         info!("Connected to database");
 
+        HandshakeFailure::prune(AUDIT_RETENTION, &mut database)?;
+
         let predictor = model::initialize!(&mut database)?;
 
         info!("Initialized predictor model");
 
         Ok(Self {
-            database,
-            listener,
+            database: Arc::new(Mutex::new(database)),
+            listener: None,
+            #[cfg(feature = "quic")]
+            endpoint: None,
             config,
+            identity,
+            secrets: RwLock::new(secrets),
             predictor: Arc::new(Mutex::new(predictor)),
             clients: Arc::new(Mutex::new(HashMap::new())),
+            settings,
+            throttle,
+            handshake_timeout,
+            grace_period,
+        })
+    }
+
+    // goes out before we know who's on the other end, so it names none of our repositories,
+    // which of them a device may touch is only decided per transaction once it's verified
+    fn hello(&self) -> HelloPacket {
+        HelloPacket::build((
+            self.config.device_name.clone(),
+            Vec::new(),
+            Capabilities::local(),
+        ))
+    }
+
+    // our identity along with the secret the client says it holds, as long as we still accept it
+    fn credentials(&self, key_id: &KeyId) -> Result<Credentials, anyhow::Error> {
+        let psk = self.read_secrets()?.find(key_id).ok_or(anyhow::anyhow!(
+            "Handshake failed, the peer holds none of the accepted secrets"
+        ))?;
+
+        Ok(Credentials {
+            keypair: self.identity.clone(),
+            psk,
         })
     }
 
-    async fn accept(&mut self) -> Result<(SecureStream, SocketAddr), anyhow::Error> {
-        let (stream, addr) = self.listener.accept().await?;
+    fn read_secrets(&self) -> Result<std::sync::RwLockReadGuard<'_, Secrets>, anyhow::Error> {
+        self.secrets
+            .read()
+            .map_err(|e| anyhow::anyhow!("Lock poisoned: {}", e))
+    }
+
+    // secures a freshly opened transport, whatever it happens to be, `binding` is whatever
+    // the transport itself negotiated (QUIC's TLS) for the handshake to be tied to
+    //
+    // None when the connection was only there to pair a device and is done already
+    async fn handshake<T: Transport>(
+        &self,
+        mut transport: T,
+        binding: &[u8],
+    ) -> Result<Option<SecureStream<T>>, anyhow::Error> {
+        let greeting = Greeting::read(&mut transport).await?;
+        let prologue = greeting.prologue(binding);
+
+        let key_id = match greeting {
+            Greeting::Session { key_id } => key_id,
+            Greeting::Pair { code_id } => {
+                self.pair(transport, &code_id, &prologue).await?;
+                return Ok(None);
+            }
+        };
+
+        let stream = SecureStream::with_prologue(
+            transport,
+            Role::Responder,
+            &self.credentials(&key_id)?,
+            self.hello(),
+            &prologue,
+        )
+        .await?;
+
+        self.verify_device(&stream)?;
+
+        Ok(Some(stream))
+    }
+
+    // a device proving it has a one-time code, its key gets pinned and it's handed
+    // the deployment's secret so it can connect like everyone else from now on
+    async fn pair<T: Transport>(
+        &self,
+        transport: T,
+        code_id: &str,
+        prologue: &[u8],
+    ) -> Result<(), anyhow::Error> {
+        // only burned once the handshake shows the peer actually knows it
+        let psk = PairingCode::usable(code_id, &mut *self.lock_database()?)?.ok_or(
+            anyhow::anyhow!("Pairing code {} is unknown, used or expired", code_id),
+        )?;
+        let psk: Psk = psk
+            .try_into()
+            .map_err(|_| anyhow::anyhow!("Pairing code {} has a malformed psk", code_id))?;
+
+        let stream = SecureStream::with_prologue(
+            transport,
+            Role::Responder,
+            &Credentials {
+                keypair: self.identity.clone(),
+                psk,
+            },
+            self.hello(),
+            prologue,
+        )
+        .await?;
+
+        let device = stream.remote().device.clone();
+        if device.is_empty() {
+            anyhow::bail!("Peer did not say which device it is");
+        }
+        if !PairingCode::redeem(code_id, &mut *self.lock_database()?)? {
+            anyhow::bail!("Pairing code {} got used or expired mid-handshake", code_id);
+        }
+        let key = *stream.remote_key();
+        let claimed = KnownDevice::claim(&device, &key, &mut *self.lock_database()?)?;
+
+        let (mut reader, writer) = stream.split();
+
+        // a code vouches for a new device, not for taking over the name (and grants) of
+        // one we already know
+        if !claimed {
+            let refusal = ErrorPacket::build((
+                ErrorCode::NameTaken,
+                format!(
+                    "{} is already paired with another key, an admin has to `syncr admin forget {}` first",
+                    device, device
+                ),
+            ));
+            send_frame(&writer, CONNECTION, &refusal).await?;
+
+            anyhow::bail!(
+                "Refused to pair {}, the name is pinned to another key",
+                device
+            );
+        }
+
+        let pairing = PairingPacket::build((
+            self.read_secrets()?.current().to_owned(),
+            self.config.salt.clone(),
+        ));
+        send_frame(&writer, CONNECTION, &pairing).await?;
+
+        // let the device hang up first, so the secret isn't cut off on its way out
+        let _ = time::timeout(PAIRING_LINGER, reader.read(&mut [0u8; 1])).await;
+
+        info!(
+            "Paired {} with key {}",
+            device,
+            known_device::fingerprint(&key)
+        );
+
+        Ok(())
+    }
+
+    fn lock_database(&self) -> Result<MutexGuard<'_, ServerDatabase>, anyhow::Error> {
+        self.database
+            .lock()
+            .map_err(|e| anyhow::anyhow!("Lock poisoned: {}", e))
+    }
+
+    // pins the device's key the first time it shows up, refuses it if the key changes later on
+    fn verify_device<T: Transport>(&self, stream: &SecureStream<T>) -> Result<(), anyhow::Error> {
+        let device = &stream.remote().device;
+        if device.is_empty() {
+            anyhow::bail!("Peer did not say which device it is");
+        }
+
+        match KnownDevice::verify(device, stream.remote_key(), &mut *self.lock_database()?) {
+            Ok(Trust::New) => info!(
+                "New device {} with key {}, pinned",
+                device,
+                known_device::fingerprint(stream.remote_key())
+            ),
+            Ok(Trust::Known) => {}
+            Err(e) => {
+                warn!("{e}");
+                anyhow::bail!("Refused {}, its key does not match the pinned one", device);
+            }
+        }
+
+        Ok(())
+    }
+
+    // the next connection on any listener, not handshaken yet
+    async fn accept(&self, listener: &TcpListener) -> Result<Arrival, anyhow::Error> {
+        tokio::select! {
+            accepted = listener.accept() => {
+                let (stream, addr) = accepted?;
+                Ok(Arrival::Tcp(stream, addr))
+            }
+            incoming = self.accept_quic() => incoming,
+        }
+    }
+
+    // never resolves if we're not listening for QUIC
+    #[cfg(feature = "quic")]
+    async fn accept_quic(&self) -> Result<Arrival, anyhow::Error> {
+        let Some(endpoint) = &self.endpoint else {
+            return std::future::pending().await;
+        };
+
+        let incoming = endpoint
+            .accept()
+            .await
+            .ok_or(anyhow::anyhow!("QUIC endpoint closed"))?;
+
+        Ok(Arrival::Quic(incoming))
+    }
+
+    #[cfg(not(feature = "quic"))]
+    async fn accept_quic(&self) -> Result<Arrival, anyhow::Error> {
+        std::future::pending().await
+    }
+
+    // whoever is turned away here never costs us a handshake
+    fn admit(&self, arrival: Arrival) -> Option<(Arrival, Permit)> {
+        let ip = arrival.ip();
+
+        match self.throttle.admit(ip) {
+            Ok(permit) => Some((arrival, permit)),
+            Err(refusal) => {
+                info!("Turned {} away: {}", ip, refusal);
+                arrival.refuse();
+
+                None
+            }
+        }
+    }
+
+    // everything it takes to go from an admitted connection to a secured one, bounded by
+    // handshake-timeout, whatever goes wrong on the way ends up in the audit table
+    //
+    // None when the connection was only there to pair a device and is done already
+    async fn establish(
+        &self,
+        arrival: Arrival,
+        permit: Permit,
+    ) -> Result<Option<Incoming>, anyhow::Error> {
+        let ip = arrival.ip();
+
+        let secured = match time::timeout(self.handshake_timeout, self.secure(arrival)).await {
+            Ok(secured) => secured,
+            Err(_) => Err(anyhow::anyhow!(
+                "Handshake timed out after {:?}",
+                self.handshake_timeout
+            )),
+        };
+
+        match secured {
+            Ok(Some((stream, streams, peer))) => Ok(Some((stream, streams, peer, Some(permit)))),
+            Ok(None) => Ok(None),
+            Err(e) => {
+                self.audit(ip, e.to_string());
+                if let Some(ban) = self.throttle.failed(ip) {
+                    let reason = format!(
+                        "too many failed handshakes in a minute, banned for {}s",
+                        ban.as_secs()
+                    );
+                    warn!("Banned {}: {}", ip, reason);
+                    self.audit(ip, reason);
+                }
+                Err(e)
+            }
+        }
+    }
+
+    async fn secure(
+        &self,
+        arrival: Arrival,
+    ) -> Result<Option<(SecureStream<Box<dyn Transport>>, Streams, String)>, anyhow::Error> {
+        match arrival {
+            Arrival::Tcp(stream, addr) => {
+                let Some(stream) = self
+                    .handshake(Box::new(stream) as Box<dyn Transport>, &[])
+                    .await?
+                else {
+                    return Ok(None);
+                };
+
+                let peer = format!("{}@{}", stream.remote().device, addr);
+
+                Ok(Some((stream, Streams::Shared, peer)))
+            }
+            #[cfg(feature = "quic")]
+            Arrival::Quic(incoming) => {
+                let connection = incoming.await?;
+                let control = quic::accept(&connection).await?;
+                let binding = quic::binding(&connection)?;
+                let Some(stream) = self
+                    .handshake(Box::new(control) as Box<dyn Transport>, &binding)
+                    .await?
+                else {
+                    return Ok(None);
+                };
+                let peer = format!(
+                    "{}@quic://{}",
+                    stream.remote().device,
+                    connection.remote_address()
+                );
+
+                Ok(Some((stream, Streams::Quic(connection), peer)))
+            }
+        }
+    }
+
+    // a failed handshake (or a ban) goes on record, failing to record it fails nothing else.
+    // written on the side, accepting shouldn't wait on a database every connection shares
+    fn audit(&self, ip: IpAddr, reason: String) {
+        let database = self.database.clone();
 
-        let stream = SecureStream::new(stream, &self.config.secret).await?;
+        tokio::task::spawn_blocking(move || {
+            let recorded = database
+                .lock()
+                .map_err(|e| anyhow::anyhow!("Lock poisoned: {}", e))
+                .and_then(|mut database| {
+                    HandshakeFailure::record(&ip.to_string(), &reason, &mut database)
+                });
 
-        Ok((stream, addr))
+            if let Err(e) = recorded {
+                warn!("Unable to record the failed handshake from {}: {e}", ip);
+            }
+        });
     }
 
-    fn insert_client(&self, addr: SocketAddr, client: Client) -> Result<(), anyhow::Error> {
+    fn insert_client(&self, peer: String, client: Client) -> Result<(), anyhow::Error> {
         if self
             .clients
             .lock()
             .map_err(|e| anyhow::anyhow!("Lock poisoned: {}", e))?
-            .insert(addr, client)
+            .insert(peer, client)
             .is_some()
         {
             return Err(anyhow::anyhow!("Client already exists"));
@@ -78,48 +517,335 @@ impl Server {
         Ok(())
     }
 
-    pub async fn run(&mut self) {
+    // serves until SIGTERM/SIGINT, then drains the open connections and shuts down cleanly
+    pub async fn run(self) -> Result<(), anyhow::Error> {
+        let Some(listener) = self.listener.as_ref() else {
+            anyhow::bail!("Server isn't listening anywhere, bind it first");
+        };
+
+        let (shutdown_tx, shutdown_rx) = watch::channel(false);
+        // every connection's cleanup, so shutdown can wait on them
+        let mut connections = JoinSet::new();
+
+        // admitted connections that are still handshaking, many at once so a slow (or
+        // malicious) one doesn't hold up everyone behind it
+        let mut handshakes = FuturesUnordered::new();
+
+        let signal = shutdown_signal();
+        tokio::pin!(signal);
+        let mut hangup = Hangup::new()?;
+
         loop {
-            match self.accept().await {
-                Ok((stream, addr)) => {
-                    info!("New connection from {}", addr.to_string());
-
-                    let handle = tokio::spawn(async move { Client::handle(stream).await });
-
-                    match self.insert_client(addr, Client::new(handle.abort_handle())) {
-                        Ok(_) => info!("Client inserted"),
-                        Err(e) => {
-                            log::error!("Client insertion failed: {e}");
-                            handle.abort();
-                            info!("{:?}", self.clients.lock().unwrap().len());
-                            info!("Client aborted");
-                            continue;
+            let established = tokio::select! {
+                arrival = self.accept(listener) => {
+                    match arrival.map(|arrival| self.admit(arrival)) {
+                        Ok(Some((arrival, permit))) => {
+                            handshakes.push(self.establish(arrival, permit))
                         }
+                        Ok(None) => {}
+                        Err(e) => log::error!("Accepting a connection failed: {e}"),
                     }
+                    continue;
+                }
+                Some(established) = handshakes.next() => established,
+                result = &mut signal => {
+                    result?;
+                    break;
+                }
+                _ = hangup.recv() => {
+                    if let Err(e) = self.reload_secrets().await {
+                        log::error!("Reloading secrets failed, keeping the old ones: {e}");
+                    }
+                    continue;
+                }
+                // reap whatever disconnected in the meantime
+                Some(_) = connections.join_next() => continue,
+            };
 
-                    let clients = self.clients.clone();
-                    let addr = Arc::new(addr);
-                    let cleanup = handle.then(|result| async move {
-                        // move clone of clients in
+            match established {
+                Ok(Some((stream, streams, peer, permit))) => self.attach(
+                    stream,
+                    streams,
+                    peer,
+                    permit,
+                    shutdown_rx.clone(),
+                    &mut connections,
+                ),
+                // a device got paired, nothing to serve
+                Ok(None) => {}
+                Err(e) => {
+                    log::error!("Connection failed: {e}");
+                }
+            }
+        }
 
-                        // todo remove unwraps
-                        clients.lock().unwrap().remove(&addr).unwrap();
+        // whatever is still mid-handshake never gets served
+        drop(handshakes);
 
-                        match result {
-                            Ok(_) => info!("Client disconnected"),
-                            Err(e) => log::error!("Client disconnected: {e}"),
-                        }
+        self.shutdown(shutdown_tx, connections).await
+    }
+
+    // serves exactly one connection over stdin/stdout, this is what a client
+    // tunneling through ssh ends up talking to
+    pub async fn serve_stdio(self) -> Result<(), anyhow::Error> {
+        self.serve(io::join(io::stdin(), io::stdout()), "stdio")
+            .await
+    }
+
+    // serves exactly one connection over whatever transport it's handed, `origin` is
+    // only there to tell it apart in the logs
+    pub async fn serve<T: Transport>(
+        self,
+        transport: T,
+        origin: &str,
+    ) -> Result<(), anyhow::Error> {
+        let (shutdown_tx, shutdown_rx) = watch::channel(false);
+        let mut connections = JoinSet::new();
+
+        // pairing over the tunnel is all there is to it, connections stays empty
+        if let Some(stream) = self.handshake(transport, &[]).await? {
+            let peer = format!("{}@{}", stream.remote().device, origin);
+            // only the one connection, there's nothing to throttle
+            self.attach(
+                stream,
+                Streams::Shared,
+                peer,
+                None,
+                shutdown_rx,
+                &mut connections,
+            );
+        }
+
+        tokio::select! {
+            // the client hung up (or the ssh session died), nothing left to serve
+            _ = connections.join_next() => {}
+            result = shutdown_signal() => result?,
+        }
+
+        self.shutdown(shutdown_tx, connections).await
+    }
+
+    // serves a secured connection on its own task, tracked until it closes
+    fn attach<T: Transport>(
+        &self,
+        stream: SecureStream<T>,
+        streams: Streams,
+        peer: String,
+        permit: Option<Permit>,
+        shutdown: watch::Receiver<bool>,
+        connections: &mut JoinSet<()>,
+    ) {
+        info!("New connection from {}", peer);
 
-                        // print all clients
-                        info!("Clients: {:?}", clients.lock().unwrap().len());
-                    });
+        // it got in with a secret that's on its way out
+        let outdated = self
+            .read_secrets()
+            .is_ok_and(|secrets| !secrets.is_current(stream.key_id()));
 
-                    tokio::spawn(cleanup);
+        let authorizer = Authorizer::new(&stream.remote().device, self.database.clone());
+        let (client, handle) = Client::spawn(
+            stream,
+            streams,
+            self.predictor.clone(),
+            self.settings.clone(),
+            authorizer,
+            shutdown,
+        );
+
+        if outdated {
+            info!("{} is on an older secret, handing it the current one", peer);
+            self.announce_secret(vec![client.clone()]);
+        }
+
+        match self.insert_client(peer.clone(), client) {
+            Ok(_) => info!("Client inserted"),
+            Err(e) => {
+                log::error!("Client insertion failed: {e}");
+                handle.abort();
+                return;
+            }
+        }
+
+        let clients = self.clients.clone();
+        let cleanup = handle.then(|result| async move {
+            // move clone of clients in, the permit goes along to free its slot once we're done
+            let _permit = permit;
+
+            match clients.lock() {
+                Ok(mut clients) => {
+                    if clients.remove(&peer).is_none() {
+                        log::warn!("{} was already gone from the connected clients", peer);
+                    }
                 }
-                Err(e) => {
-                    log::error!("Connection failed: {e}");
+                Err(e) => log::error!("Couldn't drop {} from the connected clients: {}", peer, e),
+            }
+
+            match result {
+                Ok(Ok(())) => info!("{} disconnected", peer),
+                Ok(Err(e)) => info!("{} disconnected: {e}", peer),
+                Err(e) => log::error!("{} disconnected: {e}", peer),
+            }
+        });
+
+        connections.spawn(cleanup);
+    }
+
+    // rereads the config's secrets, so rotating one doesn't take a restart, and moves
+    // every connected client over to the current one
+    async fn reload_secrets(&self) -> Result<(), anyhow::Error> {
+        let config = Config::read(Some(self.config.path().to_path_buf()))?;
+        let secrets = Secrets::load(&config).await?;
+        let rotated = secrets.current() != self.read_secrets()?.current();
+
+        *self
+            .secrets
+            .write()
+            .map_err(|e| anyhow::anyhow!("Lock poisoned: {}", e))? = secrets;
+        info!("Reloaded secrets");
+
+        if rotated {
+            let clients = self
+                .clients
+                .lock()
+                .map_err(|e| anyhow::anyhow!("Lock poisoned: {}", e))?
+                .values()
+                .cloned()
+                .collect();
+            self.announce_secret(clients);
+        }
+
+        Ok(())
+    }
+
+    // pushes the current secret in the background, a slow client shouldn't hold up the rest
+    fn announce_secret(&self, clients: Vec<Client>) {
+        let secret = match self.read_secrets() {
+            Ok(secrets) => secrets.current().to_owned(),
+            Err(e) => {
+                log::error!("{e}");
+                return;
+            }
+        };
+
+        tokio::spawn(async move {
+            let packet = RotatePacket::build(secret);
+            for client in clients {
+                if let Err(e) = client.push(&packet).await {
+                    warn!("Unable to hand a client the current secret: {e}");
                 }
             }
+        });
+    }
+
+    async fn shutdown(
+        self,
+        shutdown_tx: watch::Sender<bool>,
+        mut connections: JoinSet<()>,
+    ) -> Result<(), anyhow::Error> {
+        // nobody new gets in from here on
+        drop(self.listener);
+        #[cfg(feature = "quic")]
+        if let Some(endpoint) = &self.endpoint {
+            endpoint.set_server_config(None);
+        }
+
+        info!(
+            "Shutting down, giving {} connection(s) {:?} to finish",
+            connections.len(),
+            self.grace_period
+        );
+        shutdown_tx.send_replace(true);
+
+        let drained = time::timeout(self.grace_period, async {
+            while connections.join_next().await.is_some() {}
+        })
+        .await;
+
+        if drained.is_err() {
+            {
+                let clients = self
+                    .clients
+                    .lock()
+                    .map_err(|e| anyhow::anyhow!("Lock poisoned: {}", e))?;
+                warn!(
+                    "Grace period over, aborting {} connection(s)",
+                    clients.len()
+                );
+                for client in clients.values() {
+                    client.abort();
+                }
+            }
+
+            // the cleanups still have to run so the aborted tasks are fully gone
+            while connections.join_next().await.is_some() {}
         }
+
+        // tell whoever is still on QUIC we're gone, instead of leaving them to time out
+        #[cfg(feature = "quic")]
+        if let Some(endpoint) = &self.endpoint {
+            endpoint.close(0u32.into(), b"shutting down");
+            endpoint.wait_idle().await;
+        }
+
+        // no transaction is left to touch it, so this is the final state of the model,
+        // saved through the locks since whatever else holds on to the database (a stray
+        // handle, an admin command) must not cost us the model
+        let predictor = self
+            .predictor
+            .lock()
+            .map_err(|e| anyhow::anyhow!("Lock poisoned: {}", e))?;
+        let mut database = self
+            .database
+            .lock()
+            .map_err(|e| anyhow::anyhow!("Lock poisoned: {}", e))?;
+        predictor.save(&mut database)?;
+        info!("Saved predictor model");
+
+        Ok(())
+    }
+}
+
+// resolves on the first SIGINT (ctrl-c) or, on unix, SIGTERM
+async fn shutdown_signal() -> Result<(), anyhow::Error> {
+    #[cfg(unix)]
+    {
+        use tokio::signal::unix::{SignalKind, signal};
+
+        let mut terminate = signal(SignalKind::terminate())?;
+        tokio::select! {
+            result = tokio::signal::ctrl_c() => result?,
+            _ = terminate.recv() => {}
+        }
+    }
+
+    #[cfg(not(unix))]
+    tokio::signal::ctrl_c().await?;
+
+    info!("Received shutdown signal");
+
+    Ok(())
+}
+
+// SIGHUP on unix, which is when the secrets get reloaded, never resolves anywhere else
+struct Hangup {
+    #[cfg(unix)]
+    signal: tokio::signal::unix::Signal,
+}
+
+impl Hangup {
+    fn new() -> Result<Self, anyhow::Error> {
+        Ok(Self {
+            #[cfg(unix)]
+            signal: tokio::signal::unix::signal(tokio::signal::unix::SignalKind::hangup())?,
+        })
+    }
+
+    async fn recv(&mut self) {
+        #[cfg(unix)]
+        if self.signal.recv().await.is_some() {
+            return;
+        }
+
+        std::future::pending().await
     }
 }
@@ -1,6 +1,11 @@
-mod config;
+mod access;
+pub mod admin;
 pub mod database; // todo remove pub
 pub mod handlers;
 mod init;
+mod sandbox;
+mod secrets;
+mod storage;
+mod throttle;
 
-pub(crate) use init::Server;
+pub use init::Server;
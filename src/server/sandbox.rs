@@ -0,0 +1,171 @@
+use std::path::{Component, Path, PathBuf};
+
+// every name a client sends us ends up as a path on our disk, this is the one place
+// that turns them into paths, nothing below a root may ever resolve outside of it
+
+// a client supplied name as a plain relative path, `.` dropped and anything that could
+// point somewhere else (absolute paths, `..`, drive prefixes, NUL bytes) refused
+pub fn normalize(name: &str) -> Result<PathBuf, anyhow::Error> {
+    if name.contains('\0') {
+        anyhow::bail!("{:?} contains a NUL byte", name);
+    }
+
+    let mut normalized = PathBuf::new();
+    for component in Path::new(name).components() {
+        match component {
+            Component::Normal(part) => normalized.push(part),
+            Component::CurDir => {}
+            Component::ParentDir => anyhow::bail!("{:?} climbs out with ..", name),
+            Component::RootDir | Component::Prefix(_) => {
+                anyhow::bail!("{:?} is not a relative path", name)
+            }
+        }
+    }
+
+    if normalized.as_os_str().is_empty() {
+        anyhow::bail!("{:?} does not name anything", name);
+    }
+
+    Ok(normalized)
+}
+
+// like normalize, but it has to be exactly one directory entry (a syncr_id, say)
+pub fn single(name: &str) -> Result<PathBuf, anyhow::Error> {
+    let normalized = normalize(name)?;
+    if normalized.components().count() != 1 || normalized.as_os_str() != name {
+        anyhow::bail!("{:?} has to be a single plain name", name);
+    }
+
+    Ok(normalized)
+}
+
+// where `name` lives below `root`, refused if it (or anything on the way there) is a
+// symlink leading out of root, the root itself is created if it isn't there yet
+pub fn resolve(root: &Path, name: &str) -> Result<PathBuf, anyhow::Error> {
+    let relative = normalize(name)?;
+
+    std::fs::create_dir_all(root)?;
+    let root = root.canonicalize()?;
+    let path = root.join(&relative);
+
+    // a symlink at the very end would have reads follow it wherever it points
+    if path
+        .symlink_metadata()
+        .is_ok_and(|metadata| metadata.file_type().is_symlink())
+    {
+        anyhow::bail!("{:?} is a symlink", name);
+    }
+
+    // whatever part of the path already exists has to stay inside root once every
+    // symlink in it is followed, the rest gets created by us as plain directories
+    let existing = path
+        .ancestors()
+        .find(|ancestor| ancestor.exists())
+        .unwrap_or(&root)
+        .canonicalize()?;
+    if !existing.starts_with(&root) {
+        anyhow::bail!("{:?} leads outside of {:?}", name, root);
+    }
+
+    Ok(path)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn normalizes_plain_names() {
+        assert_eq!(normalize("a/b.txt").unwrap(), Path::new("a/b.txt"));
+        assert_eq!(normalize("./a/./b.txt").unwrap(), Path::new("a/b.txt"));
+        assert_eq!(normalize("a//b.txt").unwrap(), Path::new("a/b.txt"));
+    }
+
+    #[test]
+    fn refuses_parent_dirs() {
+        for name in ["..", "../etc/passwd", "a/../../b", "a/.."] {
+            assert!(normalize(name).is_err(), "{name}");
+        }
+    }
+
+    #[test]
+    fn refuses_absolute_paths() {
+        for name in ["/etc/passwd", "/", "//server/share"] {
+            assert!(normalize(name).is_err(), "{name}");
+        }
+    }
+
+    #[test]
+    fn refuses_empty_names() {
+        for name in ["", ".", "./", "./././", "a\0b"] {
+            assert!(normalize(name).is_err(), "{name:?}");
+        }
+    }
+
+    #[test]
+    fn single_is_one_plain_entry() {
+        assert_eq!(single("repo").unwrap(), Path::new("repo"));
+        for name in ["a/b", "./repo", "repo/", "..", ""] {
+            assert!(single(name).is_err(), "{name:?}");
+        }
+    }
+
+    #[test]
+    fn resolves_below_root() {
+        let root = tempfile::tempdir().unwrap();
+        let canonical = root.path().canonicalize().unwrap();
+
+        assert_eq!(
+            resolve(root.path(), "a/b.txt").unwrap(),
+            canonical.join("a/b.txt")
+        );
+        assert!(resolve(root.path(), "../b.txt").is_err());
+        assert!(resolve(root.path(), "/tmp/b.txt").is_err());
+        assert!(resolve(root.path(), ".").is_err());
+    }
+
+    #[test]
+    fn creates_a_missing_root() {
+        let parent = tempfile::tempdir().unwrap();
+        let root = parent.path().join("not/there/yet");
+
+        resolve(&root, "file").unwrap();
+        assert!(root.is_dir());
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn refuses_symlinks_out_of_root() {
+        use std::os::unix::fs::symlink;
+
+        let root = tempfile::tempdir().unwrap();
+        let outside = tempfile::tempdir().unwrap();
+        std::fs::write(outside.path().join("secret"), b"nope").unwrap();
+
+        // a directory on the way there leading out
+        symlink(outside.path(), root.path().join("escape")).unwrap();
+        assert!(resolve(root.path(), "escape/secret").is_err());
+        assert!(resolve(root.path(), "escape/new/file").is_err());
+
+        // the file itself leading out
+        symlink(outside.path().join("secret"), root.path().join("leaf")).unwrap();
+        assert!(resolve(root.path(), "leaf").is_err());
+
+        // even one that stays inside, the leaf is never followed
+        std::fs::write(root.path().join("real"), b"fine").unwrap();
+        symlink(root.path().join("real"), root.path().join("alias")).unwrap();
+        assert!(resolve(root.path(), "alias").is_err());
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn follows_symlinks_that_stay_inside() {
+        use std::os::unix::fs::symlink;
+
+        let root = tempfile::tempdir().unwrap();
+        std::fs::create_dir(root.path().join("real")).unwrap();
+        symlink(root.path().join("real"), root.path().join("linked")).unwrap();
+
+        assert!(resolve(root.path(), "linked/file").is_ok());
+    }
+}
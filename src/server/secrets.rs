@@ -0,0 +1,130 @@
+use chrono::{DateTime, Utc};
+use log::info;
+
+use crate::common::config::Config;
+use crate::common::config::structure::AcceptedSecret;
+use crate::common::psk::{self, KeyId, Psk};
+
+// every secret the server lets clients in with, the current one first
+pub struct Secrets {
+    // what pairing hands out and clients still on an older secret are moved over to
+    current: String,
+    // never empty, the current one's psk never expires
+    psks: Vec<(KeyId, Psk, Option<DateTime<Utc>>)>,
+}
+
+impl Secrets {
+    // derives every configured secret up front, argon2 is far too slow to run per handshake
+    pub async fn load(config: &Config) -> Result<Self, anyhow::Error> {
+        let server_ref = config.as_server()?;
+        let current = psk::from_config(config).await?;
+        let mut psks = vec![(psk::key_id(&current), current, None)];
+
+        for accepted in &server_ref.server().accepted_secrets {
+            let expires = expiry(accepted)?;
+            if expires.is_some_and(|expires| expires <= Utc::now()) {
+                info!("Skipping an accepted secret that expired at {:?}", expires);
+                continue;
+            }
+
+            // an old secret lets anyone in just as well as the current one
+            psk::check(
+                "an accepted secret",
+                &accepted.secret,
+                &config.salt,
+                config.insecure_secret,
+            )?;
+
+            let (secret, salt) = (accepted.secret.clone(), config.salt.clone());
+            let psk = tokio::task::spawn_blocking(move || psk::derive(&secret, &salt)).await??;
+            psks.push((psk::key_id(&psk), psk, expires));
+        }
+
+        Ok(Self {
+            current: config.secret.clone(),
+            psks,
+        })
+    }
+
+    pub fn current(&self) -> &str {
+        &self.current
+    }
+
+    // the psk a client's greeting asked for, as long as it hasn't expired
+    pub fn find(&self, key_id: &KeyId) -> Option<Psk> {
+        let now = Utc::now();
+
+        self.psks
+            .iter()
+            .find(|(id, _, expires)| id == key_id && expires.is_none_or(|expires| expires > now))
+            .map(|(_, psk, _)| *psk)
+    }
+
+    // whether a client got in with the current secret rather than one on its way out
+    pub fn is_current(&self, key_id: &KeyId) -> bool {
+        self.psks.first().is_some_and(|(id, _, _)| id == key_id)
+    }
+}
+
+fn expiry(accepted: &AcceptedSecret) -> Result<Option<DateTime<Utc>>, anyhow::Error> {
+    let Some(expires) = &accepted.expires else {
+        return Ok(None);
+    };
+
+    let expires = DateTime::parse_from_rfc3339(&expires.to_string()).map_err(|_| {
+        anyhow::anyhow!(
+            "expires = {} has to be a full date and time with an offset, e.g. 2026-11-01T00:00:00Z",
+            expires
+        )
+    })?;
+
+    Ok(Some(expires.with_timezone(&Utc)))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn config(accepted: &str) -> (tempfile::TempDir, Config) {
+        let dir = tempfile::TempDir::new().unwrap();
+        let path = dir.path().join("server.toml");
+        std::fs::write(
+            &path,
+            format!(
+                r#"[config]
+secret = "a passphrase nobody guesses"
+salt = "a-salt-for-the-tests"
+auto-wonder = false
+mode = "server"
+
+[config.server]
+ip = "127.0.0.1"
+port = 0
+
+[[config.server.accepted-secrets]]
+secret = "{accepted}"
+"#
+            ),
+        )
+        .unwrap();
+
+        let config = Config::read(Some(path)).unwrap();
+        (dir, config)
+    }
+
+    #[tokio::test]
+    async fn refuses_an_insecure_accepted_secret() {
+        let (_dir, config) = config("change-me");
+
+        let error = Secrets::load(&config).await.err().unwrap();
+        assert!(error.to_string().contains("accepted secret"), "{error}");
+    }
+
+    #[tokio::test]
+    async fn loads_a_proper_accepted_secret() {
+        let (_dir, config) = config("the previous passphrase");
+
+        let secrets = Secrets::load(&config).await.unwrap();
+        assert_eq!(secrets.psks.len(), 2);
+    }
+}
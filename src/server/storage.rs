@@ -0,0 +1,124 @@
+use std::collections::{HashMap, HashSet};
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex, PoisonError};
+
+use crate::common::config::structure::ServerConfig;
+use crate::server::sandbox;
+
+// ~/.syncr, everything below lives in here
+fn syncr_dir() -> Result<PathBuf, anyhow::Error> {
+    dirs::home_dir()
+        .map(|dir| dir.join(".syncr"))
+        .ok_or(anyhow::anyhow!(
+            "Unable to extract storage path, default home directory not found."
+        ))
+}
+
+// where every repository's files are kept, shared by all connections
+#[derive(Debug, Clone)]
+pub struct Storage {
+    // repositories without a root of their own get a directory named after them in here
+    default_root: Arc<Path>,
+    // syncr_id -> root, from the config's storage table
+    roots: Arc<HashMap<String, PathBuf>>,
+    // partial uploads some transaction is appending to right now
+    uploads: Arc<Mutex<HashSet<PathBuf>>>,
+}
+
+impl Storage {
+    pub fn from_config(config: &ServerConfig) -> Result<Self, anyhow::Error> {
+        let default_root = match &config.storage_root {
+            Some(root) => root.clone(),
+            None => syncr_dir()?.join("storage"),
+        };
+
+        // a configured root is named by its syncr_id too, it has to be a sane one
+        for syncr_id in config.storage.keys() {
+            sandbox::single(syncr_id)?;
+        }
+
+        Ok(Self {
+            default_root: default_root.into(),
+            roots: Arc::new(config.storage.clone()),
+            uploads: Arc::new(Mutex::new(HashSet::new())),
+        })
+    }
+
+    // the directory a repository's files live in
+    // <storage root>/<syncr_id> unless the config gives it its own
+    fn root(&self, syncr_id: &str) -> Result<PathBuf, anyhow::Error> {
+        match self.roots.get(syncr_id) {
+            Some(root) => Ok(root.clone()),
+            None => Ok(self.default_root.join(sandbox::single(syncr_id)?)),
+        }
+    }
+
+    // resolves where the server keeps its copy of a synced file, refusing any
+    // known_name that would land outside of the repository's root
+    pub fn resolve(&self, syncr_id: &str, known_name: &str) -> Result<PathBuf, anyhow::Error> {
+        sandbox::resolve(&self.root(syncr_id)?, known_name)
+    }
+
+    // the partial upload of this version of the file, None while another transaction
+    // (on this connection or any other) is already appending to it
+    pub fn claim_partial(
+        &self,
+        syncr_id: &str,
+        known_name: &str,
+        hash: &blake3::Hash,
+    ) -> Result<Option<Partial>, anyhow::Error> {
+        let path = partial(syncr_id, known_name, hash)?;
+
+        let mut uploads = self.uploads.lock().unwrap_or_else(PoisonError::into_inner);
+        if !uploads.insert(path.clone()) {
+            return Ok(None);
+        }
+
+        Ok(Some(Partial {
+            path,
+            uploads: self.uploads.clone(),
+        }))
+    }
+}
+
+// a partial upload only one transaction at a time may touch, free again once dropped
+#[derive(Debug)]
+pub struct Partial {
+    path: PathBuf,
+    uploads: Arc<Mutex<HashSet<PathBuf>>>,
+}
+
+impl Partial {
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+}
+
+impl Drop for Partial {
+    fn drop(&mut self) {
+        self.uploads
+            .lock()
+            .unwrap_or_else(PoisonError::into_inner)
+            .remove(&self.path);
+    }
+}
+
+// where an interrupted FRCE upload is kept until the client comes back for it
+// ~/.syncr/partial/<blake3 of syncr_id + known_name>-<hash of the finished file>
+//
+// keying on the final hash means a file that changed in the meantime starts over
+fn partial(
+    syncr_id: &str,
+    known_name: &str,
+    hash: &blake3::Hash,
+) -> Result<PathBuf, anyhow::Error> {
+    let dir = syncr_dir()?.join("partial");
+    std::fs::create_dir_all(&dir)?;
+
+    let mut key = blake3::Hasher::new();
+    key.update(syncr_id.as_bytes());
+    key.update(&[0]);
+    key.update(known_name.as_bytes());
+
+    Ok(dir.join(format!("{}-{}", key.finalize().to_hex(), hash.to_hex())))
+}
@@ -0,0 +1,280 @@
+use std::collections::{HashMap, VecDeque};
+use std::fmt;
+use std::net::IpAddr;
+use std::sync::{Arc, Mutex, MutexGuard, PoisonError};
+use std::time::{Duration, Instant};
+
+use crate::common::config::structure::ServerConfig;
+
+// how far back failed handshakes are counted against handshakes-per-minute
+const FAILURE_WINDOW: Duration = Duration::from_secs(60);
+
+// why a connection was turned away before it got to the (expensive) handshake
+#[derive(Debug)]
+pub enum Refusal {
+    Full(usize),
+    TooManyFromIp(usize),
+    // still banned for this long
+    Banned(Duration),
+}
+
+impl fmt::Display for Refusal {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Refusal::Full(max) => write!(f, "already serving {} connections", max),
+            Refusal::TooManyFromIp(max) => write!(f, "already {} connections from there", max),
+            Refusal::Banned(left) => write!(f, "banned for another {}s", left.as_secs()),
+        }
+    }
+}
+
+#[derive(Default)]
+struct Host {
+    // connections of this ip being handshaken or served right now
+    active: usize,
+    // when its recent handshakes failed, oldest first
+    failures: VecDeque<Instant>,
+    banned_until: Option<Instant>,
+}
+
+impl Host {
+    // nothing worth remembering about it anymore
+    fn is_idle(&self, now: Instant) -> bool {
+        self.active == 0
+            && self.banned_until.is_none_or(|until| until <= now)
+            && self
+                .failures
+                .back()
+                .is_none_or(|last| now.duration_since(*last) >= FAILURE_WINDOW)
+    }
+}
+
+#[derive(Default)]
+struct State {
+    active: usize,
+    hosts: HashMap<IpAddr, Host>,
+}
+
+// decides who gets to handshake at all, shared by the tcp and the QUIC listener
+#[derive(Clone)]
+pub struct Throttle {
+    max_connections: usize,
+    max_per_ip: usize,
+    failures_per_minute: usize,
+    ban: Duration,
+    state: Arc<Mutex<State>>,
+}
+
+impl Throttle {
+    pub fn from_config(config: &ServerConfig) -> Self {
+        Self {
+            max_connections: config.max_connections,
+            max_per_ip: config.max_connections_per_ip,
+            failures_per_minute: config.handshakes_per_minute,
+            ban: Duration::from_secs(config.ban_duration),
+            state: Arc::new(Mutex::new(State::default())),
+        }
+    }
+
+    // takes up a slot for the connection if it's let in, the slot is given back once
+    // the permit is dropped
+    pub fn admit(&self, ip: IpAddr) -> Result<Permit, Refusal> {
+        let now = Instant::now();
+        let mut guard = lock(&self.state);
+        let state = &mut *guard;
+
+        state.hosts.retain(|_, host| !host.is_idle(now));
+        let host = state.hosts.entry(ip).or_default();
+
+        if let Some(until) = host.banned_until.filter(|until| *until > now) {
+            return Err(Refusal::Banned(until - now));
+        }
+
+        if state.active >= self.max_connections {
+            return Err(Refusal::Full(self.max_connections));
+        }
+        if host.active >= self.max_per_ip {
+            return Err(Refusal::TooManyFromIp(self.max_per_ip));
+        }
+
+        state.active += 1;
+        host.active += 1;
+
+        Ok(Permit {
+            ip,
+            state: self.state.clone(),
+        })
+    }
+
+    // counts a handshake from ip that went wrong, Some(ban) when it was one too many
+    // and got the ip banned
+    pub fn failed(&self, ip: IpAddr) -> Option<Duration> {
+        let now = Instant::now();
+        let mut state = lock(&self.state);
+        let host = state.hosts.entry(ip).or_default();
+
+        while host
+            .failures
+            .front()
+            .is_some_and(|first| now.duration_since(*first) >= FAILURE_WINDOW)
+        {
+            host.failures.pop_front();
+        }
+        if host.failures.len() >= self.failures_per_minute {
+            host.failures.clear();
+            host.banned_until = Some(now + self.ban);
+            return Some(self.ban);
+        }
+        host.failures.push_back(now);
+
+        None
+    }
+}
+
+// a connection's slot, held from before the handshake until it's closed
+pub struct Permit {
+    ip: IpAddr,
+    state: Arc<Mutex<State>>,
+}
+
+impl Drop for Permit {
+    fn drop(&mut self) {
+        let mut state = lock(&self.state);
+
+        state.active = state.active.saturating_sub(1);
+        if let Some(host) = state.hosts.get_mut(&self.ip) {
+            host.active = host.active.saturating_sub(1);
+        }
+    }
+}
+
+// only counters in there, whatever panicked while holding it can't have left them unusable
+fn lock(state: &Mutex<State>) -> MutexGuard<'_, State> {
+    state.lock().unwrap_or_else(PoisonError::into_inner)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn throttle(max_connections: usize, max_per_ip: usize, per_minute: usize) -> Throttle {
+        Throttle {
+            max_connections,
+            max_per_ip,
+            failures_per_minute: per_minute,
+            ban: Duration::from_millis(100),
+            state: Arc::new(Mutex::new(State::default())),
+        }
+    }
+
+    fn ip(last: u8) -> IpAddr {
+        IpAddr::from([10, 0, 0, last])
+    }
+
+    #[test]
+    fn caps_connections_per_server() {
+        let throttle = throttle(2, 10, 100);
+
+        let _first = throttle.admit(ip(1)).unwrap();
+        let _second = throttle.admit(ip(2)).unwrap();
+        assert!(matches!(throttle.admit(ip(3)), Err(Refusal::Full(2))));
+    }
+
+    #[test]
+    fn caps_connections_per_ip() {
+        let throttle = throttle(10, 2, 100);
+
+        let _first = throttle.admit(ip(1)).unwrap();
+        let _second = throttle.admit(ip(1)).unwrap();
+        assert!(matches!(
+            throttle.admit(ip(1)),
+            Err(Refusal::TooManyFromIp(2))
+        ));
+
+        // nobody else is held back by it
+        assert!(throttle.admit(ip(2)).is_ok());
+    }
+
+    #[test]
+    fn releases_slots_on_drop() {
+        let throttle = throttle(1, 1, 100);
+
+        let permit = throttle.admit(ip(1)).unwrap();
+        assert!(throttle.admit(ip(2)).is_err());
+
+        drop(permit);
+        let permit = throttle.admit(ip(2)).unwrap();
+        assert_eq!(lock(&throttle.state).active, 1);
+
+        drop(permit);
+        assert_eq!(lock(&throttle.state).active, 0);
+        assert_eq!(lock(&throttle.state).hosts[&ip(2)].active, 0);
+    }
+
+    #[test]
+    fn bans_repeated_failures() {
+        let throttle = throttle(10, 10, 3);
+
+        for _ in 0..3 {
+            drop(throttle.admit(ip(1)).unwrap());
+            assert!(throttle.failed(ip(1)).is_none());
+        }
+        assert!(throttle.failed(ip(1)).is_some());
+        assert!(matches!(throttle.admit(ip(1)), Err(Refusal::Banned(_))));
+
+        // only that ip
+        assert!(throttle.admit(ip(2)).is_ok());
+    }
+
+    #[test]
+    fn refusals_and_successes_dont_count() {
+        let throttle = throttle(10, 1, 1);
+
+        let held = throttle.admit(ip(1)).unwrap();
+        for _ in 0..5 {
+            assert!(matches!(
+                throttle.admit(ip(1)),
+                Err(Refusal::TooManyFromIp(1))
+            ));
+        }
+        drop(held);
+
+        for _ in 0..5 {
+            drop(throttle.admit(ip(1)).unwrap());
+        }
+    }
+
+    #[test]
+    fn bans_expire() {
+        let throttle = throttle(10, 10, 1);
+
+        assert!(throttle.failed(ip(1)).is_none());
+        assert!(throttle.failed(ip(1)).is_some());
+        assert!(matches!(throttle.admit(ip(1)), Err(Refusal::Banned(_))));
+
+        std::thread::sleep(Duration::from_millis(150));
+        assert!(throttle.admit(ip(1)).is_ok());
+    }
+
+    #[test]
+    fn forgets_idle_hosts() {
+        let throttle = throttle(10, 10, 100);
+
+        drop(throttle.admit(ip(1)).unwrap());
+        throttle.failed(ip(1));
+        assert!(lock(&throttle.state).hosts.contains_key(&ip(1)));
+
+        // still within the failure window, so it's kept around
+        drop(throttle.admit(ip(2)).unwrap());
+        assert!(lock(&throttle.state).hosts.contains_key(&ip(1)));
+
+        // once its failures are old enough there's nothing left to remember
+        for host in lock(&throttle.state).hosts.values_mut() {
+            for failure in host.failures.iter_mut() {
+                *failure -= FAILURE_WINDOW;
+            }
+        }
+        drop(throttle.admit(ip(3)).unwrap());
+        assert!(!lock(&throttle.state).hosts.contains_key(&ip(1)));
+    }
+}
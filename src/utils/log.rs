@@ -23,6 +23,15 @@ pub struct Logger;
 
 impl Logger {
     pub fn init(level: Option<LevelFilter>) {
+        Self::init_to(level, std::io::stdout())
+    }
+
+    // for when stdout is taken (serve --stdio speaks the protocol over it)
+    pub fn init_stderr(level: Option<LevelFilter>) {
+        Self::init_to(level, std::io::stderr())
+    }
+
+    fn init_to<W: std::io::Write + Send + 'static>(level: Option<LevelFilter>, console: W) {
         let path = dirs::home_dir()
             .map(|dir| dir.join(".syncr").join("logs"))
             .unwrap();
@@ -40,9 +49,7 @@ impl Logger {
                 .unwrap(),
             regex: Regex::new(r"\u001b\[.*?m").unwrap(),
         };
-        let stdout = std::io::stdout();
-
-        let tee_writer = io_tee::TeeWriter::new(stdout, file);
+        let tee_writer = io_tee::TeeWriter::new(console, file);
 
         Builder::new()
             .filter(None, level.unwrap_or(LevelFilter::Info))
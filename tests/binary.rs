@@ -0,0 +1,91 @@
+// the real binary serving, an in-process client syncing against it
+mod common;
+
+use std::net::{SocketAddr, TcpListener, TcpStream};
+use std::path::Path;
+use std::process::{Child, Command};
+use std::time::{Duration, Instant};
+
+use syncr_rust::client::Client;
+use syncr_rust::common::config::Config;
+use tempfile::TempDir;
+
+const BIN: &str = env!("CARGO_BIN_EXE_syncr-rust");
+
+// kills the server even when an assertion fails halfway through
+struct Serving(Child);
+
+impl Drop for Serving {
+    fn drop(&mut self) {
+        let _ = self.0.kill();
+        let _ = self.0.wait();
+    }
+}
+
+// the binary reads ./server.toml and keeps its database under HOME, both in here
+fn syncr(dir: &Path, args: &[&str]) -> Command {
+    let mut command = Command::new(BIN);
+    command.args(args).current_dir(dir).env("HOME", dir);
+    command
+}
+
+fn free_port() -> u16 {
+    TcpListener::bind("127.0.0.1:0")
+        .and_then(|listener| listener.local_addr())
+        .map(|addr| addr.port())
+        .expect("free port")
+}
+
+fn wait_until_listening(addr: SocketAddr, server: &mut Serving) {
+    let started = Instant::now();
+
+    while TcpStream::connect_timeout(&addr, Duration::from_millis(100)).is_err() {
+        if let Some(status) = server.0.try_wait().unwrap() {
+            panic!("server exited early: {status}");
+        }
+        assert!(
+            started.elapsed() < Duration::from_secs(30),
+            "server never started listening"
+        );
+        std::thread::sleep(Duration::from_millis(100));
+    }
+}
+
+#[tokio::test]
+async fn client_syncs_against_the_served_binary() {
+    let _serial = common::serial().await;
+    let server_dir = TempDir::new().unwrap();
+    let client_dir = TempDir::new().unwrap();
+
+    let addr = SocketAddr::from(([127, 0, 0, 1], free_port()));
+    common::server_config(server_dir.path(), addr.port(), false);
+
+    let granted = syncr(
+        server_dir.path(),
+        &["admin", "grant", "client", "binary", "rw"],
+    )
+    .status()
+    .unwrap();
+    assert!(granted.success());
+
+    let mut server = Serving(syncr(server_dir.path(), &["serve"]).spawn().unwrap());
+    wait_until_listening(addr, &mut server);
+
+    let local = client_dir.path().join("notes.txt");
+    let contents = common::contents(3, 200_000);
+    std::fs::write(&local, &contents).unwrap();
+
+    let config = Config::read(Some(common::client_config(client_dir.path(), addr, "tcp")))
+        .expect("client config");
+    let client = Client::connect(Some(config))
+        .await
+        .expect("client connects");
+    client.sync(&local, "binary", "notes.txt").await.unwrap();
+
+    let stored = server_dir
+        .path()
+        .join("storage")
+        .join("binary")
+        .join("notes.txt");
+    assert_eq!(std::fs::read(stored).unwrap(), contents);
+}
@@ -0,0 +1,114 @@
+// shared by the integration tests, every test binary gets its own throwaway ~/.syncr
+#![allow(dead_code)]
+
+use std::net::SocketAddr;
+use std::path::{Path, PathBuf};
+use std::sync::OnceLock;
+
+use syncr_rust::common::config::Config;
+use syncr_rust::server::Server;
+use tempfile::TempDir;
+use tokio::sync::{Mutex, MutexGuard};
+use tokio::task::JoinHandle;
+
+const SECRET: &str = "integration tests only";
+const SALT: &str = "syncr-integration-tests";
+
+// client and server share the database and identity under HOME, so the tests take turns
+static SERIAL: Mutex<()> = Mutex::const_new(());
+
+fn home() -> &'static Path {
+    static HOME: OnceLock<TempDir> = OnceLock::new();
+
+    HOME.get_or_init(|| {
+        let home = TempDir::new().expect("temporary home");
+        // set once, before anything reads it
+        unsafe { std::env::set_var("HOME", home.path()) };
+        home
+    })
+    .path()
+}
+
+pub async fn serial() -> MutexGuard<'static, ()> {
+    home();
+    SERIAL.lock().await
+}
+
+pub fn server_config(dir: &Path, port: u16, quic: bool) -> PathBuf {
+    let path = dir.join("server.toml");
+    std::fs::write(
+        &path,
+        format!(
+            r#"[config]
+secret = "{SECRET}"
+salt = "{SALT}"
+device-name = "server"
+auto-wonder = false
+mode = "server"
+
+[config.server]
+ip = "127.0.0.1"
+port = {port}
+quic = {quic}
+storage-root = "{storage}"
+"#,
+            storage = dir.join("storage").display()
+        ),
+    )
+    .expect("server config");
+
+    path
+}
+
+pub fn client_config(dir: &Path, server: SocketAddr, transport: &str) -> PathBuf {
+    let path = dir.join("client.toml");
+    std::fs::write(
+        &path,
+        format!(
+            r#"[config]
+secret = "{SECRET}"
+salt = "{SALT}"
+device-name = "client"
+auto-wonder = false
+mode = "client"
+
+[config.client]
+server-ip = "{ip}"
+server-port = {port}
+transport = "{transport}"
+directories = []
+"#,
+            ip = server.ip(),
+            port = server.port()
+        ),
+    )
+    .expect("client config");
+
+    path
+}
+
+// a server on a free port of 127.0.0.1, aborted once the handle is dropped
+pub async fn spawn_server(dir: &Path, quic: bool) -> (SocketAddr, JoinHandle<()>) {
+    let config = Config::read(Some(server_config(dir, 0, quic))).expect("server config");
+    let server = Server::bind(Some(config)).await.expect("server binds");
+    let addr = server.local_addr().expect("server listens");
+
+    let handle = tokio::spawn(async move {
+        server.run().await.expect("server runs");
+    });
+
+    (addr, handle)
+}
+
+pub async fn grant(syncr_id: &str) {
+    syncr_rust::server::admin::run(&["grant", "client", syncr_id, "rw"])
+        .await
+        .expect("grant");
+}
+
+// a few blocks worth of something that isn't all the same byte
+pub fn contents(seed: u8, len: usize) -> Vec<u8> {
+    (0..len)
+        .map(|i| (i as u8).wrapping_mul(31).wrapping_add(seed) ^ (i >> 8) as u8)
+        .collect()
+}
@@ -0,0 +1,103 @@
+// QUIC over 127.0.0.1, the handshake has to be tied to the TLS session underneath it
+#![cfg(feature = "quic")]
+
+mod common;
+
+use std::sync::Arc;
+use std::time::Duration;
+
+use syncr_rust::client::Client;
+use syncr_rust::common::config::Config;
+use syncr_rust::common::identity;
+use syncr_rust::common::packets::{Capabilities, HelloPacket, PacketBase};
+use syncr_rust::common::psk;
+use syncr_rust::common::quic;
+use syncr_rust::common::stream::{Credentials, Role, SecureStream};
+use tempfile::TempDir;
+
+fn credentials() -> Credentials {
+    Credentials {
+        keypair: Arc::new(identity::load_or_create(None).unwrap()),
+        psk: psk::derive("integration tests only", "syncr-integration-tests").unwrap(),
+    }
+}
+
+fn hello(name: &str) -> HelloPacket {
+    HelloPacket::build((name.to_owned(), Vec::new(), Capabilities::local()))
+}
+
+#[tokio::test]
+async fn syncs_a_file_over_quic() {
+    let _serial = common::serial().await;
+    let dir = TempDir::new().unwrap();
+    let (addr, server) = common::spawn_server(dir.path(), true).await;
+    common::grant("quic").await;
+
+    let local = dir.path().join("notes.txt");
+    let contents = common::contents(4, 200_000);
+    std::fs::write(&local, &contents).unwrap();
+
+    let config =
+        Config::read(Some(common::client_config(dir.path(), addr, "quic"))).expect("client config");
+    let client = Client::connect(Some(config))
+        .await
+        .expect("client connects");
+    client.sync(&local, "quic", "notes.txt").await.unwrap();
+
+    let stored = dir.path().join("storage").join("quic").join("notes.txt");
+    assert_eq!(std::fs::read(stored).unwrap(), contents);
+
+    server.abort();
+}
+
+#[tokio::test]
+async fn mismatched_binding_is_rejected() {
+    let _serial = common::serial().await;
+    let endpoint = quic::listen(([127, 0, 0, 1], 0).into()).unwrap();
+    let addr = endpoint.local_addr().unwrap();
+    let credentials = credentials();
+
+    // what a relay in the middle would end up with, a TLS session of its own on this side
+    let responder = {
+        let credentials = credentials.clone();
+        async move {
+            let connection = endpoint.accept().await.unwrap().await.unwrap();
+            let control = quic::accept(&connection).await?;
+
+            SecureStream::with_prologue(
+                control,
+                Role::Responder,
+                &credentials,
+                hello("server"),
+                &[0u8; 32],
+            )
+            .await
+            .map(|_| ())
+        }
+    };
+
+    let initiator = async {
+        let (connection, control) = quic::connect(addr).await?;
+        let binding = quic::binding(&connection)?;
+        assert_ne!(binding, [0u8; 32]);
+
+        SecureStream::with_prologue(
+            control,
+            Role::Initiator,
+            &credentials,
+            hello("client"),
+            &binding,
+        )
+        .await
+        .map(|_| ())
+    };
+
+    let (responded, initiated) = tokio::time::timeout(Duration::from_secs(10), async {
+        tokio::join!(responder, initiator)
+    })
+    .await
+    .expect("handshake hung instead of failing");
+
+    assert!(initiated.is_err());
+    assert!(responded.is_err());
+}
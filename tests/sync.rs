@@ -0,0 +1,212 @@
+// client and server in one process, talking over a real tcp socket on 127.0.0.1
+// (or an in-memory pipe, the secure stream doesn't care what's underneath)
+mod common;
+
+use std::sync::Arc;
+
+use memmap2::Mmap;
+use syncr_rust::client::Client;
+use syncr_rust::common::config::Config;
+use syncr_rust::common::identity;
+use syncr_rust::common::packets::{
+    PacketBase, Packets, SyncAcknowledgePacket, SyncForcePacket, SyncInitPacket,
+};
+use syncr_rust::common::pairing::Greeting;
+use syncr_rust::common::psk;
+use syncr_rust::common::stream::{Credentials, Streams};
+use syncr_rust::server::Server;
+use syncr_rust::utils::hash::hash_file;
+use tempfile::TempDir;
+use tokio::net::TcpStream;
+
+async fn connect(dir: &TempDir, server: std::net::SocketAddr) -> Client {
+    let config = Config::read(Some(common::client_config(dir.path(), server, "tcp")))
+        .expect("client config");
+
+    Client::connect(Some(config))
+        .await
+        .expect("client connects")
+}
+
+#[tokio::test]
+async fn forced_sync_uploads_a_new_file() {
+    let _serial = common::serial().await;
+    let dir = TempDir::new().unwrap();
+    let (addr, server) = common::spawn_server(dir.path(), false).await;
+    common::grant("forced").await;
+
+    let local = dir.path().join("notes.txt");
+    let contents = common::contents(1, 300_000);
+    std::fs::write(&local, &contents).unwrap();
+
+    let client = connect(&dir, addr).await;
+    client.sync(&local, "forced", "notes.txt").await.unwrap();
+
+    let stored = dir.path().join("storage").join("forced").join("notes.txt");
+    assert_eq!(std::fs::read(stored).unwrap(), contents);
+
+    server.abort();
+}
+
+#[tokio::test]
+async fn delta_sync_updates_an_existing_file() {
+    let _serial = common::serial().await;
+    let dir = TempDir::new().unwrap();
+    let (addr, server) = common::spawn_server(dir.path(), false).await;
+    common::grant("delta").await;
+
+    // the server already holds an older version, only the changed bits should travel
+    let stored = dir.path().join("storage").join("delta").join("notes.txt");
+    let old = common::contents(2, 300_000);
+    std::fs::create_dir_all(stored.parent().unwrap()).unwrap();
+    std::fs::write(&stored, &old).unwrap();
+
+    let mut new = old.clone();
+    new[150_000..150_100].fill(0xAA);
+    new.extend_from_slice(b"and a little more at the end");
+    let local = dir.path().join("notes.txt");
+    std::fs::write(&local, &new).unwrap();
+
+    let client = connect(&dir, addr).await;
+    client.sync(&local, "delta", "notes.txt").await.unwrap();
+    assert_eq!(std::fs::read(&stored).unwrap(), new);
+
+    // nothing left to do the second time around
+    client.sync(&local, "delta", "notes.txt").await.unwrap();
+    assert_eq!(std::fs::read(&stored).unwrap(), new);
+
+    server.abort();
+}
+
+#[tokio::test]
+async fn refused_sync_surfaces_the_error_packet() {
+    let _serial = common::serial().await;
+    let dir = TempDir::new().unwrap();
+    let (addr, server) = common::spawn_server(dir.path(), false).await;
+    // no grant for this one
+
+    let local = dir.path().join("notes.txt");
+    std::fs::write(&local, b"not welcome").unwrap();
+
+    let client = connect(&dir, addr).await;
+    let error = client
+        .sync(&local, "ungranted", "notes.txt")
+        .await
+        .unwrap_err()
+        .to_string();
+
+    assert!(error.contains("Forbidden"), "{error}");
+    assert!(
+        !dir.path()
+            .join("storage")
+            .join("ungranted")
+            .join("notes.txt")
+            .exists()
+    );
+
+    server.abort();
+}
+
+#[tokio::test]
+async fn forced_upload_is_taken_instead_of_a_delta() {
+    let _serial = common::serial().await;
+    let dir = TempDir::new().unwrap();
+    let (addr, server) = common::spawn_server(dir.path(), false).await;
+    common::grant("unasked").await;
+
+    let stored = dir.path().join("storage").join("unasked").join("notes.txt");
+    std::fs::create_dir_all(stored.parent().unwrap()).unwrap();
+    std::fs::write(&stored, common::contents(5, 100_000)).unwrap();
+
+    let local = dir.path().join("notes.txt");
+    let contents = common::contents(6, 120_000);
+    std::fs::write(&local, &contents).unwrap();
+    let hash = hash_file(&local).unwrap();
+
+    // the client's side by hand, Client::sync always takes the delta it's offered
+    let config = Config::read(Some(common::client_config(dir.path(), addr, "tcp"))).unwrap();
+    let credentials = Credentials {
+        keypair: Arc::new(identity::load_or_create(None).unwrap()),
+        psk: psk::from_config(&config).await.unwrap(),
+    };
+    let mut transport = TcpStream::connect(addr).await.unwrap();
+    let greeting = Greeting::Session {
+        key_id: psk::key_id(&credentials.psk),
+    };
+    greeting.write(&mut transport).await.unwrap();
+    let connection = Client::handshake(
+        transport,
+        Streams::Shared,
+        &greeting.prologue(&[]),
+        &config,
+        &credentials,
+    )
+    .await
+    .unwrap();
+
+    let mut transaction = connection.open().await.unwrap();
+    transaction
+        .send(&SyncInitPacket::build((
+            hash,
+            "unasked".to_owned(),
+            "notes.txt".to_owned(),
+        )))
+        .await
+        .unwrap();
+    assert!(matches!(
+        transaction.receive().await.unwrap(),
+        Packets::SyncAck(SyncAcknowledgePacket {
+            ack: true,
+            data: Some(_)
+        })
+    ));
+
+    let file = std::fs::File::open(&local).unwrap();
+    let chunk = unsafe { Mmap::map(&file).unwrap() };
+    transaction
+        .send_mmap(&SyncForcePacket::build((
+            chunk,
+            0,
+            contents.len() as u64,
+            hash,
+        )))
+        .await
+        .unwrap();
+    assert!(matches!(
+        transaction.receive().await.unwrap(),
+        Packets::SyncAck(SyncAcknowledgePacket { ack: false, .. })
+    ));
+
+    assert_eq!(std::fs::read(&stored).unwrap(), contents);
+
+    server.abort();
+}
+
+#[tokio::test]
+async fn syncs_over_an_in_memory_transport() {
+    let _serial = common::serial().await;
+    let dir = TempDir::new().unwrap();
+    common::grant("piped").await;
+
+    let config = Config::read(Some(common::server_config(dir.path(), 0, false))).unwrap();
+    let server = Server::open(Some(config)).await.unwrap();
+    let (client_end, server_end) = tokio::io::duplex(64 * 1024);
+    let server = tokio::spawn(server.serve(server_end, "duplex"));
+
+    let local = dir.path().join("notes.txt");
+    let contents = common::contents(7, 200_000);
+    std::fs::write(&local, &contents).unwrap();
+
+    // never dialed, the pipe stands in for it
+    let unreachable = "127.0.0.1:9".parse().unwrap();
+    let config = Config::read(Some(common::client_config(dir.path(), unreachable, "tcp"))).unwrap();
+    let client = Client::over(Some(config), client_end).await.unwrap();
+    client.sync(&local, "piped", "notes.txt").await.unwrap();
+
+    let stored = dir.path().join("storage").join("piped").join("notes.txt");
+    assert_eq!(std::fs::read(stored).unwrap(), contents);
+
+    // hanging up is what ends a single-connection server
+    drop(client);
+    server.await.unwrap().unwrap();
+}